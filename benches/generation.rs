@@ -0,0 +1,68 @@
+//! Benchmarks of the CPU side generation, which runs without a GL context.
+//!
+//! The inputs are fixed, so the numbers are comparable between commits. Run
+//! from the repository root, since the base map is loaded from `textures/`.
+
+use std::rc::Rc;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use noise::NoiseFn;
+
+use undergrowth::generation::{generate_foliage, generate_points_on_distribution, height_grid};
+use undergrowth::prelude::*;
+
+const SEED: u32 = 13;
+
+fn base_map() -> Rc<image::RgbaImage> {
+    let image = image::open("textures/map.png").expect("Loading base map failed");
+    Rc::new(image.into_rgba8())
+}
+
+fn maps(c: &mut Criterion) {
+    let base = base_map();
+    let mut group = c.benchmark_group("maps");
+
+    group.bench_function("height_map", |b| {
+        b.iter(|| height_map(base.clone(), SCENE_SIZE, black_box(SEED)))
+    });
+    group.bench_function("variant_map", |b| {
+        b.iter(|| variant_map(base.clone(), SCENE_SIZE, black_box(SEED)))
+    });
+
+    // The CPU part of `Texture::from_noise` for a terrain map
+    let heights = height_map(base.clone(), SCENE_SIZE, SEED);
+    group.bench_function("height_grid_256", |b| {
+        b.iter(|| height_grid(&heights, (0., SCENE_SIZE, 0., SCENE_SIZE), black_box(256)))
+    });
+    group.finish();
+}
+
+fn foliage(c: &mut Criterion) {
+    let mut group = c.benchmark_group("foliage");
+
+    // Two points per square meter everywhere, like a dense shrub layer
+    let uniform = noise::Constant::new(2.0);
+    group.bench_function("points_on_distribution", |b| {
+        b.iter(|| {
+            generate_points_on_distribution(
+                uniform,
+                (0., SCENE_SIZE, 0., SCENE_SIZE),
+                black_box(SEED as u64),
+            )
+        })
+    });
+
+    let heights: Rc<dyn NoiseFn<f64, 2>> = Rc::new(height_map(base_map(), SCENE_SIZE, SEED));
+    let config = FoliageConfig {
+        density: 10.0,
+        scale_range: (0.8, 1.2),
+        ..FoliageConfig::new(heights)
+    };
+    group.bench_function("generate_foliage", |b| {
+        b.iter(|| generate_foliage(&config, black_box(SEED)))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, maps, foliage);
+criterion_main!(benches);
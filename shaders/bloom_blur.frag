@@ -0,0 +1,21 @@
+#version 410 core
+
+in vec2 v_uv;
+
+uniform sampler2D source;
+// The size of one source texel in the blur direction, ie (1/width, 0) or (0, 1/height)
+uniform vec2 direction;
+
+out vec4 out_color;
+
+// Normalized 9 tap Gaussian, center first
+const float weights[5] = float[](0.227027, 0.1945946, 0.1216216, 0.054054, 0.016216);
+
+void main() {
+    vec3 color = texture(source, v_uv).rgb * weights[0];
+    for (int i = 1; i < 5; i++) {
+        color += texture(source, v_uv + float(i) * direction).rgb * weights[i];
+        color += texture(source, v_uv - float(i) * direction).rgb * weights[i];
+    }
+    out_color = vec4(color, 1.0);
+}
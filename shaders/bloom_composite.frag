@@ -0,0 +1,16 @@
+#version 410 core
+
+in vec2 v_uv;
+
+uniform sampler2D scene;
+uniform sampler2D bloom;
+uniform float intensity;
+
+out vec4 out_color;
+
+void main() {
+    vec4 scene_color = texture(scene, v_uv);
+    vec3 color = scene_color.rgb + intensity * texture(bloom, v_uv).rgb;
+    // Keeps the background transparent, if it was cleared that way
+    out_color = vec4(color, scene_color.a);
+}
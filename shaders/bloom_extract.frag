@@ -0,0 +1,16 @@
+#version 410 core
+
+in vec2 v_uv;
+
+uniform sampler2D scene;
+uniform float threshold;
+
+out vec4 out_color;
+
+void main() {
+    vec3 color = texture(scene, v_uv).rgb;
+    float brightness = max(color.r, max(color.g, color.b));
+    // Only the part above the threshold contributes, which avoids hard edges
+    float contribution = max(brightness - threshold, 0.0) / max(brightness, 0.0001);
+    out_color = vec4(color * contribution, 1.0);
+}
@@ -0,0 +1,28 @@
+#version 410 core
+
+in vec2 v_uv;
+in vec3 v_position;
+
+// Opacity of the shadow in its center
+uniform float strength;
+// The terrain spans from (0, 0) to (terrain_size, terrain_size)
+uniform float terrain_size;
+uniform vec3 eye_position;
+
+#include "lib/fog.glsl"
+
+out vec4 out_color;
+
+void main() {
+    // Past the terrain edge there is only sky to darken
+    if (any(lessThan(v_position.xy, vec2(0.0))) || any(greaterThan(v_position.xy, vec2(terrain_size)))) {
+        discard;
+    }
+
+    // 0 in the center, 1 on the inscribed circle
+    float dist = length(v_uv * 2.0 - 1.0);
+    float falloff = 1.0 - smoothstep(0.0, 1.0, dist);
+    // The fogged ground behind has little left to darken
+    float fade = fog_transmittance(distance(eye_position, v_position));
+    out_color = vec4(0.0, 0.0, 0.0, strength * falloff * falloff * fade);
+}
@@ -0,0 +1,17 @@
+#version 410 core
+
+layout(location = 0) in vec3 position;
+layout(location = 4) in vec2 uv;
+layout(location = 8) in mat4 model_mat;
+
+out vec2 v_uv;
+out vec3 v_position;
+
+uniform mat4 view_proj;
+
+void main() {
+    vec4 world_pos = model_mat * vec4(position, 1.0);
+    v_uv = uv;
+    v_position = world_pos.xyz;
+    gl_Position = view_proj * world_pos;
+}
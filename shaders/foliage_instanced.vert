@@ -5,17 +5,38 @@ layout(location = 1) in vec3 normal;
 layout(location = 4) in vec2 texcord;
 layout(location = 8) in mat4 model_mat;
 layout(location = 12) in mat3 normal_mat;
+// Offsets of the hue, saturation and value of the albedo for this instance
+layout(location = 15) in vec3 color_jitter;
 
 out vec3 v_position;
 out vec3 v_normal;
 out vec2 v_texcord;
+flat out vec3 v_color_jitter;
 
 uniform mat4 view_proj;
+// Seconds since the scene was created
+uniform float time;
+// Sway in meters per meter of height above the instance origin
+uniform float wind_strength;
+
+const vec2 wind_direction = vec2(0.928, 0.371);
 
 void main() {
     vec4 world_pos = model_mat * vec4(position, 1.0);
+
+    // Neighbors sway out of phase, since the phase depends on the instance position
+    vec3 instance_pos = model_mat[3].xyz;
+    float phase = dot(instance_pos.xy, vec2(1.7, 2.3))
+        + 3.0 * sin(0.5 * instance_pos.x + 0.8 * instance_pos.y);
+    float sway = sin(1.5 * time + phase) + 0.3 * sin(3.7 * time + 1.3 * phase);
+    // The base stays in place, higher parts move further
+    float height = max(world_pos.z - instance_pos.z, 0.0);
+    world_pos.xy += wind_direction * (wind_strength * height * sway);
     v_position = world_pos.xyz;
-    v_normal = normal_mat * normal;
+    // The inverse transpose, so stretched or squashed instances keep their
+    // normals perpendicular to the surface. Ignores the small wind sway.
+    v_normal = normalize(normal_mat * normal);
     v_texcord = texcord;
+    v_color_jitter = color_jitter;
     gl_Position = view_proj * world_pos;
 }
@@ -3,23 +3,85 @@
 in vec3 v_position;
 in vec3 v_normal;
 in vec2 v_texcord;
+flat in vec3 v_color_jitter;
 
 uniform sampler2D albedo;
+#include "lib/lighting.glsl"
+#include "lib/fog.glsl"
+uniform sampler2D emissive_map;
+uniform bool has_emissive_map;
+// Emitted color, already multiplied by its strength
+uniform vec3 emissive;
+// Replaces the color when not zero, see `DebugView` in renderer.rs
+uniform int debug_view;
+// Depth along the view axis, 0 at the near and 1 at the far plane
+uniform vec4 depth_plane;
+uniform vec3 eye_position;
+// Alpha tested when false. Transparent layers are drawn a second time with
+// only their faded edges, see `RenderContext::soft_edges` in renderer.rs
+uniform bool soft_edges;
 
 out vec4 out_color;
 
-const vec3 light_up = normalize(vec3(0.5, 0.5, 1.0));
+// How much of the fog the emission goes through, below 1 so glowing things
+// stay visible at distance
+const float EMISSIVE_FOG = 0.5;
+
+// Branchless conversions after Sam Hocevar
+vec3 rgb_to_hsv(vec3 c) {
+    vec4 K = vec4(0.0, -1.0 / 3.0, 2.0 / 3.0, -1.0);
+    vec4 p = mix(vec4(c.bg, K.wz), vec4(c.gb, K.xy), step(c.b, c.g));
+    vec4 q = mix(vec4(p.xyw, c.r), vec4(c.r, p.yzx), step(p.x, c.r));
+    float d = q.x - min(q.w, q.y);
+    float e = 1.0e-10;
+    return vec3(abs(q.z + (q.w - q.y) / (6.0 * d + e)), d / (q.x + e), q.x);
+}
+
+vec3 hsv_to_rgb(vec3 c) {
+    vec3 p = abs(fract(c.xxx + vec3(1.0, 2.0 / 3.0, 1.0 / 3.0)) * 6.0 - 3.0);
+    return c.z * mix(vec3(1.0), clamp(p - 1.0, 0.0, 1.0), c.y);
+}
 
 void main() {
     vec4 color = texture(albedo, v_texcord, -1.5);
+    if (v_color_jitter != vec3(0.0)) {
+        vec3 hsv = rgb_to_hsv(color.rgb) + v_color_jitter;
+        color.rgb = hsv_to_rgb(vec3(fract(hsv.x), clamp(hsv.yz, 0.0, 1.0)));
+    }
 
-    // Cheap order independent transparency
-    if (color.a <= 0.5) {
+    // Cheap order independent transparency, the edges fade in a later pass
+    float alpha = soft_edges ? smoothstep(0.35, 0.5, color.a) : step(0.5, color.a);
+    // The opaque core was drawn before and wrote its depth
+    if (alpha <= 0.0 || (soft_edges && alpha >= 1.0)) {
         discard;
     }
 
+    // Already in world space, but interpolation shortens it. Leaves are seen
+    // from both sides, so the back faces light with the flipped normal.
     vec3 normal = normalize(v_normal);
-    float diff = clamp(dot(normal, light_up), 0.0, 1.0);
-    out_color = vec4(color.rgb * (0.5 * diff + 0.5), 1.0);
-    out_color *= clamp(0.3 + 2.0 * v_position.z, 0., 1.);
+    if (!gl_FrontFacing) {
+        normal = -normal;
+    }
+    // The blending keeps the alpha of the window, see `render_entities`
+    out_color = vec4(color.rgb * (diffuse_light(normal) + 0.5 * ambient_color), alpha);
+    // Only the color, the alpha must stay opaque for transparent windows
+    out_color.rgb *= clamp(0.3 + 2.0 * v_position.z, 0., 1.);
+
+    // Added after the height darkening, so glowing things stay visible in the hollows
+    vec3 emission = emissive;
+    if (has_emissive_map) {
+        emission *= texture(emissive_map, v_texcord, -1.5).rgb;
+    }
+    float fog_distance = distance(eye_position, v_position);
+    out_color.rgb = apply_fog(out_color.rgb, fog_distance);
+    // Only partially attenuated by the fog, the glow shines through it
+    out_color.rgb += emission * mix(1.0, fog_transmittance(fog_distance), EMISSIVE_FOG);
+
+    if (debug_view == 1) {
+        out_color.rgb = 0.5 * normal + 0.5;
+    } else if (debug_view == 2) {
+        out_color.rgb = vec3(dot(depth_plane, vec4(v_position, 1.0)));
+    } else if (debug_view == 3) {
+        out_color.rgb = vec3(fract(v_texcord), 0.0);
+    }
 }
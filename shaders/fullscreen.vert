@@ -0,0 +1,9 @@
+#version 410 core
+
+out vec2 v_uv;
+
+void main() {
+    // Triangle strip corners (0, 0), (1, 0), (0, 1), (1, 1) from the vertex id
+    v_uv = vec2(float(gl_VertexID & 1), float(gl_VertexID >> 1));
+    gl_Position = vec4(v_uv * 2.0 - 1.0, 0.0, 1.0);
+}
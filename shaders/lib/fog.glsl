@@ -0,0 +1,15 @@
+// Shared by the terrain, foliage and contact shadow shaders, see `RenderContext::set_fog_uniforms` in renderer.rs
+
+uniform vec3 fog_color;
+// Per meter, zero disables the fog
+uniform float fog_density;
+
+// Exponential squared, clear up close and thickening towards the far plane
+float fog_transmittance(float distance) {
+    float amount = distance * fog_density;
+    return exp(-amount * amount);
+}
+
+vec3 apply_fog(vec3 color, float distance) {
+    return mix(fog_color, color, fog_transmittance(distance));
+}
@@ -0,0 +1,16 @@
+// Shared by the terrain and foliage shaders, see `set_light_uniforms` in lighting.rs
+
+uniform vec3 ambient_color;
+// Directional lights, the direction pointing towards the light
+#define MAX_LIGHTS 4
+uniform int light_count;
+uniform vec3 light_directions[MAX_LIGHTS];
+uniform vec3 light_colors[MAX_LIGHTS];
+
+vec3 diffuse_light(vec3 normal) {
+    vec3 light = vec3(0.0);
+    for (int i = 0; i < min(light_count, MAX_LIGHTS); i++) {
+        light += max(dot(normal, light_directions[i]), 0.0) * light_colors[i];
+    }
+    return light;
+}
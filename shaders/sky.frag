@@ -0,0 +1,34 @@
+#version 410 core
+
+in vec2 v_uv;
+
+#ifdef EQUIRECTANGULAR
+// Equirectangular panorama with the top row first, like the panorama captures
+uniform sampler2D sky_image;
+#else
+uniform samplerCube environment;
+#endif
+uniform mat4 inverse_view_proj;
+
+out vec4 out_color;
+
+const float PI = 3.14159265;
+
+void main() {
+    // The view ray through this pixel, from the near to the far plane
+    vec2 ndc = v_uv * 2.0 - 1.0;
+    vec4 near = inverse_view_proj * vec4(ndc, -1.0, 1.0);
+    vec4 far = inverse_view_proj * vec4(ndc, 1.0, 1.0);
+    vec3 direction = normalize(far.xyz / far.w - near.xyz / near.w);
+
+#ifdef EQUIRECTANGULAR
+    // The center column looks along +x, turning right turns clockwise seen from above
+    float longitude = atan(-direction.y, direction.x);
+    float latitude = asin(clamp(direction.z, -1.0, 1.0));
+    vec2 uv = vec2(longitude / (2.0 * PI) + 0.5, 0.5 - latitude / PI);
+    // With mipmaps, the jump in longitude at the seam would pick a tiny one
+    out_color = vec4(textureLod(sky_image, uv, 0.0).rgb, 1.0);
+#else
+    out_color = vec4(texture(environment, direction).rgb, 1.0);
+#endif
+}
@@ -3,15 +3,45 @@
 in vec3 v_pos;
 in vec3 v_normal;
 in float v_variant;
+in float v_rocky;
 
 uniform sampler2D terrain_albedo_xy1;
 uniform sampler2D terrain_albedo_xy2;
 uniform sampler2D terrain_albedo_xz;
 uniform sampler2D terrain_albedo_yz;
+#include "lib/lighting.glsl"
+#include "lib/fog.glsl"
+uniform vec3 eye_position;
+// Strength of the highlights on glossy surfaces, zero disables them
+uniform float specular_strength;
+// Between 0 (mirror like) and 1 (matte), of dry glossy surfaces. Wet ones are smoother.
+uniform float roughness;
+// Surroundings reflected by wet ground, zero strength without one
+uniform samplerCube environment;
+uniform float environment_strength;
+// Replaces the color when not zero, see `DebugView` in renderer.rs
+uniform int debug_view;
+// Depth along the view axis, 0 at the near and 1 at the far plane
+uniform vec4 depth_plane;
+// Alpha tested when false. Transparent layers are drawn a second time with
+// only their faded edges, see `RenderContext::soft_edges` in renderer.rs
+uniform bool soft_edges;
 
 out vec4 color;
 
-const vec3 light_up = normalize(vec3(0.5, 0.5, 1.0));
+// Blinn-Phong highlights of all lights
+vec3 specular_light(vec3 normal, vec3 view_dir, float roughness) {
+    // The usual mapping of roughness to a Blinn-Phong exponent
+    float r4 = pow(max(roughness, 0.05), 4.0);
+    float exponent = 2.0 / r4 - 2.0;
+    vec3 light = vec3(0.0);
+    for (int i = 0; i < min(light_count, MAX_LIGHTS); i++) {
+        vec3 halfway = normalize(light_directions[i] + view_dir);
+        float facing = step(0.0, dot(normal, light_directions[i]));
+        light += facing * pow(max(dot(normal, halfway), 0.0), exponent) * light_colors[i];
+    }
+    return light;
+}
 
 // 2d 45 degree rotation mat
 const mat2 rotation45 = mat2(0.707, -0.707, 0.707, 0.707);
@@ -28,12 +58,17 @@ void main() {
     vec4 color_xy1 = texture(terrain_albedo_xy1, 1.6 * v_pos.xy, -1.5);
     vec4 color_xy2 = texture(terrain_albedo_xy2, 1.5 * rotation45 * v_pos.xy, -1.5);
     vec4 color_xy = mix(color_xy1, color_xy2, interp_quintic(v_variant));
+    // The rocky biome is bare rock from above as well
+    vec4 color_xy_rock = texture(terrain_albedo_xz, v_pos.xy, -1.5);
+    color_xy = mix(color_xy, color_xy_rock, smoothstep(0.0, 1.0, v_rocky));
     vec4 color_xz = texture(terrain_albedo_xz, v_pos.xz, -1.5);
     vec4 color_yz = texture(terrain_albedo_yz, v_pos.yz, -1.5);
     color = color_xy * weights.z + color_xz * weights.y + color_yz * weights.x;
 
-    // Cheap order independent transparency
-    if (color.a < 0.6) {
+    // Cheap order independent transparency, the edges fade in a later pass
+    float alpha = soft_edges ? smoothstep(0.45, 0.6, color.a) : step(0.6, color.a);
+    // The opaque core was drawn before and wrote its depth
+    if (alpha <= 0.0 || (soft_edges && alpha >= 1.0)) {
         discard;
     }
 
@@ -44,10 +79,33 @@ void main() {
 
     }
 
-    // Shading based on normal (half ambient, half diffuse from above)
-    color *= clamp(dot(light_up, normal) * 0.5 + 0.5, 0., 1.);
+    // Shading based on normal (half ambient, half diffuse from the lights)
+    vec3 light = diffuse_light(normal) + 0.5 * ambient_color;
+    color.rgb *= clamp(light, 0., 1.);
+
+    // Moss stays matte, bare rock and the wet lowlands glint
+    float moss = 1.0 - interp_quintic(v_variant);
+    float rock = clamp(1.0 - weights.z + v_rocky, 0.0, 1.0);
+    float wetness = 1.0 - smoothstep(0.0, 0.5, v_pos.z);
+    float gloss = max(rock, wetness) * (1.0 - moss * (1.0 - rock));
+    vec3 view_dir = normalize(eye_position - v_pos);
+    float wet_roughness = mix(roughness, 0.5 * roughness, wetness);
+    color.rgb += specular_strength * gloss * specular_light(normal, view_dir, wet_roughness);
     // Shading based on height
     color *= clamp(0.3 + 2.0 * v_pos.z, 0., 1.);
+    // Schlick's Fresnel, puddles mirror the sky mostly at grazing angles
+    float fresnel = 0.04 + 0.96 * pow(1.0 - max(dot(normal, view_dir), 0.0), 5.0);
+    vec3 reflected = textureLod(environment, reflect(-view_dir, normal), 6.0 * wet_roughness).rgb;
+    color.rgb = mix(color.rgb, reflected, environment_strength * wetness * fresnel);
+    color.rgb = apply_fog(color.rgb, distance(eye_position, v_pos));
 
-    color.a = 1.0;
+    color.a = alpha;
+
+    if (debug_view == 1) {
+        color.rgb = 0.5 * normal + 0.5;
+    } else if (debug_view == 2) {
+        color.rgb = vec3(dot(depth_plane, vec4(v_pos, 1.0)));
+    } else if (debug_view == 3) {
+        color.rgb = vec3(fract(1.6 * v_pos.xy), 0.0);
+    }
 }
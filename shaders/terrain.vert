@@ -6,14 +6,21 @@ layout(location = 4) in vec2 uv;
 out vec3 v_pos;
 out vec3 v_normal;
 out float v_variant;
+out float v_rocky;
 
 uniform sampler2D displacement_map;
 uniform sampler2D variant_map;
+// 1 in the rocky biome, 0 outside, blurred at the boundary
+uniform sampler2D rocky_map;
 uniform mat4 view_proj;
 // This will only transform the base shape, not the displacement
 uniform mat4 model_mat;
 // A matrix that will right multiply a world coordinate into a uv coordinate
 uniform mat3 world_to_uv;
+// Copies of the terrain, one per instance. The xy offset moves a copy, the zw
+// signs mirror it, so that neighboring copies meet without cracks.
+#define MAX_TILES 49
+uniform vec4 tiles[MAX_TILES];
 
 // Min feature size is less than 5cm
 const float dx = 0.05;
@@ -32,13 +39,19 @@ void main() {
     float du = (z - zu) / dx;
     float dv = (z - zv) / dx;
 
-    v_variant = texture(variant_map, (world_to_uv * vec3(world_pos.xy, 1.0)).xy).r;
+    vec2 uv_pos = (world_to_uv * vec3(world_pos.xy, 1.0)).xy;
+    v_variant = texture(variant_map, uv_pos).r;
+    v_rocky = texture(rocky_map, uv_pos).r;
 
-    // FIXME: This might be wrong
-    v_normal = normalize(vec3(du, dv, 1.0));
+    // The maps are sampled on the original terrain, only the result is moved
+    vec4 tile = tiles[gl_InstanceID];
+
+    // The same finite differences as `generation::terrain_normal` on the CPU
+    v_normal = normalize(vec3(tile.zw * vec2(du, dv), 1.0));
 
     // Doing this after the model matrix means that the direction is hardcoded
     vec4 displaced_pos = world_pos + z * vec4(0.0, 0.0, 1.0, 0.0);
+    displaced_pos.xy = tile.zw * displaced_pos.xy + tile.xy;
     v_pos = displaced_pos.xyz;
     gl_Position = view_proj * displaced_pos;
 }
@@ -0,0 +1,11 @@
+#version 410 core
+
+in vec2 v_uv;
+
+uniform sampler2D source;
+
+out vec4 out_color;
+
+void main() {
+    out_color = vec4(texture(source, v_uv).rgb, 1.0);
+}
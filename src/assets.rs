@@ -1,19 +1,22 @@
 use noise::NoiseFn;
 
 use crate::mesh::{ElementMeshVAO, Mesh};
+use crate::quality::{QualityPreset, QualitySettings};
 use crate::scene::SCENE_SIZE;
 use crate::shader::{Shader, ShaderBuilder};
 use crate::texture::Texture;
 
+use std::collections::HashMap;
+use std::error::Error;
+use std::path::PathBuf;
 use std::rc::Rc;
-use std::time::Instant;
 
+/// Wraps the loading in a `tracing` span with the `trace` feature, does nothing otherwise.
 macro_rules! time {
     ($name:expr, $block:expr) => {{
-        let start = Instant::now();
-        let result = $block;
-        println!("Loading {} took {}ms", $name, start.elapsed().as_millis());
-        result
+        #[cfg(feature = "trace")]
+        let _span = tracing::info_span!("load", asset = %$name).entered();
+        $block
     }};
 }
 
@@ -42,15 +45,26 @@ pub struct Assets {
     pub bush1_model: Rc<Mesh>,
     pub shrub_model: Rc<Mesh>,
     pub tree_model: Rc<Mesh>,
+    /// The diffuse maps of the models' materials by path, see
+    /// [`Mesh::diffuse_texture`] and [`Assets::load_material_texture`].
+    pub material_textures: HashMap<PathBuf, Rc<Texture>>,
     pub terrain_quad_mesh: Rc<ElementMeshVAO>,
+    /// The scenes and the terrain mesh above follow these, see
+    /// [`Assets::set_quality`].
+    pub quality: QualitySettings,
 
     pub terrain_shader: Rc<Shader>,
     pub foliage_shader: Rc<Shader>,
+    pub decal_shader: Rc<Shader>,
 }
 
 impl Assets {
     pub fn load() -> Self {
-        time!(
+        Self::load_with_quality(QualityPreset::default().settings())
+    }
+
+    pub fn load_with_quality(quality: QualitySettings) -> Self {
+        let mut assets = time!(
             "ASSETS",
             Assets {
                 // Compile shaders
@@ -59,7 +73,7 @@ impl Assets {
                         .with_shader_file("shaders/terrain.vert")
                         .with_shader_file("shaders/terrain.frag")
                         .link()
-                        .expect("Terrain shader had errors. See stdout.");
+                        .unwrap_or_else(|err| panic!("Terrain shader had errors: {err}"));
                     Rc::new(shader)
                 }),
                 foliage_shader: time!("foliage shader", {
@@ -67,59 +81,61 @@ impl Assets {
                         .with_shader_file("shaders/foliage_instanced.vert")
                         .with_shader_file("shaders/foliage_shaded_texture.frag")
                         .link()
-                        .expect("Foliage shader had errors. See stdout.");
+                        .unwrap_or_else(|err| panic!("Foliage shader had errors: {err}"));
+                    Rc::new(shader)
+                }),
+                decal_shader: time!("decal shader", {
+                    let shader = ShaderBuilder::new()
+                        .with_shader_file("shaders/decal_instanced.vert")
+                        .with_shader_file("shaders/contact_shadow.frag")
+                        .link()
+                        .unwrap_or_else(|err| panic!("Decal shader had errors: {err}"));
                     Rc::new(shader)
                 }),
 
                 // Load Textures
                 moss_tex: time!("moss texture", {
-                    let tex = Texture::from_file("textures/moss1.jpeg")
+                    let tex = Texture::from_file_srgb("textures/moss1.jpeg")
                         .expect("Loading moss texture failed");
                     tex.enable_mipmap();
                     Rc::new(tex)
                 }),
                 ground_tex: time!("ground texture", {
-                    let tex = Texture::from_file("textures/ground1.jpeg")
+                    let tex = Texture::from_file_srgb("textures/ground1.jpeg")
                         .expect("Loading ground texture failed");
                     tex.enable_mipmap();
                     Rc::new(tex)
                 }),
                 rock_tex: time!("rock texture", {
-                    let tex = Texture::from_file("textures/rock1.jpeg")
+                    let tex = Texture::from_file_srgb("textures/rock1.jpeg")
                         .expect("Loading rock texture failed");
                     tex.enable_mipmap();
                     Rc::new(tex)
                 }),
                 bush_tex: time!("bush texture", {
-                    let tex = Texture::from_file("textures/bush_masked1.png")
+                    let tex = Texture::from_file_srgb("textures/bush_masked1.png")
                         .expect("Loading bush texture failed");
                     tex.enable_mipmap();
                     Rc::new(tex)
                 }),
                 shrub_side_tex: time!("bush side texture", {
-                    let tex = Texture::from_file("textures/bush_masked2.png")
+                    let tex = Texture::from_file_srgb("textures/bush_masked2.png")
                         .expect("Loading bush side texture failed");
                     tex.enable_mipmap();
                     Rc::new(tex)
                 }),
                 sapling_tex: time!("shrub texture", {
-                    let tex = Texture::new::<f32, crate::texture::format::RGBA>(
-                        1,
-                        1,
-                        &[92. / 255., 63. / 255., 83. / 255., 1.0],
-                    );
+                    let tex =
+                        Texture::new::<u8, crate::texture::format::SRGBA>(1, 1, &[92, 63, 83, 255]);
                     Rc::new(tex)
                 }),
                 transparent_tex: time!("transparent texture", {
-                    let tex = Texture::new::<f32, crate::texture::format::RGBA>(
-                        1,
-                        1,
-                        &[85. / 255., 92. / 255., 42. / 255., 0.0],
-                    );
+                    let tex =
+                        Texture::new::<u8, crate::texture::format::SRGBA>(1, 1, &[85, 92, 42, 0]);
                     Rc::new(tex)
                 }),
                 bark_tex: time!("bark texture", {
-                    let tex = Texture::from_file("textures/bark1.jpeg")
+                    let tex = Texture::from_file_srgb("textures/bark1.jpeg")
                         .expect("Loading bark texture failed");
                     tex.enable_mipmap();
                     Rc::new(tex)
@@ -135,57 +151,135 @@ impl Assets {
 
                 // Load obj models
                 sapling_model: time!("shrub model", {
-                    let model = Mesh::load("models/shrub2.obj");
+                    let model =
+                        Mesh::load("models/shrub2.obj").unwrap_or_else(|err| panic!("{err}"));
                     Rc::new(model)
                 }),
                 bush1_model: time!("bush model", {
-                    let model = Mesh::load("models/bush1.obj");
+                    let model =
+                        Mesh::load("models/bush1.obj").unwrap_or_else(|err| panic!("{err}"));
                     Rc::new(model)
                 }),
                 shrub_model: time!("bush model", {
-                    let model = Mesh::load("models/bush2.obj");
+                    let model =
+                        Mesh::load("models/bush2.obj").unwrap_or_else(|err| panic!("{err}"));
                     Rc::new(model)
                 }),
                 tree_model: time!("tree model", {
-                    let model = Mesh::load("models/tree1.obj");
+                    let model =
+                        Mesh::load("models/tree1.obj").unwrap_or_else(|err| panic!("{err}"));
                     Rc::new(model)
                 }),
+                material_textures: HashMap::new(),
                 terrain_quad_mesh: time!("terrain mesh", {
-                    let quad = Mesh::quad_mesh(256);
-                    let quad_vao = ElementMeshVAO::new_from_mesh(&quad);
-                    Rc::new(quad_vao)
+                    terrain_mesh(quality.terrain_tessellation)
                 }),
+                quality,
             }
-        )
+        );
+
+        let models = [
+            assets.sapling_model.clone(),
+            assets.bush1_model.clone(),
+            assets.shrub_model.clone(),
+            assets.tree_model.clone(),
+        ];
+        for model in &models {
+            time!("material texture", {
+                assets
+                    .load_material_texture(model)
+                    .unwrap_or_else(|err| panic!("{err}"));
+            });
+        }
+
+        if cfg!(debug_assertions) {
+            assets.terrain_shader.check_attributes(
+                &assets.terrain_quad_mesh.enabled_attributes(),
+                "the terrain mesh",
+            );
+        }
+        assets
+    }
+
+    /// Loads the diffuse map of the model's material into
+    /// [`Assets::material_textures`], unless it is there already. `None` for
+    /// models without one.
+    pub fn load_material_texture(
+        &mut self,
+        model: &Mesh,
+    ) -> Result<Option<Rc<Texture>>, Box<dyn Error>> {
+        let Some(path) = &model.diffuse_texture else {
+            return Ok(None);
+        };
+        if let Some(texture) = self.material_textures.get(path) {
+            return Ok(Some(texture.clone()));
+        }
+        let texture = Texture::from_file_srgb(path)
+            .map_err(|err| format!("Loading the model texture {} failed: {err}", path.display()))?;
+        texture.enable_mipmap();
+        let texture = Rc::new(texture);
+        self.material_textures.insert(path.clone(), texture.clone());
+        Ok(Some(texture))
+    }
+
+    /// Applies to the scenes created afterwards. Rebuilds the terrain mesh if
+    /// its tessellation changed.
+    pub fn set_quality(&mut self, quality: QualitySettings) {
+        if quality.terrain_tessellation != self.quality.terrain_tessellation {
+            self.terrain_quad_mesh = terrain_mesh(quality.terrain_tessellation);
+        }
+        self.quality = quality;
     }
 }
 
+fn terrain_mesh(tessellation: u32) -> Rc<ElementMeshVAO> {
+    let quad = Mesh::quad_mesh(tessellation.max(1));
+    Rc::new(ElementMeshVAO::new_from_mesh(&quad))
+}
+
 pub struct ImageNoiseFnWrapper<const CHANNEL: usize> {
     image: Rc<image::RgbaImage>,
+    /// The image is stretched over the scene from (0, 0) to (size, size).
+    size: f64,
 }
 
 impl ImageNoiseFnWrapper<0> {
     pub fn new_red(image: Rc<image::RgbaImage>) -> Self {
-        ImageNoiseFnWrapper { image }
+        ImageNoiseFnWrapper::new(image)
     }
 }
 
 impl ImageNoiseFnWrapper<1> {
     pub fn new_green(image: Rc<image::RgbaImage>) -> Self {
-        ImageNoiseFnWrapper { image }
+        ImageNoiseFnWrapper::new(image)
     }
 }
 
 impl ImageNoiseFnWrapper<2> {
     pub fn new_blue(image: Rc<image::RgbaImage>) -> Self {
-        ImageNoiseFnWrapper { image }
+        ImageNoiseFnWrapper::new(image)
+    }
+}
+
+impl<const CHANNEL: usize> ImageNoiseFnWrapper<CHANNEL> {
+    pub(crate) fn new(image: Rc<image::RgbaImage>) -> Self {
+        ImageNoiseFnWrapper {
+            image,
+            size: SCENE_SIZE as f64,
+        }
+    }
+
+    /// Stretches the image over a scene of this size instead of [`SCENE_SIZE`].
+    pub fn with_scene_size(mut self, size: f32) -> Self {
+        self.size = size as f64;
+        self
     }
 }
 
 impl<const CHANNEL: usize> NoiseFn<f64, 2> for ImageNoiseFnWrapper<CHANNEL> {
     fn get(&self, point: [f64; 2]) -> f64 {
-        let x = (point[0] / SCENE_SIZE as f64 * self.image.width() as f64) as u32;
-        let y = (point[1] / SCENE_SIZE as f64 * self.image.height() as f64) as u32;
+        let x = (point[0] / self.size * self.image.width() as f64) as u32;
+        let y = (point[1] / self.size * self.image.height() as f64) as u32;
         // clamp x & y
         let x = x.clamp(0, self.image.width() - 1);
         let y = y.clamp(0, self.image.height() - 1);
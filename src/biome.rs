@@ -0,0 +1,64 @@
+//! Discrete regions of the terrain, each with its own look and vegetation.
+
+use noise::NoiseFn;
+use std::rc::Rc;
+
+/// Above this height the ground is bare rock.
+const ROCKY_HEIGHT: f64 = 1.4;
+/// Below this variant value the moss takes over the open ground.
+const MOSSY_VARIANT: f64 = 0.25;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BiomeId {
+    /// Open forest floor with blueberry bushes.
+    Meadow,
+    /// High ground, where mostly rock shows through.
+    Rocky,
+    /// Low lying, damp areas covered in moss.
+    Mossy,
+}
+
+/// Quantizes the continuous height and variant maps into biomes.
+pub struct BiomeMap {
+    height_map: Rc<dyn NoiseFn<f64, 2>>,
+    variant_map: Rc<dyn NoiseFn<f64, 2>>,
+}
+
+impl BiomeMap {
+    pub fn new(height_map: Rc<dyn NoiseFn<f64, 2>>, variant_map: Rc<dyn NoiseFn<f64, 2>>) -> Self {
+        BiomeMap {
+            height_map,
+            variant_map,
+        }
+    }
+
+    pub fn biome_at(&self, x: f32, y: f32) -> BiomeId {
+        let point = [x as f64, y as f64];
+        if self.height_map.get(point) > ROCKY_HEIGHT {
+            BiomeId::Rocky
+        } else if self.variant_map.get(point) < MOSSY_VARIANT {
+            BiomeId::Mossy
+        } else {
+            BiomeId::Meadow
+        }
+    }
+
+    /// One inside the biome, zero outside. Sampled into a linearly filtered
+    /// texture, this gives soft boundaries.
+    pub fn mask(&self, biome: BiomeId) -> BiomeMask<'_> {
+        BiomeMask { map: self, biome }
+    }
+}
+
+/// See [`BiomeMap::mask`].
+pub struct BiomeMask<'a> {
+    map: &'a BiomeMap,
+    biome: BiomeId,
+}
+
+impl NoiseFn<f64, 2> for BiomeMask<'_> {
+    fn get(&self, point: [f64; 2]) -> f64 {
+        let biome = self.map.biome_at(point[0] as f32, point[1] as f32);
+        (biome == self.biome) as u8 as f64
+    }
+}
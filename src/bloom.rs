@@ -0,0 +1,171 @@
+//! Bloom post-process, letting bright parts of the image bleed into their
+//! surroundings.
+//!
+//! The bright pixels are extracted into a half resolution buffer, which is then
+//! blurred and downsampled a few times. Going back up, each level is added onto
+//! the next larger one, and finally the sum is added onto the scene.
+
+use crate::framebuffer::{Framebuffer, FullscreenQuad};
+use crate::shader::{Shader, ShaderBuilder};
+use crate::texture::Texture;
+
+/// Number of blur levels, each half the size of the previous one.
+const BLOOM_LEVELS: usize = 4;
+
+pub struct Bloom {
+    /// Brightness above which pixels start to glow.
+    pub threshold: f32,
+    /// How strongly the blurred glow is added. Zero disables the bloom.
+    pub intensity: f32,
+
+    extract_shader: Shader,
+    blur_shader: Shader,
+    copy_shader: Shader,
+    composite_shader: Shader,
+    quad: FullscreenQuad,
+
+    /// Ping-pong buffer pairs at half, quarter, ... of the screen size.
+    /// The result of each level ends up in the first one.
+    levels: Vec<(Framebuffer, Framebuffer)>,
+    screen_size: (i32, i32),
+}
+
+impl Bloom {
+    pub fn new() -> Self {
+        let load = |frag: &str| {
+            ShaderBuilder::new()
+                .with_shader_file("shaders/fullscreen.vert")
+                .with_shader_file(frag)
+                .link()
+                .unwrap_or_else(|err| panic!("Bloom shader had errors: {err}"))
+        };
+
+        Bloom {
+            threshold: 1.0,
+            intensity: 0.0,
+            extract_shader: load("shaders/bloom_extract.frag"),
+            blur_shader: load("shaders/bloom_blur.frag"),
+            copy_shader: load("shaders/texture_copy.frag"),
+            composite_shader: load("shaders/bloom_composite.frag"),
+            quad: FullscreenQuad::new(),
+            levels: Vec::new(),
+            screen_size: (0, 0),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.intensity > 0.0
+    }
+
+    /// Draws the `scene` texture with bloom applied into the window.
+    pub fn apply(&mut self, scene: &Texture, width: i32, height: i32) {
+        if self.screen_size != (width, height) {
+            self.create_levels(width, height);
+        }
+
+        // The quad would vanish with clockwise front faces
+        let culling = unsafe { gl::IsEnabled(gl::CULL_FACE) } == gl::TRUE;
+        unsafe {
+            gl::Disable(gl::DEPTH_TEST);
+            gl::Disable(gl::CULL_FACE);
+        }
+
+        // Extract the bright parts
+        self.levels[0].0.bind();
+        unsafe {
+            self.extract_shader.activate();
+            scene.activate(0);
+            gl::Uniform1i(self.extract_shader.get_uniform_location("scene"), 0);
+            gl::Uniform1f(
+                self.extract_shader.get_uniform_location("threshold"),
+                self.threshold,
+            );
+        }
+        self.quad.render();
+
+        // Blur, each smaller level starting from the previous one
+        for i in 0..self.levels.len() {
+            let source = if i == 0 {
+                &self.levels[0].0
+            } else {
+                &self.levels[i - 1].0
+            };
+            let (result, temp) = &self.levels[i];
+
+            let (source_width, _) = source.size();
+            temp.bind();
+            self.blur(source.color(), (1.0 / source_width as f32, 0.0));
+
+            let (_, temp_height) = temp.size();
+            result.bind();
+            self.blur(temp.color(), (0.0, 1.0 / temp_height as f32));
+        }
+
+        // Sum up the levels from smallest to largest
+        unsafe {
+            gl::Enable(gl::BLEND);
+            gl::BlendFunc(gl::ONE, gl::ONE);
+        }
+        for i in (0..self.levels.len() - 1).rev() {
+            self.levels[i].0.bind();
+            unsafe {
+                self.copy_shader.activate();
+                self.levels[i + 1].0.color().activate(0);
+                gl::Uniform1i(self.copy_shader.get_uniform_location("source"), 0);
+            }
+            self.quad.render();
+        }
+        unsafe {
+            gl::Disable(gl::BLEND);
+        }
+
+        Framebuffer::bind_default(width, height);
+        unsafe {
+            self.composite_shader.activate();
+            scene.activate(0);
+            gl::Uniform1i(self.composite_shader.get_uniform_location("scene"), 0);
+            self.levels[0].0.color().activate(1);
+            gl::Uniform1i(self.composite_shader.get_uniform_location("bloom"), 1);
+            gl::Uniform1f(
+                self.composite_shader.get_uniform_location("intensity"),
+                self.intensity,
+            );
+        }
+        self.quad.render();
+
+        unsafe {
+            gl::Enable(gl::DEPTH_TEST);
+            if culling {
+                gl::Enable(gl::CULL_FACE);
+            }
+        }
+    }
+
+    fn blur(&self, source: &Texture, direction: (f32, f32)) {
+        unsafe {
+            self.blur_shader.activate();
+            source.activate(0);
+            gl::Uniform1i(self.blur_shader.get_uniform_location("source"), 0);
+            gl::Uniform2f(
+                self.blur_shader.get_uniform_location("direction"),
+                direction.0,
+                direction.1,
+            );
+        }
+        self.quad.render();
+    }
+
+    fn create_levels(&mut self, width: i32, height: i32) {
+        self.levels = (1..=BLOOM_LEVELS)
+            .map(|level| {
+                let level_width = (width >> level).max(1);
+                let level_height = (height >> level).max(1);
+                (
+                    Framebuffer::new(level_width, level_height, None),
+                    Framebuffer::new(level_width, level_height, None),
+                )
+            })
+            .collect();
+        self.screen_size = (width, height);
+    }
+}
@@ -0,0 +1,194 @@
+//! Orientation of the viewpoint the scene is rendered from.
+
+use nalgebra_glm as glm;
+use serde::{Deserialize, Serialize};
+
+/// The up direction and roll of the camera. Where it stands and looks is
+/// decided by the scene.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Camera {
+    /// The world direction that points upwards on the screen without roll.
+    pub up: glm::Vec3,
+    /// Rotation around the view direction in radians. Positive values roll
+    /// to the right, so the horizon tilts the other way.
+    pub roll: f32,
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Camera {
+            up: glm::Vec3::z(),
+            roll: 0.0,
+        }
+    }
+}
+
+impl Camera {
+    /// The right, up and forward axes of the camera looking along `forward`.
+    ///
+    /// When looking straight along the up vector, the usual cross product
+    /// vanishes. The camera then keeps the world axis that is least aligned
+    /// with the view as its up instead.
+    pub fn basis(&self, forward: &glm::Vec3) -> (glm::Vec3, glm::Vec3, glm::Vec3) {
+        let forward = glm::normalize(forward);
+        let mut right = glm::cross(&forward, &self.up);
+        if right.norm() < 1e-4 {
+            let fallback = [glm::Vec3::x(), glm::Vec3::y(), glm::Vec3::z()]
+                .into_iter()
+                .min_by(|a, b| {
+                    glm::dot(a, &forward)
+                        .abs()
+                        .total_cmp(&glm::dot(b, &forward).abs())
+                })
+                .expect("There are three axes");
+            right = glm::cross(&forward, &fallback);
+        }
+        let right = glm::normalize(&right);
+        let up = glm::cross(&right, &forward);
+
+        let (sin, cos) = self.roll.sin_cos();
+        let rolled_right = cos * right - sin * up;
+        let rolled_up = sin * right + cos * up;
+        (rolled_right, rolled_up, forward)
+    }
+
+    /// The view matrix from `eye` towards `target`.
+    pub fn view_matrix(&self, eye: &glm::Vec3, target: &glm::Vec3) -> glm::Mat4 {
+        let (_, up, forward) = self.basis(&(target - eye));
+        glm::look_at(eye, &(eye + forward), &up)
+    }
+}
+
+/// Where the camera stands and looks.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum CameraMode {
+    /// The framing chosen by the scene, slowly bobbing up and down.
+    #[default]
+    Scene,
+    /// Flown around with the keyboard.
+    Free(FreeCamera),
+    /// Circling a point, to inspect the scene from all sides.
+    Orbit(OrbitCamera),
+}
+
+/// A camera that moves freely through the scene, turned by yaw and pitch
+/// around the z axis as up.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FreeCamera {
+    pub position: glm::Vec3,
+    /// Heading in radians, counterclockwise from the x axis seen from above.
+    pub yaw: f32,
+    /// Radians above the horizon.
+    pub pitch: f32,
+    /// In meters per second.
+    pub speed: f32,
+}
+
+/// Just short of straight up or down, where the heading would be lost.
+const MAX_PITCH: f32 = 1.55;
+
+impl FreeCamera {
+    /// A camera at `eye` looking towards `target`, to continue from another
+    /// view without a jump.
+    pub fn looking_at(eye: glm::Vec3, target: glm::Vec3) -> Self {
+        let direction = glm::normalize(&(target - eye));
+        FreeCamera {
+            position: eye,
+            yaw: direction.y.atan2(direction.x),
+            pitch: direction
+                .z
+                .clamp(-1.0, 1.0)
+                .asin()
+                .clamp(-MAX_PITCH, MAX_PITCH),
+            speed: 2.0,
+        }
+    }
+
+    pub fn forward(&self) -> glm::Vec3 {
+        let (sin_yaw, cos_yaw) = self.yaw.sin_cos();
+        let (sin_pitch, cos_pitch) = self.pitch.sin_cos();
+        glm::vec3(cos_pitch * cos_yaw, cos_pitch * sin_yaw, sin_pitch)
+    }
+
+    /// A point one meter ahead, for [`Camera::view_matrix`].
+    pub fn target(&self) -> glm::Vec3 {
+        self.position + self.forward()
+    }
+
+    /// Turns by the given angles in radians, positive yaw to the left and
+    /// positive pitch upwards.
+    pub fn rotate(&mut self, yaw: f32, pitch: f32) {
+        self.yaw = (self.yaw + yaw).rem_euclid(std::f32::consts::TAU);
+        self.pitch = (self.pitch + pitch).clamp(-MAX_PITCH, MAX_PITCH);
+    }
+
+    /// Moves for `dt` seconds along `direction`, given relative to the heading
+    /// with x to the right, y forwards and z straight up. Forwards stays level
+    /// even when looking down, and diagonals are no faster than straight moves.
+    pub fn advance(&mut self, direction: glm::Vec3, dt: f32) {
+        let (sin_yaw, cos_yaw) = self.yaw.sin_cos();
+        let forward = glm::vec3(cos_yaw, sin_yaw, 0.0);
+        let right = glm::vec3(sin_yaw, -cos_yaw, 0.0);
+        let motion = direction.x * right + direction.y * forward + direction.z * glm::Vec3::z();
+        let motion = if motion.norm() > 1.0 {
+            motion.normalize()
+        } else {
+            motion
+        };
+        self.position += motion * self.speed * dt;
+    }
+}
+
+/// A camera on a sphere around a target, always looking at it.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct OrbitCamera {
+    pub target: glm::Vec3,
+    /// Distance to the target in meters.
+    pub radius: f32,
+    /// Radians counterclockwise from the x axis seen from above.
+    pub azimuth: f32,
+    /// Radians above the horizontal plane through the target.
+    pub elevation: f32,
+}
+
+impl OrbitCamera {
+    /// Keeps the camera from going through the target or flipping over.
+    const MIN_RADIUS: f32 = 0.5;
+    const MAX_RADIUS: f32 = 100.0;
+
+    /// Around `target` from the side of `eye`, at a distance of `radius`
+    /// and looking down by `elevation`.
+    pub fn around(target: glm::Vec3, eye: glm::Vec3, radius: f32, elevation: f32) -> Self {
+        let offset = eye - target;
+        OrbitCamera {
+            target,
+            radius: radius.clamp(Self::MIN_RADIUS, Self::MAX_RADIUS),
+            azimuth: offset.y.atan2(offset.x),
+            elevation: elevation.clamp(-MAX_PITCH, MAX_PITCH),
+        }
+    }
+
+    pub fn eye_position(&self) -> glm::Vec3 {
+        let (sin_azimuth, cos_azimuth) = self.azimuth.sin_cos();
+        let (sin_elevation, cos_elevation) = self.elevation.sin_cos();
+        self.target
+            + self.radius
+                * glm::vec3(
+                    cos_elevation * cos_azimuth,
+                    cos_elevation * sin_azimuth,
+                    sin_elevation,
+                )
+    }
+
+    /// Moves around the target by the given angles in radians,
+    /// counterclockwise seen from above and upwards.
+    pub fn rotate(&mut self, azimuth: f32, elevation: f32) {
+        self.azimuth = (self.azimuth + azimuth).rem_euclid(std::f32::consts::TAU);
+        self.elevation = (self.elevation + elevation).clamp(-MAX_PITCH, MAX_PITCH);
+    }
+
+    /// Multiplies the distance to the target, below one to come closer.
+    pub fn zoom(&mut self, factor: f32) {
+        self.radius = (self.radius * factor).clamp(Self::MIN_RADIUS, Self::MAX_RADIUS);
+    }
+}
@@ -0,0 +1,85 @@
+//! Bookmarked scene seeds, kept across runs in a JSON file.
+
+use std::error::Error;
+use std::path::PathBuf;
+
+#[derive(Debug, Default)]
+pub struct Favorites {
+    seeds: Vec<u32>,
+    /// Where the list is saved, `None` if there is no config directory.
+    path: Option<PathBuf>,
+    /// Index of the favorite shown last, to cycle from.
+    current: Option<usize>,
+}
+
+impl Favorites {
+    /// `favorites.json` in the `undergrowth` folder of the user's config directory.
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("undergrowth").join("favorites.json"))
+    }
+
+    /// Loads the favorites from the default path. A missing or broken file
+    /// gives an empty list, since losing the bookmarks shouldn't stop the demo.
+    pub fn load() -> Self {
+        let path = Self::default_path();
+        let seeds = match path.as_ref().map(std::fs::read_to_string) {
+            Some(Ok(json)) => serde_json::from_str(&json).unwrap_or_else(|err| {
+                eprintln!("Ignoring the broken favorites file: {err}");
+                Vec::new()
+            }),
+            _ => Vec::new(),
+        };
+        Favorites {
+            seeds,
+            path,
+            current: None,
+        }
+    }
+
+    pub fn seeds(&self) -> &[u32] {
+        &self.seeds
+    }
+
+    /// Adds the seed unless it already is a favorite, and saves the list.
+    pub fn add(&mut self, seed: u32) -> Result<(), Box<dyn Error>> {
+        match self.seeds.iter().position(|&favorite| favorite == seed) {
+            Some(index) => self.current = Some(index),
+            None => {
+                self.seeds.push(seed);
+                self.current = Some(self.seeds.len() - 1);
+            }
+        }
+        self.save()
+    }
+
+    /// The favorite after the one shown last, wrapping around.
+    pub fn next_seed(&mut self) -> Option<u32> {
+        self.step(1)
+    }
+
+    /// The favorite before the one shown last, wrapping around.
+    pub fn prev_seed(&mut self) -> Option<u32> {
+        self.step(self.seeds.len().saturating_sub(1))
+    }
+
+    fn step(&mut self, offset: usize) -> Option<u32> {
+        if self.seeds.is_empty() {
+            return None;
+        }
+        let index = match self.current {
+            Some(current) => (current + offset) % self.seeds.len(),
+            None => 0,
+        };
+        self.current = Some(index);
+        Some(self.seeds[index])
+    }
+
+    fn save(&self) -> Result<(), Box<dyn Error>> {
+        let path = self.path.as_ref().ok_or("No config directory to save to")?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, serde_json::to_string_pretty(&self.seeds)?)?;
+        Ok(())
+    }
+}
@@ -1,35 +1,251 @@
-use crate::mesh::{ElementMeshVAO, InstancedMeshesVAO, Mesh};
-use crate::renderer::Renderable;
+use crate::assets::ImageNoiseFnWrapper;
+use crate::biome::{BiomeId, BiomeMap};
+use crate::generation::{
+    contact_shadow_matrices, generate_foliage, Attraction, FoliageConfig, SpatialGrid,
+    DEFAULT_CLUMPING,
+};
+use crate::lighting::set_light_uniforms;
+use crate::mesh::{mat_floats, ElementMeshVAO, InstanceAttribute, InstancedMeshesVAO, Mesh};
+use crate::renderer::{RenderContext, Renderable};
 use crate::scene::SCENE_SIZE;
+use crate::seed::Seed;
 use crate::shader::Shader;
 use crate::texture::Texture;
 
 use nalgebra_glm as glm;
-use noise::{MultiFractal, NoiseFn};
-use rand::rngs::StdRng;
+use noise::NoiseFn;
 use rand::seq::SliceRandom;
-use rand::{Rng, SeedableRng};
-use rand_distr::Uniform;
+use rand::Rng;
+use std::collections::HashMap;
+use std::path::PathBuf;
 use std::rc::Rc;
 
 #[derive(Clone)]
 pub struct ShrubEntities {
+    /// The instances of each model, see [`ShrubEntitiesBuilder::with_models`].
+    pub models: Vec<FoliageModel>,
+    /// Optional glow texture, multiplied with `emissive_color`.
+    pub emissive: Option<Rc<Texture>>,
+    /// Emitted light, already scaled by the strength. Black means no glow.
+    pub emissive_color: glm::Vec3,
+    pub shader: Rc<Shader>,
+    pub contact_shadows: Option<Rc<ContactShadows>>,
+    /// How far the models sway per meter of height. Zero keeps them still.
+    pub wind_strength: f32,
+    /// Alpha masked models, like leaves, blend their edges onto everything
+    /// else in a second pass, see [`crate::renderer::render_entities`]. Their
+    /// chunks of [`DensityLod`] are drawn back to front.
+    pub transparent: bool,
+    /// Average position of the instances.
+    pub center: glm::Vec3,
+    /// The model matrices of the instances by where they stand, to find the
+    /// ones near a point, or for other layers to cluster around, see
+    /// [`ShrubEntitiesBuilder::with_attraction_to`].
+    pub instances: Rc<SpatialGrid<glm::Mat4>>,
+}
+
+/// The instances of a layer that share one model.
+#[derive(Clone)]
+pub struct FoliageModel {
     pub albedo: Rc<Texture>,
     pub vao: Rc<InstancedMeshesVAO>,
+    /// The chunks that are culled and drawn fewer in the distance, see
+    /// [`ShrubEntitiesBuilder::with_density_lod`]. Only missing without any
+    /// instances.
+    pub density_lod: Option<Rc<DensityLod>>,
+}
+
+/// Side length in meters of the chunks the density LOD thins and the frustum
+/// culls as a whole.
+const LOD_CHUNK_SIZE: f32 = 2.0;
+
+/// Cell size in meters of [`ShrubEntities::instances`], about the radius of
+/// typical queries.
+const INSTANCE_GRID_CELL_SIZE: f32 = 1.0;
+
+/// Thins out the instances with the distance to the camera.
+///
+/// The instances are grouped into square chunks, each sorted by a hash of the
+/// instance position. A chunk then draws only a prefix of its instances, so
+/// the same ones stay visible from frame to frame and thinning never swaps
+/// them around, it only drops the last ones. Chunks outside of the view
+/// aren't drawn at all.
+pub struct DensityLod {
+    /// Up to here all instances are drawn.
+    pub near_distance: f32,
+    /// From here on only `far_fraction` of the instances are drawn.
+    pub far_distance: f32,
+    pub far_fraction: f32,
+    chunks: Vec<LodChunk>,
+}
+
+/// A range of instances in the instance buffers.
+struct LodChunk {
+    first_instance: usize,
+    count: usize,
+    center: glm::Vec3,
+    /// The minimum and maximum corner around the instances and their origins,
+    /// without the wind sway.
+    bounding_box: (glm::Vec3, glm::Vec3),
+}
+
+impl DensityLod {
+    /// Reorders the models into chunks, the instance buffers must be uploaded
+    /// in the new order. The `mesh_box` is the bounding box of the model, see
+    /// [`Mesh::bounding_box`].
+    fn new(
+        model_mats: &mut [glm::Mat4],
+        mesh_box: (glm::Vec3, glm::Vec3),
+        (near, far, fraction): (f32, f32, f32),
+    ) -> Self {
+        let position = |model_mat: &glm::Mat4| model_mat.column(3).xyz();
+        let chunk_of = |model_mat: &glm::Mat4| {
+            let p = position(model_mat);
+            (
+                (p.x / LOD_CHUNK_SIZE).floor() as i32,
+                (p.y / LOD_CHUNK_SIZE).floor() as i32,
+            )
+        };
+        model_mats.sort_by_cached_key(|model_mat| {
+            (chunk_of(model_mat), position_hash(&position(model_mat)))
+        });
+
+        let (low, high) = mesh_box;
+        let corners: Vec<glm::Vec4> = (0..8)
+            .map(|corner| {
+                let pick = |axis: usize| {
+                    if corner & (1 << axis) == 0 {
+                        low[axis]
+                    } else {
+                        high[axis]
+                    }
+                };
+                glm::vec4(pick(0), pick(1), pick(2), 1.0)
+            })
+            .collect();
+        let chunks = model_mats
+            .chunk_by(|a, b| chunk_of(a) == chunk_of(b))
+            .scan(0, |first_instance, chunk| {
+                let center = chunk.iter().map(position).sum::<glm::Vec3>() / chunk.len() as f32;
+                let bounding_box = chunk
+                    .iter()
+                    .flat_map(|model_mat| {
+                        corners
+                            .iter()
+                            .map(move |corner| (model_mat * corner).xyz())
+                            .chain([position(model_mat)])
+                    })
+                    .fold((center, center), |(min, max), p| {
+                        (glm::min2(&min, &p), glm::max2(&max, &p))
+                    });
+                let lod_chunk = LodChunk {
+                    first_instance: *first_instance,
+                    count: chunk.len(),
+                    center,
+                    bounding_box,
+                };
+                *first_instance += chunk.len();
+                Some(lod_chunk)
+            })
+            .collect();
+
+        DensityLod {
+            near_distance: near,
+            far_distance: far,
+            far_fraction: fraction,
+            chunks,
+        }
+    }
+
+    /// How many of the `count` instances are drawn at this distance.
+    fn visible_count(&self, count: usize, distance: f32) -> usize {
+        let range = (self.far_distance - self.near_distance).max(f32::EPSILON);
+        let t = ((distance - self.near_distance) / range).clamp(0.0, 1.0);
+        let fraction = 1.0 + t * (self.far_fraction - 1.0);
+        ((fraction * count as f32).ceil() as usize).min(count)
+    }
+
+    /// Whether instances at this distance are thinned at all.
+    fn is_far(&self, distance: f32) -> bool {
+        self.far_fraction < 1.0 && distance > self.near_distance
+    }
+}
+
+/// A stable pseudo random order of the instances, which doesn't depend on the
+/// order they were generated in.
+fn position_hash(position: &glm::Vec3) -> u32 {
+    let mut hash = position.x.to_bits().wrapping_mul(0x9e37_79b9);
+    hash ^= position.y.to_bits().wrapping_mul(0x85eb_ca6b);
+    hash ^= hash >> 15;
+    hash = hash.wrapping_mul(0xc2b2_ae35);
+    hash ^ (hash >> 13)
+}
+
+/// One channel of an image stretched over a terrain of `size` meters, picking
+/// the wrapper for the channel at runtime.
+fn image_channel(
+    image: Rc<image::RgbaImage>,
+    channel: usize,
+    size: f32,
+) -> Box<dyn NoiseFn<f64, 2>> {
+    match channel {
+        0 => Box::new(ImageNoiseFnWrapper::<0>::new(image).with_scene_size(size)),
+        1 => Box::new(ImageNoiseFnWrapper::<1>::new(image).with_scene_size(size)),
+        2 => Box::new(ImageNoiseFnWrapper::<2>::new(image).with_scene_size(size)),
+        _ => Box::new(ImageNoiseFnWrapper::<3>::new(image).with_scene_size(size)),
+    }
+}
+
+/// Soft dark blobs on the ground below each instance, to make them look less
+/// pasted onto the terrain.
+pub struct ContactShadows {
+    pub vao: InstancedMeshesVAO,
     pub shader: Rc<Shader>,
+    /// Darkening in the center, between 0 and 1.
+    pub strength: f32,
+    /// The shadows are cut off at the edge of the terrain.
+    pub terrain_size: f32,
 }
 
 pub struct ShrubEntitiesBuilder {
     density: f64,
     num_limit: usize,
+    exact_count: Option<usize>,
+    count_tolerance: Option<f32>,
     height_map: Option<Rc<dyn NoiseFn<f64, 2>>>,
-    model: Option<Rc<Mesh>>,
+    /// Each with its own texture, if set by [`ShrubEntitiesBuilder::with_textured_models`].
+    models: Vec<(Rc<Mesh>, Option<Rc<Texture>>)>,
     z_scale_range: (f32, f32),
     scale_range: (f32, f32),
-    bounds: (f32, f32, f32, f32),
+    /// Defaults to the whole terrain.
+    bounds: Option<(f32, f32, f32, f32)>,
+    terrain_size: f32,
     texture: Option<Rc<Texture>>,
+    /// See [`ShrubEntitiesBuilder::with_material_textures`].
+    material_textures: HashMap<PathBuf, Rc<Texture>>,
     shader: Option<Rc<Shader>>,
     bushiness: Option<Box<dyn NoiseFn<f64, 2>>>,
+    density_mask: Option<(Rc<image::RgbaImage>, usize)>,
+    emissive: Option<Rc<Texture>>,
+    emissive_color: Option<(glm::Vec3, f32)>,
+    contact_shadow: Option<(f32, f32, Rc<Shader>)>,
+    height_range: Option<(f32, f32)>,
+    biome_map: Option<Rc<BiomeMap>>,
+    biome: Option<BiomeId>,
+    transparent: bool,
+    density_lod: Option<(f32, f32, f32)>,
+    clumping: (f64, usize),
+    jitter: f32,
+    attraction: Option<Attraction>,
+    slope_alignment: f32,
+    wind_strength: f32,
+    color_jitter: glm::Vec3,
+}
+
+impl Default for ShrubEntitiesBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ShrubEntitiesBuilder {
@@ -37,107 +253,340 @@ impl ShrubEntitiesBuilder {
         ShrubEntitiesBuilder {
             density: 0.0,
             num_limit: usize::MAX,
+            exact_count: None,
+            count_tolerance: None,
             height_map: None,
-            model: None,
+            models: Vec::new(),
             z_scale_range: (1.0, 1.0),
             scale_range: (1.0, 1.0),
-            bounds: (0., SCENE_SIZE, 0., SCENE_SIZE),
+            bounds: None,
+            terrain_size: SCENE_SIZE,
             texture: None,
+            material_textures: HashMap::new(),
             shader: None,
             bushiness: None,
+            density_mask: None,
+            emissive: None,
+            emissive_color: None,
+            contact_shadow: None,
+            height_range: None,
+            biome_map: None,
+            biome: None,
+            transparent: false,
+            density_lod: None,
+            clumping: DEFAULT_CLUMPING,
+            jitter: 0.0,
+            attraction: None,
+            slope_alignment: 0.0,
+            wind_strength: 0.0,
+            color_jitter: glm::Vec3::zeros(),
         }
     }
 
     pub fn load(self, seed: u32) -> ShrubEntities {
-        let mut rng = StdRng::seed_from_u64(seed as u64);
-
         let height_map = self.height_map.expect("Height map is required");
-        let model = self.model.expect("Model source file path is required");
-        let texture = self.texture.expect("Texture is required");
+        assert!(!self.models.is_empty(), "A model is required");
         let shader = self.shader.expect("Shader is required");
 
-        let mesh_vao = ElementMeshVAO::new_from_mesh(&model);
-
-        let distr = probability_distribution(self.density, rng.gen());
-        let mut positions = if let Some(bushiness) = self.bushiness {
-            let bushiness = noise::Power::new(bushiness, noise::Constant::new(2.0));
-            let bushiness = noise::ScaleBias::new(bushiness)
-                .set_scale(2.0)
-                .set_bias(0.1);
-
-            let distr = noise::Multiply::new(distr, bushiness);
-            generate_points_on_distribution(distr, self.bounds, rng.gen())
-        } else {
-            generate_points_on_distribution(distr, self.bounds, rng.gen())
+        let config = FoliageConfig {
+            density: self.density,
+            num_limit: self.num_limit,
+            exact_count: self.exact_count,
+            count_tolerance: self.count_tolerance,
+            height_map: height_map.clone(),
+            z_scale_range: self.z_scale_range,
+            scale_range: self.scale_range,
+            bounds: self
+                .bounds
+                .unwrap_or((0., self.terrain_size, 0., self.terrain_size)),
+            bushiness: self.bushiness,
+            density_mask: self
+                .density_mask
+                .map(|(image, channel)| image_channel(image, channel, self.terrain_size)),
+            height_range: self.height_range,
+            biome: self.biome.map(|biome| {
+                let biome_map = self.biome_map.expect("Biome map is required for a biome");
+                (biome_map, biome)
+            }),
+            clumping: self.clumping,
+            jitter: self.jitter,
+            attraction: self.attraction,
+            slope_alignment: self.slope_alignment,
         };
-
-        if positions.len() > self.num_limit {
-            positions.shuffle(&mut rng);
-            positions.resize_with(self.num_limit, || {
-                unreachable!("Len is less than the limit")
-            });
+        let mut model_mats = generate_foliage(&config, seed);
+        // Dropped at random like the surplus over the limit, rather than
+        // failing to upload the instance buffers
+        let mut attributes = vec![
+            InstanceAttribute::MODEL_MAT,
+            InstanceAttribute::NORMAL_MAT,
+            InstanceAttribute::COLOR_JITTER,
+        ];
+        if self.contact_shadow.is_some() {
+            attributes.push(InstanceAttribute::MODEL_MAT);
+        }
+        let fitting = InstancedMeshesVAO::max_instances(&attributes);
+        if model_mats.len() > fitting {
+            eprintln!(
+                "Only {fitting} of {} instances fit into the free GPU memory, dropping the rest",
+                model_mats.len()
+            );
+            model_mats.shuffle(&mut Seed::from(seed).derive("surplus").rng());
+            model_mats.truncate(fitting);
         }
-        let positions = positions;
-
-        println!("Spawned {} entities", positions.len());
 
-        // For some very weird ass reason do the translate & scale functions right multiply,
-        // thus for scale than translate, I need to translate then scale...
+        println!("Spawned {} entities", model_mats.len());
+        let center = model_mats
+            .iter()
+            .map(|model_mat| model_mat.column(3).xyz())
+            .sum::<glm::Vec3>()
+            / model_mats.len().max(1) as f32;
+        let instances = SpatialGrid::new(
+            model_mats
+                .iter()
+                .map(|model_mat| (model_mat.column(3).xy(), *model_mat)),
+            INSTANCE_GRID_CELL_SIZE,
+        );
+
+        let contact_shadows = self.contact_shadow.and_then(|(radius, strength, shader)| {
+            let shadow_mats = contact_shadow_matrices(
+                &model_mats,
+                height_map.as_ref(),
+                radius,
+                self.terrain_size,
+            );
 
-        let model_mats: Vec<glm::Mat4> = positions
-            .into_iter()
-            .map(|p| {
-                // TODO: rotation based on height gradient
-                let rotation_angle: f32 = rng.sample(Uniform::new(0.0, 6.28));
-                // TODO: scale in a more natural distribution
-                let z_scale: f32 = rng.sample(Uniform::new_inclusive(
-                    self.z_scale_range.0,
-                    self.z_scale_range.1,
-                ));
-                let scale: f32 = rng.sample(Uniform::new_inclusive(
-                    self.scale_range.0,
-                    self.scale_range.1,
-                ));
-
-                glm::scale(
-                    &glm::rotate_z(
-                        &glm::translate(
-                            &glm::identity(),
-                            &glm::vec3(p.x, p.y, height_map.get([p.x as f64, p.y as f64]) as f32),
-                        ),
-                        rotation_angle,
-                    ),
-                    &glm::vec3(scale, scale, scale * z_scale),
-                )
+            let quad_vao = ElementMeshVAO::new_from_mesh(&Mesh::quad());
+            // The decals are flat, the shader doesn't need normal matrices
+            let vao = InstancedMeshesVAO::from_existing_with_attributes(
+                quad_vao,
+                shadow_mats.len(),
+                &[(InstanceAttribute::MODEL_MAT, mat_floats(&shadow_mats))],
+            );
+            // Only a detail, the foliage still shows without them
+            let vao = vao
+                .map_err(|err| eprintln!("Skipping the contact shadows: {err}"))
+                .ok()?;
+            if cfg!(debug_assertions) {
+                shader.check_attributes(&vao.enabled_attributes(), "the contact shadow VAO");
+            }
+            Some(Rc::new(ContactShadows {
+                vao,
+                shader,
+                strength,
+                terrain_size: self.terrain_size,
+            }))
+        });
+
+        // Each instance picks a model, then every model gets its own buffers
+        let mut model_rng = Seed::from(seed).derive("models").rng();
+        let mut model_groups = vec![Vec::new(); self.models.len()];
+        for model_mat in &model_mats {
+            model_groups[model_rng.gen_range(0..self.models.len())].push(*model_mat);
+        }
+        let mut color_rng = Seed::from(seed).derive("colors").rng();
+        let models = self
+            .models
+            .iter()
+            .zip(model_groups)
+            .map(|((model, model_texture), mut model_mats)| {
+                let texture = model_texture.as_ref().or(self.texture.as_ref());
+                let albedo = texture.cloned().unwrap_or_else(|| {
+                    let path = model
+                        .diffuse_texture
+                        .as_ref()
+                        .expect("Texture is required, if the model's material has none");
+                    let texture = self.material_textures.get(path).unwrap_or_else(|| {
+                        panic!(
+                            "The model texture {} isn't loaded, see Assets::load_material_texture",
+                            path.display()
+                        )
+                    });
+                    texture.clone()
+                });
+                // Always in chunks, so they can be culled and the transparent
+                // ones drawn back to front, even without thinning any of them
+                let lod = self.density_lod.unwrap_or((0.0, 0.0, 1.0));
+                let mut density_lod = Some(Rc::new(DensityLod::new(
+                    &mut model_mats,
+                    model.bounding_box(),
+                    lod,
+                )));
+                // After the sorting by distance, so the colors belong to the final order
+                let color_jitter: Vec<glm::Vec3> = model_mats
+                    .iter()
+                    .map(|_| {
+                        self.color_jitter.map(|range| {
+                            if range > 0.0 {
+                                color_rng.gen_range(-range..=range)
+                            } else {
+                                0.0
+                            }
+                        })
+                    })
+                    .collect();
+                let upload = |model_mats: &[glm::Mat4], color_jitter: &[glm::Vec3]| {
+                    InstancedMeshesVAO::from_existing_with_models(
+                        ElementMeshVAO::new_from_mesh(model),
+                        model_mats,
+                        color_jitter,
+                    )
+                };
+                let vao = upload(&model_mats, &color_jitter).unwrap_or_else(|err| {
+                    // The memory may have run out since the count was capped
+                    eprintln!("Dropping {} foliage instances: {err}", model_mats.len());
+                    density_lod = None;
+                    upload(&[], &[]).expect("No instances always fit")
+                });
+                if cfg!(debug_assertions) {
+                    shader.check_attributes(&vao.enabled_attributes(), "the foliage VAO");
+                }
+                FoliageModel {
+                    albedo,
+                    vao: Rc::new(vao),
+                    density_lod,
+                }
             })
             .collect();
 
-        let instanced_vao = InstancedMeshesVAO::from_existing_with_models(mesh_vao, &model_mats);
+        // An emissive texture on its own glows with its own colors.
+        let emissive_color = match (self.emissive_color, &self.emissive) {
+            (Some((color, strength)), _) => color * strength,
+            (None, Some(_)) => glm::vec3(1.0, 1.0, 1.0),
+            (None, None) => glm::Vec3::zeros(),
+        };
 
         ShrubEntities {
-            albedo: texture,
-            vao: Rc::new(instanced_vao),
+            models,
+            emissive: self.emissive,
+            emissive_color,
             shader,
+            contact_shadows,
+            wind_strength: self.wind_strength,
+            transparent: self.transparent,
+            center,
+            instances: Rc::new(instances),
         }
     }
 
+    /// Approximate instances per square meter. The actual count varies a lot
+    /// between seeds, see [`ShrubEntitiesBuilder::with_count_normalization`]
+    /// and [`ShrubEntitiesBuilder::with_exact_count`] for steadier ones.
     pub fn with_density(mut self, density: f64) -> Self {
         self.density = density;
         self
     }
 
+    /// Frequency in 1/m and number of octaves of the noise that varies the
+    /// density. Lower frequencies give broad groves, higher ones fine patches.
+    /// Defaults to [`DEFAULT_CLUMPING`].
+    pub fn with_clumping(mut self, frequency: f64, octaves: usize) -> Self {
+        self.clumping = (frequency, octaves);
+        self
+    }
+
+    /// Moves every instance by a random offset of up to `amount` meters, so
+    /// sparse foliage doesn't line up with the placement grid. Off by default.
+    pub fn with_jitter(mut self, amount: f32) -> Self {
+        self.jitter = amount;
+        self
+    }
+
+    /// Tilts the instances towards the slope of the ground, from 0 for
+    /// standing upright, the default, to 1 for perpendicular to the ground.
+    /// Values in between keep some of the upright look on steep terrain.
+    pub fn with_slope_alignment(mut self, alignment: f32) -> Self {
+        self.slope_alignment = alignment;
+        self
+    }
+
+    /// Grows up to `1 + strength` times as densely within `radius` meters of
+    /// the `points`, e.g. `trees.instances.points()` of a layer of
+    /// [`ShrubEntities`], so the understory clusters around their bases.
+    pub fn with_attraction_to(
+        mut self,
+        points: impl IntoIterator<Item = glm::Vec2>,
+        radius: f32,
+        strength: f32,
+    ) -> Self {
+        self.attraction = Some(Attraction::new(points, radius, strength));
+        self
+    }
+
     pub fn with_entitiy_limit(mut self, num_limit: usize) -> Self {
         self.num_limit = num_limit;
         self
     }
 
+    /// Places exactly `count` instances, distributed like with a density.
+    /// Fewer, with a warning, only if the height range, biome, mask,
+    /// attraction and bushiness together leave too little room, see
+    /// [`FoliageConfig::exact_count`].
+    ///
+    /// Replaces [`ShrubEntitiesBuilder::with_density`] and the entity limit,
+    /// a density set as well only speeds up finding the count.
+    pub fn with_exact_count(mut self, count: usize) -> Self {
+        self.exact_count = Some(count);
+        self
+    }
+
+    /// Adds or drops instances until their number is within `tolerance`,
+    /// e.g. 0.1 for 10%, of what the density gives on average over all seeds,
+    /// keeping the shape of the distribution. The entity limit still caps the count.
+    pub fn with_count_normalization(mut self, tolerance: f32) -> Self {
+        self.count_tolerance = Some(tolerance);
+        self
+    }
+
     pub fn on_height_map(mut self, height_map: &Rc<dyn NoiseFn<f64, 2>>) -> Self {
         self.height_map = Some(height_map.clone());
         self
     }
 
+    pub fn on_biome_map(mut self, biome_map: &Rc<BiomeMap>) -> Self {
+        self.biome_map = Some(biome_map.clone());
+        self
+    }
+
+    /// Only places instances where the terrain height is between `min` and `max`.
+    pub fn with_height_range(mut self, min: f32, max: f32) -> Self {
+        self.height_range = Some((min, max));
+        self
+    }
+
+    /// Only places instances inside this biome. Requires a biome map.
+    pub fn with_biome(mut self, biome: BiomeId) -> Self {
+        self.biome = Some(biome);
+        self
+    }
+
+    /// Draws all instances up to `near_distance` from the camera, and linearly
+    /// fewer further away, down to `far_fraction` of them beyond `far_distance`.
+    /// For dense ground cover, where the far instances are barely visible.
+    pub fn with_density_lod(
+        mut self,
+        near_distance: f32,
+        far_distance: f32,
+        far_fraction: f32,
+    ) -> Self {
+        self.density_lod = Some((near_distance, far_distance, far_fraction.clamp(0.0, 1.0)));
+        self
+    }
+
+    /// Marks the texture as having cut out parts, see [`ShrubEntities::transparent`].
+    pub fn with_transparency(mut self) -> Self {
+        self.transparent = true;
+        self
+    }
+
     pub fn with_bounds(mut self, min_x: f32, max_x: f32, min_y: f32, max_y: f32) -> Self {
-        self.bounds = (min_x, max_x, min_y, max_y);
+        self.bounds = Some((min_x, max_x, min_y, max_y));
+        self
+    }
+
+    /// The terrain spans from (0, 0) to (`size`, `size`), defaults to [`SCENE_SIZE`].
+    /// Without explicit bounds, the instances cover all of it.
+    pub fn with_terrain_size(mut self, size: f32) -> Self {
+        self.terrain_size = size;
         self
     }
 
@@ -146,8 +595,36 @@ impl ShrubEntitiesBuilder {
         self
     }
 
+    /// Multiplies the density with a channel (0 to 3 for RGBA) of an image
+    /// stretched over the terrain. Nothing grows where it is zero, so paths and
+    /// clearings can be painted in. Unlike [`ShrubEntitiesBuilder::with_bushiness`],
+    /// the values are used as they are.
+    pub fn with_density_mask(mut self, mask: Rc<image::RgbaImage>, channel: usize) -> Self {
+        assert!(channel < 4, "An RGBA image only has 4 channels");
+        self.density_mask = Some((mask, channel));
+        self
+    }
+
     pub fn with_model(mut self, model: Rc<Mesh>) -> Self {
-        self.model = Some(model);
+        self.models = vec![(model, None)];
+        self
+    }
+
+    /// Lets every instance pick one of the models at random. Without a
+    /// texture for all of them, each one uses the texture of its material,
+    /// see [`ShrubEntitiesBuilder::with_material_textures`].
+    pub fn with_models(mut self, models: Vec<Rc<Mesh>>) -> Self {
+        self.models = models.into_iter().map(|model| (model, None)).collect();
+        self
+    }
+
+    /// Like [`ShrubEntitiesBuilder::with_models`], with a texture for each
+    /// model, for models made for different textures.
+    pub fn with_textured_models(mut self, models: Vec<(Rc<Mesh>, Rc<Texture>)>) -> Self {
+        self.models = models
+            .into_iter()
+            .map(|(model, texture)| (model, Some(texture)))
+            .collect();
         self
     }
 
@@ -163,19 +640,71 @@ impl ShrubEntitiesBuilder {
         self
     }
 
+    /// Overrides the diffuse map of the model's material, which is used
+    /// otherwise. Required for models without one.
     pub fn with_texture(mut self, texture: Rc<Texture>) -> Self {
         self.texture = Some(texture);
         self
     }
 
+    /// The loaded diffuse maps the models' materials name, by path, like
+    /// [`crate::assets::Assets::material_textures`]. Used for the models
+    /// without a texture set, so the files aren't decoded for every scene.
+    pub fn with_material_textures(mut self, textures: &HashMap<PathBuf, Rc<Texture>>) -> Self {
+        self.material_textures = textures.clone();
+        self
+    }
+
     pub fn with_shader(mut self, shader: Rc<Shader>) -> Self {
         self.shader = Some(shader);
         self
     }
+
+    /// Adds the texture as light emitted by the models, on top of the shading.
+    pub fn with_emissive(mut self, emissive: Rc<Texture>) -> Self {
+        self.emissive = Some(emissive);
+        self
+    }
+
+    /// Darkens the ground below each instance in a disc of `radius` meters
+    /// (before the instance scale), `strength` being the darkening in the center.
+    /// The decals are drawn with the `decal_shader`, like
+    /// [`crate::assets::Assets::decal_shader`].
+    pub fn with_contact_shadow(
+        mut self,
+        radius: f32,
+        strength: f32,
+        decal_shader: Rc<Shader>,
+    ) -> Self {
+        self.contact_shadow = Some((radius, strength, decal_shader));
+        self
+    }
+
+    /// Color and strength of the emitted light. Without an emissive texture
+    /// the whole model glows uniformly.
+    pub fn with_emissive_color(mut self, color: glm::Vec3, strength: f32) -> Self {
+        self.emissive_color = Some((color, strength));
+        self
+    }
+
+    /// Shifts the hue, saturation and value of each instance by up to the
+    /// components of `hsv_range`, with the hue in full turns, so not all
+    /// of them look the same. None by default.
+    pub fn with_color_jitter(mut self, hsv_range: glm::Vec3) -> Self {
+        self.color_jitter = hsv_range;
+        self
+    }
+
+    /// How far the models sway in the wind, in meters per meter of height.
+    /// Still by default.
+    pub fn with_wind_strength(mut self, strength: f32) -> Self {
+        self.wind_strength = strength;
+        self
+    }
 }
 
 impl Renderable for ShrubEntities {
-    fn render(&self, view_proj_mat: &glm::Mat4) {
+    fn render(&self, ctx: &RenderContext) {
         // SAFETY: fine, if the matrix/vector types match.
         unsafe {
             self.shader.activate();
@@ -183,82 +712,164 @@ impl Renderable for ShrubEntities {
                 self.shader.get_uniform_location("view_proj"),
                 1,
                 gl::FALSE,
-                view_proj_mat.as_ptr(),
+                ctx.view_proj_mat.as_ptr(),
+            );
+            gl::Uniform1f(self.shader.get_uniform_location("time"), ctx.time);
+            gl::Uniform1f(
+                self.shader.get_uniform_location("wind_strength"),
+                self.wind_strength,
+            );
+            set_light_uniforms(&self.shader, ctx.lights);
+            ctx.set_fog_uniforms(&self.shader);
+            gl::Uniform3fv(
+                self.shader.get_uniform_location("ambient_color"),
+                1,
+                ctx.palette.ambient.as_ptr(),
             );
 
-            self.albedo.activate(0);
             gl::Uniform1i(self.shader.get_uniform_location("albedo"), 0);
+
+            if let Some(emissive) = &self.emissive {
+                emissive.activate(1);
+                gl::Uniform1i(self.shader.get_uniform_location("emissive_map"), 1);
+            }
+            gl::Uniform1i(
+                self.shader.get_uniform_location("has_emissive_map"),
+                self.emissive.is_some() as i32,
+            );
+            gl::Uniform3fv(
+                self.shader.get_uniform_location("emissive"),
+                1,
+                self.emissive_color.as_ptr(),
+            );
+            gl::Uniform3fv(
+                self.shader.get_uniform_location("eye_position"),
+                1,
+                ctx.eye.as_ptr(),
+            );
+            gl::Uniform1i(
+                self.shader.get_uniform_location("soft_edges"),
+                ctx.soft_edges as i32,
+            );
+            ctx.set_debug_uniforms(&self.shader);
         }
 
-        self.vao.render();
+        for model in &self.models {
+            model.albedo.activate(0);
+            model.render(ctx, self.wind_strength);
+        }
     }
-}
 
-/// Note that the `density` might not actually be the average, since
-/// this is too difficult to enforce. Just some scale approximately in the same
-/// order as the average.
-///
-/// FIXME: more consitent shrub number. Large scale randomness has too big influence.
-fn probability_distribution(density: f64, seed: u32) -> impl NoiseFn<f64, 2> {
-    let noise = noise::Fbm::<noise::Perlin>::new(seed)
-        .set_octaves(4) // Not very much detail required
-        .set_frequency(0.2); // Large scale features approx 5 meters large
-
-    // Transform from [-1, 1] to [0, density]
-    let noise = noise::ScaleBias::new(noise)
-        .set_bias(1.0)
-        .set_scale(density / 2.0);
-
-    // // Make it less uniform.
-    // let noise = noise::Power::new(noise, noise::Constant::new(5.0));
-    noise
-}
+    fn is_transparent(&self) -> bool {
+        self.transparent
+    }
 
-/// Generates random points in a rectangle according to the given density distribution.
-///
-/// It does this sampling the distribution at discrete locations and then drawing
-/// from a poission variable N with expected value equal to the density times the
-/// area of the chunk. In this tiny chunk the N points are uniformly distributed.
-///
-/// Given the same seed and distribution, this function is deterministic.
-///
-/// The distribution is assumed to be normalized, ie the value of an integral over a
-/// unit area should be the number of points in this area.
-/// The unit is therefore [number of points / area].
-fn generate_points_on_distribution(
-    distribution: impl NoiseFn<f64, 2>,
-    (x_min, x_max, y_min, y_max): (f32, f32, f32, f32),
-    seed: u64,
-) -> Vec<glm::Vec2> {
-    let mut points = Vec::new();
-    let resolution = 100;
-
-    let dx = (x_max - x_min) / resolution as f32;
-    let dy = (y_max - y_min) / resolution as f32;
-    let area = dx * dy;
-
-    let mut rng = StdRng::seed_from_u64(seed);
-
-    // Y is going front to back. Potentially reducing double drawing.
-    for x in 0..resolution {
-        for y in 0..resolution {
-            let fx = x_min + dx * x as f32;
-            let fy = y_min + dy * y as f32;
-
-            let density =
-                distribution.get([(fx + dx / 2.).into(), (fy + dy / 2.).into()]) as f32 * area;
-            if density <= 0.0 {
-                continue;
-            }
+    fn center(&self) -> glm::Vec3 {
+        self.center
+    }
 
-            let num_points_in_chunk = (density + rng.gen::<f32>()).floor() as usize;
+    fn render_decals(&self, ctx: &RenderContext) {
+        if let Some(contact_shadows) = &self.contact_shadows {
+            contact_shadows.render(ctx);
+        }
+    }
+}
 
-            for _ in 0..num_points_in_chunk as usize {
-                let point = glm::vec2(fx + dx * rng.gen::<f32>(), fy + dy * rng.gen::<f32>());
-                points.push(point);
+impl FoliageModel {
+    /// The blended edges draw the chunks back to front, the instances within
+    /// a chunk stay unsorted. Only the alpha tested pass is counted.
+    fn render(&self, ctx: &RenderContext, wind_strength: f32) {
+        match &self.density_lod {
+            None => {
+                self.vao.render();
+                if !ctx.soft_edges {
+                    ctx.record_stats(|stats| {
+                        stats.instances_submitted += self.vao.instance_count()
+                    });
+                }
+            }
+            Some(lod) => {
+                let mut chunks: Vec<_> = lod.chunks.iter().collect();
+                if ctx.soft_edges {
+                    chunks.sort_by(|a, b| {
+                        let distance_a = glm::distance2(&a.center, &ctx.eye);
+                        let distance_b = glm::distance2(&b.center, &ctx.eye);
+                        distance_b.total_cmp(&distance_a)
+                    });
+                }
+                for chunk in chunks {
+                    // The sway reaches at most 1.3 times the height, see
+                    // foliage_instanced.vert
+                    let (min, max) = chunk.bounding_box;
+                    let sway = 1.3 * wind_strength.abs() * (max.z - min.z);
+                    let sway = glm::vec3(sway, sway, 0.0);
+                    if !ctx.frustum.intersects_box(&(min - sway), &(max + sway)) {
+                        if !ctx.soft_edges {
+                            ctx.record_stats(|stats| stats.instances_culled += chunk.count);
+                        }
+                        continue;
+                    }
+                    let distance = glm::distance(&chunk.center, &ctx.eye);
+                    let count = lod.visible_count(chunk.count, distance);
+                    if count > 0 {
+                        self.vao.render_range(chunk.first_instance, count);
+                    }
+                    if !ctx.soft_edges {
+                        ctx.record_stats(|stats| {
+                            stats.instances_submitted += count;
+                            if lod.is_far(distance) {
+                                stats.instances_far_lod += count;
+                            }
+                            stats.instances_thinned += chunk.count - count;
+                        });
+                    }
+                }
             }
         }
     }
+}
 
-    points
+impl ContactShadows {
+    fn render(&self, ctx: &RenderContext) {
+        unsafe {
+            self.shader.activate();
+            gl::UniformMatrix4fv(
+                self.shader.get_uniform_location("view_proj"),
+                1,
+                gl::FALSE,
+                ctx.view_proj_mat.as_ptr(),
+            );
+            gl::Uniform1f(self.shader.get_uniform_location("strength"), self.strength);
+            gl::Uniform1f(
+                self.shader.get_uniform_location("terrain_size"),
+                self.terrain_size,
+            );
+            gl::Uniform3fv(
+                self.shader.get_uniform_location("eye_position"),
+                1,
+                ctx.eye.as_ptr(),
+            );
+            // Only fades out, the fog color is already on the ground below
+            gl::Uniform1f(
+                self.shader.get_uniform_location("fog_density"),
+                ctx.fog_density,
+            );
+
+            // Blend onto the ground without occluding each other
+            gl::Enable(gl::BLEND);
+            // Keep the alpha of the ground, the window might be transparent
+            gl::BlendFuncSeparate(gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA, gl::ZERO, gl::ONE);
+            gl::DepthMask(gl::FALSE);
+            gl::Enable(gl::POLYGON_OFFSET_FILL);
+            gl::PolygonOffset(-1.0, -1.0);
+        }
+
+        self.vao.render();
+
+        unsafe {
+            gl::Disable(gl::POLYGON_OFFSET_FILL);
+            gl::DepthMask(gl::TRUE);
+            gl::Disable(gl::BLEND);
+        }
+    }
 }
@@ -0,0 +1,361 @@
+//! Offscreen render targets and the fullscreen quad used by post-processing.
+
+use std::marker::PhantomData;
+
+use gl::types::GLuint;
+
+use crate::error::{clear_gl_errors, get_gl_errors};
+use crate::texture::Texture;
+
+/// A framebuffer with a single HDR color texture and optionally a depth buffer.
+pub struct Framebuffer {
+    id: GLuint,
+    color: Texture,
+    depth_renderbuffer: Option<GLuint>,
+    width: i32,
+    height: i32,
+    /// Mark the framebuffer as !Send and !Sync, since OpenGL is not thread safe
+    _marker: PhantomData<*const ()>,
+}
+
+/// A multisampled HDR color and depth target. It can't be sampled directly,
+/// but has to be resolved into a [`Framebuffer`] first.
+pub struct MultisampleFramebuffer {
+    id: GLuint,
+    /// Color and depth renderbuffers
+    renderbuffers: [GLuint; 2],
+    width: i32,
+    height: i32,
+    _marker: PhantomData<*const ()>,
+}
+
+/// Covers the whole viewport. The corners are generated from the vertex id
+/// in `shaders/fullscreen.vert`, so the VAO has no buffers.
+pub(crate) struct FullscreenQuad {
+    vao_id: GLuint,
+    _marker: PhantomData<*const ()>,
+}
+
+/// The smallest depth format with at least `bits` bits, up to 32 bit floats.
+pub fn depth_format(bits: u8) -> gl::types::GLenum {
+    match bits {
+        0..=16 => gl::DEPTH_COMPONENT16,
+        17..=24 => gl::DEPTH_COMPONENT24,
+        _ => gl::DEPTH_COMPONENT32F,
+    }
+}
+
+impl Framebuffer {
+    /// With a depth buffer of `depth_bits`, if given, see [`depth_format`].
+    pub fn new(width: i32, height: i32, depth_bits: Option<u8>) -> Self {
+        let color = Texture::new_render_target(width as u32, height as u32);
+
+        clear_gl_errors();
+        let mut id = 0;
+        let mut depth_renderbuffer = None;
+        unsafe {
+            gl::GenFramebuffers(1, &mut id);
+            gl::BindFramebuffer(gl::FRAMEBUFFER, id);
+            gl::FramebufferTexture2D(
+                gl::FRAMEBUFFER,
+                gl::COLOR_ATTACHMENT0,
+                gl::TEXTURE_2D,
+                color.raw_id(),
+                0,
+            );
+
+            if let Some(depth_bits) = depth_bits {
+                let mut rbo = 0;
+                gl::GenRenderbuffers(1, &mut rbo);
+                gl::BindRenderbuffer(gl::RENDERBUFFER, rbo);
+                gl::RenderbufferStorage(gl::RENDERBUFFER, depth_format(depth_bits), width, height);
+                gl::FramebufferRenderbuffer(
+                    gl::FRAMEBUFFER,
+                    gl::DEPTH_ATTACHMENT,
+                    gl::RENDERBUFFER,
+                    rbo,
+                );
+                depth_renderbuffer = Some(rbo);
+            }
+
+            let status = gl::CheckFramebufferStatus(gl::FRAMEBUFFER);
+            assert!(
+                status == gl::FRAMEBUFFER_COMPLETE,
+                "Framebuffer is incomplete: 0x{status:x}"
+            );
+            gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+        }
+        get_gl_errors().expect("Failed to create framebuffer");
+
+        Framebuffer {
+            id,
+            color,
+            depth_renderbuffer,
+            width,
+            height,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Directs all following draw calls into this framebuffer and sets the
+    /// viewport to cover it.
+    pub fn bind(&self) {
+        unsafe {
+            gl::BindFramebuffer(gl::FRAMEBUFFER, self.id);
+            gl::Viewport(0, 0, self.width, self.height);
+        }
+    }
+
+    /// Goes back to drawing into the window.
+    pub fn bind_default(width: i32, height: i32) {
+        unsafe {
+            gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+            gl::Viewport(0, 0, width, height);
+        }
+    }
+
+    /// The color attachment, its [`Texture::raw_id`] can be sampled by other GL code.
+    pub fn color(&self) -> &Texture {
+        &self.color
+    }
+
+    /// The OpenGL framebuffer object, under the same thread restrictions as
+    /// [`Texture::raw_id`].
+    pub fn raw_id(&self) -> GLuint {
+        self.id
+    }
+
+    /// Reads the color texture back, top row first. The texture holds linear
+    /// values, which get clamped and encoded to 8 bit sRGB, so the image
+    /// matches what [`read_window_pixels`] reads from an sRGB window.
+    pub fn read_pixels(&self) -> image::RgbaImage {
+        let mut values = vec![0f32; (self.width * self.height * 4) as usize];
+        unsafe {
+            gl::BindFramebuffer(gl::READ_FRAMEBUFFER, self.id);
+            gl::ReadBuffer(gl::COLOR_ATTACHMENT0);
+            gl::PixelStorei(gl::PACK_ALIGNMENT, 1);
+            // Read as floats, dark values need more than 8 linear bits
+            gl::ReadPixels(
+                0,
+                0,
+                self.width,
+                self.height,
+                gl::RGBA,
+                gl::FLOAT,
+                values.as_mut_ptr() as *mut _,
+            );
+            gl::BindFramebuffer(gl::READ_FRAMEBUFFER, 0);
+        }
+
+        let pixels = values
+            .chunks_exact(4)
+            .flat_map(|pixel| {
+                [
+                    linear_to_srgb(pixel[0]),
+                    linear_to_srgb(pixel[1]),
+                    linear_to_srgb(pixel[2]),
+                    (pixel[3].clamp(0.0, 1.0) * 255.0).round() as u8,
+                ]
+            })
+            .collect();
+        let mut image = image::RgbaImage::from_raw(self.width as u32, self.height as u32, pixels)
+            .expect("Pixel buffer matches the size");
+        // OpenGL starts at the bottom row
+        image::imageops::flip_vertical_in_place(&mut image);
+        image
+    }
+
+    pub fn size(&self) -> (i32, i32) {
+        (self.width, self.height)
+    }
+}
+
+impl Drop for Framebuffer {
+    fn drop(&mut self) {
+        unsafe {
+            if let Some(rbo) = self.depth_renderbuffer {
+                gl::DeleteRenderbuffers(1, &rbo);
+            }
+            gl::DeleteFramebuffers(1, &self.id);
+        }
+    }
+}
+
+impl MultisampleFramebuffer {
+    pub fn new(width: i32, height: i32, samples: i32, depth_bits: u8) -> Self {
+        clear_gl_errors();
+        let mut id = 0;
+        let mut renderbuffers = [0; 2];
+        unsafe {
+            gl::GenFramebuffers(1, &mut id);
+            gl::BindFramebuffer(gl::FRAMEBUFFER, id);
+            gl::GenRenderbuffers(2, renderbuffers.as_mut_ptr());
+
+            gl::BindRenderbuffer(gl::RENDERBUFFER, renderbuffers[0]);
+            gl::RenderbufferStorageMultisample(
+                gl::RENDERBUFFER,
+                samples,
+                gl::RGBA16F,
+                width,
+                height,
+            );
+            gl::FramebufferRenderbuffer(
+                gl::FRAMEBUFFER,
+                gl::COLOR_ATTACHMENT0,
+                gl::RENDERBUFFER,
+                renderbuffers[0],
+            );
+
+            gl::BindRenderbuffer(gl::RENDERBUFFER, renderbuffers[1]);
+            gl::RenderbufferStorageMultisample(
+                gl::RENDERBUFFER,
+                samples,
+                depth_format(depth_bits),
+                width,
+                height,
+            );
+            gl::FramebufferRenderbuffer(
+                gl::FRAMEBUFFER,
+                gl::DEPTH_ATTACHMENT,
+                gl::RENDERBUFFER,
+                renderbuffers[1],
+            );
+
+            let status = gl::CheckFramebufferStatus(gl::FRAMEBUFFER);
+            assert!(
+                status == gl::FRAMEBUFFER_COMPLETE,
+                "Multisampled framebuffer is incomplete: 0x{status:x}"
+            );
+            gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+        }
+        get_gl_errors().expect("Failed to create multisampled framebuffer");
+
+        MultisampleFramebuffer {
+            id,
+            renderbuffers,
+            width,
+            height,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn bind(&self) {
+        unsafe {
+            gl::BindFramebuffer(gl::FRAMEBUFFER, self.id);
+            gl::Viewport(0, 0, self.width, self.height);
+        }
+    }
+
+    /// Averages the samples into the color texture of `target`.
+    pub fn resolve_into(&self, target: &Framebuffer) {
+        unsafe {
+            gl::BindFramebuffer(gl::READ_FRAMEBUFFER, self.id);
+            gl::BindFramebuffer(gl::DRAW_FRAMEBUFFER, target.id);
+            gl::BlitFramebuffer(
+                0,
+                0,
+                self.width,
+                self.height,
+                0,
+                0,
+                target.width,
+                target.height,
+                gl::COLOR_BUFFER_BIT,
+                gl::NEAREST,
+            );
+            gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+        }
+    }
+}
+
+impl Drop for MultisampleFramebuffer {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteRenderbuffers(2, self.renderbuffers.as_ptr());
+            gl::DeleteFramebuffers(1, &self.id);
+        }
+    }
+}
+
+/// Reads the back buffer of the window into an image, top row first.
+///
+/// Has to be called before swapping the buffers. The stored values are read
+/// as they are, so an sRGB encoded window gives the same bytes the screen
+/// shows. `GL_FRAMEBUFFER_SRGB` is switched off meanwhile, since some drivers
+/// decode the values to linear while it is enabled.
+pub fn read_window_pixels(width: i32, height: i32) -> image::RgbaImage {
+    let mut values = vec![0f32; (width * height * 4) as usize];
+    unsafe {
+        gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+        let srgb_enabled = gl::IsEnabled(gl::FRAMEBUFFER_SRGB) == gl::TRUE;
+        gl::Disable(gl::FRAMEBUFFER_SRGB);
+
+        gl::ReadBuffer(gl::BACK);
+        // Rows are tightly packed, even if the width isn't a multiple of 4
+        gl::PixelStorei(gl::PACK_ALIGNMENT, 1);
+        // Read as floats like `Framebuffer::read_pixels`, so no driver
+        // quantizes a decoded value to 8 linear bits on the way
+        gl::ReadPixels(
+            0,
+            0,
+            width,
+            height,
+            gl::RGBA,
+            gl::FLOAT,
+            values.as_mut_ptr() as *mut _,
+        );
+
+        if srgb_enabled {
+            gl::Enable(gl::FRAMEBUFFER_SRGB);
+        }
+    }
+
+    let pixels = values
+        .into_iter()
+        .map(|value| (value.clamp(0.0, 1.0) * 255.0).round() as u8)
+        .collect();
+    let mut image = image::RgbaImage::from_raw(width as u32, height as u32, pixels)
+        .expect("Pixel buffer matches the size");
+    // OpenGL starts at the bottom row
+    image::imageops::flip_vertical_in_place(&mut image);
+    image
+}
+
+fn linear_to_srgb(linear: f32) -> u8 {
+    let linear = linear.clamp(0.0, 1.0);
+    let srgb = if linear <= 0.0031308 {
+        12.92 * linear
+    } else {
+        1.055 * linear.powf(1.0 / 2.4) - 0.055
+    };
+    (srgb * 255.0).round() as u8
+}
+
+impl FullscreenQuad {
+    pub fn new() -> Self {
+        let mut vao_id = 0;
+        unsafe {
+            // Core profile refuses to draw without a VAO bound, even an empty one.
+            gl::GenVertexArrays(1, &mut vao_id);
+        }
+        FullscreenQuad {
+            vao_id,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn render(&self) {
+        unsafe {
+            gl::BindVertexArray(self.vao_id);
+            gl::DrawArrays(gl::TRIANGLE_STRIP, 0, 4);
+        }
+    }
+}
+
+impl Drop for FullscreenQuad {
+    fn drop(&mut self) {
+        unsafe {
+            gl::DeleteVertexArrays(1, &self.vao_id);
+        }
+    }
+}
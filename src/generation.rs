@@ -0,0 +1,711 @@
+//! CPU side scene generation, without any OpenGL resources.
+//!
+//! Everything here works without a GL context, so the generated data can be
+//! used outside of the renderer. [`crate::foliage`] and [`crate::terrain`]
+//! upload the results of these functions to the GPU.
+
+use crate::biome::{BiomeId, BiomeMap};
+use crate::scene::SCENE_SIZE;
+use crate::seed::Seed;
+
+use nalgebra_glm as glm;
+use noise::{MultiFractal, NoiseFn};
+use rand::seq::SliceRandom;
+use rand::Rng;
+use rand_distr::Uniform;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// Parameters for scattering one kind of foliage over a height map.
+pub struct FoliageConfig {
+    /// Approximate number of instances per square meter.
+    pub density: f64,
+    /// Upper bound on the number of instances, the rest are dropped at random.
+    pub num_limit: usize,
+    /// Places exactly this many instances, or fewer with a warning if even
+    /// 16 candidates per instance over the room the layer has don't give
+    /// enough. Replaces the `density` and `num_limit`, which then only give
+    /// the starting point of the search.
+    pub exact_count: Option<usize>,
+    /// Brings the number of instances within this relative tolerance of the
+    /// number `density` places on average over all seeds, so it varies less
+    /// between seeds. The `num_limit` still caps it. Ignored with an `exact_count`.
+    pub count_tolerance: Option<f32>,
+    pub height_map: Rc<dyn NoiseFn<f64, 2>>,
+    /// Range of the additional stretch in z direction (height).
+    pub z_scale_range: (f32, f32),
+    /// Range of the uniform scale.
+    pub scale_range: (f32, f32),
+    /// Area to scatter in, as `(min_x, max_x, min_y, max_y)`.
+    pub bounds: (f32, f32, f32, f32),
+    /// Optional map in [0, 1] of where the foliage grows more densely.
+    pub bushiness: Option<Box<dyn NoiseFn<f64, 2>>>,
+    /// Optional map in [0, 1] the density is multiplied with. Nothing grows
+    /// where it is zero, for carving out paths and clearings.
+    pub density_mask: Option<Box<dyn NoiseFn<f64, 2>>>,
+    /// Only grow between these terrain heights, inclusive.
+    pub height_range: Option<(f32, f32)>,
+    /// Only grow inside this biome.
+    pub biome: Option<(Rc<BiomeMap>, BiomeId)>,
+    /// Largest random offset in meters added to each point after the placement,
+    /// which hides the grid the points are placed on when they are sparse.
+    pub jitter: f32,
+    /// Frequency in 1/m and octaves of the noise the density varies with.
+    /// Lower frequencies give broad groves, higher ones small patches.
+    pub clumping: (f64, usize),
+    /// Optionally grow more densely around the instances of another layer.
+    pub attraction: Option<Attraction>,
+    /// How far the instances tilt towards the slope of the terrain, from 0
+    /// for upright to 1 for perpendicular to the ground.
+    pub slope_alignment: f32,
+}
+
+/// Large scale features approx 5 meters large, without much detail.
+pub const DEFAULT_CLUMPING: (f64, usize) = (0.2, 4);
+
+impl FoliageConfig {
+    /// Covers the whole scene with a density of zero.
+    pub fn new(height_map: Rc<dyn NoiseFn<f64, 2>>) -> Self {
+        FoliageConfig {
+            density: 0.0,
+            num_limit: usize::MAX,
+            exact_count: None,
+            count_tolerance: None,
+            height_map,
+            z_scale_range: (1.0, 1.0),
+            scale_range: (1.0, 1.0),
+            bounds: (0., SCENE_SIZE, 0., SCENE_SIZE),
+            bushiness: None,
+            density_mask: None,
+            height_range: None,
+            biome: None,
+            jitter: 0.0,
+            clumping: DEFAULT_CLUMPING,
+            attraction: None,
+            slope_alignment: 0.0,
+        }
+    }
+}
+
+/// Items bucketed by their position on the ground, for finding the ones near
+/// a point without checking all of them.
+pub struct SpatialGrid<T> {
+    cell_size: f32,
+    items: Vec<(glm::Vec2, T)>,
+    /// Indices into `items` per cell.
+    cells: HashMap<(i32, i32), Vec<usize>>,
+}
+
+impl<T> SpatialGrid<T> {
+    /// Queries are fastest with a `cell_size` about as large as their radius.
+    pub fn new(items: impl IntoIterator<Item = (glm::Vec2, T)>, cell_size: f32) -> Self {
+        let cell_size = cell_size.max(f32::EPSILON);
+        let items: Vec<_> = items.into_iter().collect();
+        let mut cells: HashMap<_, Vec<_>> = HashMap::new();
+        for (index, (position, _)) in items.iter().enumerate() {
+            cells
+                .entry(Self::cell_of(*position, cell_size))
+                .or_default()
+                .push(index);
+        }
+        SpatialGrid {
+            cell_size,
+            items,
+            cells,
+        }
+    }
+
+    fn cell_of(point: glm::Vec2, cell_size: f32) -> (i32, i32) {
+        (
+            (point.x / cell_size).floor() as i32,
+            (point.y / cell_size).floor() as i32,
+        )
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// All items with their positions, in the order they were added.
+    pub fn iter(&self) -> impl Iterator<Item = (glm::Vec2, &T)> {
+        self.items.iter().map(|(position, item)| (*position, item))
+    }
+
+    pub fn points(&self) -> impl Iterator<Item = glm::Vec2> + '_ {
+        self.items.iter().map(|(position, _)| *position)
+    }
+
+    /// The items at most `radius` meters away from `center`, measured along
+    /// the ground, in no particular order.
+    pub fn query_radius(&self, center: glm::Vec2, radius: f32) -> impl Iterator<Item = &T> {
+        let (min_x, min_y) = Self::cell_of(center - glm::vec2(radius, radius), self.cell_size);
+        let (max_x, max_y) = Self::cell_of(center + glm::vec2(radius, radius), self.cell_size);
+        (min_x..=max_x)
+            .flat_map(move |x| (min_y..=max_y).map(move |y| (x, y)))
+            .filter_map(|cell| self.cells.get(&cell))
+            .flatten()
+            .map(|&index| &self.items[index])
+            .filter(move |(position, _)| glm::distance2(position, &center) <= radius * radius)
+            .map(|(_, item)| item)
+    }
+}
+
+/// Raises the density around a set of points, to cluster one layer of
+/// foliage around the instances of another, like ferns around tree trunks.
+pub struct Attraction {
+    radius: f32,
+    strength: f32,
+    points: SpatialGrid<glm::Vec2>,
+}
+
+impl Attraction {
+    /// Multiplies the density by up to `1 + strength` right at the points,
+    /// falling off smoothly to no change at `radius` meters.
+    pub fn new(points: impl IntoIterator<Item = glm::Vec2>, radius: f32, strength: f32) -> Self {
+        let radius = radius.max(f32::EPSILON);
+        Attraction {
+            radius,
+            strength,
+            points: SpatialGrid::new(points.into_iter().map(|point| (point, point)), radius),
+        }
+    }
+
+    /// The factor on the density at `point`. Overlapping points don't add
+    /// up, only the closest one counts.
+    pub fn factor(&self, point: glm::Vec2) -> f32 {
+        let closest = self
+            .points
+            .query_radius(point, self.radius)
+            .map(|other| glm::distance2(other, &point))
+            .fold(f32::INFINITY, f32::min);
+        let t = (closest / (self.radius * self.radius)).min(1.0);
+        1.0 + self.strength * (1.0 - t).powi(2)
+    }
+}
+
+impl NoiseFn<f64, 2> for Attraction {
+    fn get(&self, point: [f64; 2]) -> f64 {
+        self.factor(glm::vec2(point[0] as f32, point[1] as f32)) as f64
+    }
+}
+
+/// Generates the model matrices of all foliage instances described by `config`.
+///
+/// Given the same config and seed, the result is always the same.
+pub fn generate_foliage(config: &FoliageConfig, seed: u32) -> Vec<glm::Mat4> {
+    let seed = Seed::from(seed);
+    let mut rng = seed.derive("instances").rng();
+
+    let distr_seed = seed.derive("distribution").noise_seed();
+    let points_seed = seed.derive("points");
+    // The range the number of instances should end up in
+    let target = match (config.exact_count, config.count_tolerance) {
+        (Some(count), _) => Some((count, count)),
+        (None, Some(tolerance)) => {
+            let count = expected_count(config, config.density);
+            let tolerance = tolerance.max(0.0) as f64;
+            // Searching for more than the limit keeps would be wasted
+            Some((
+                ((count * (1.0 - tolerance)).ceil() as usize).min(config.num_limit),
+                ((count * (1.0 + tolerance)).floor() as usize).min(config.num_limit),
+            ))
+        }
+        (None, None) => None,
+    };
+    let (mut positions, limit) = match target {
+        None => (
+            place_positions(config, config.density, distr_seed, points_seed),
+            config.num_limit,
+        ),
+        Some((min_count, max_count)) => (
+            place_at_least(config, min_count, max_count, distr_seed, points_seed),
+            max_count,
+        ),
+    };
+
+    if positions.len() > limit {
+        positions.shuffle(&mut rng);
+        positions.resize_with(limit, || unreachable!("Len is less than the limit"));
+    }
+    let positions = positions;
+
+    // For some very weird ass reason do the translate & scale functions right multiply,
+    // thus for scale than translate, I need to translate then scale...
+
+    positions
+        .into_iter()
+        .map(|p| {
+            let rotation_angle: f32 = rng.sample(Uniform::new(0.0, std::f32::consts::TAU));
+            // TODO: scale in a more natural distribution
+            let z_scale: f32 = rng.sample(Uniform::new_inclusive(
+                config.z_scale_range.0,
+                config.z_scale_range.1,
+            ));
+            let scale: f32 = rng.sample(Uniform::new_inclusive(
+                config.scale_range.0,
+                config.scale_range.1,
+            ));
+
+            let height = config.height_map.get([p.x as f64, p.y as f64]) as f32;
+            let translation = glm::translate(&glm::identity(), &glm::vec3(p.x, p.y, height));
+            let tilted = translation * slope_tilt(config, p);
+            glm::scale(
+                &glm::rotate_z(&tilted, rotation_angle),
+                &glm::vec3(scale, scale, scale * z_scale),
+            )
+        })
+        .collect()
+}
+
+/// Rotates the up axis towards the terrain normal at `point`, by the
+/// [`FoliageConfig::slope_alignment`]. The identity on flat ground.
+fn slope_tilt(config: &FoliageConfig, point: glm::Vec2) -> glm::Mat4 {
+    if config.slope_alignment == 0.0 {
+        return glm::identity();
+    }
+    let normal = terrain_normal(config.height_map.as_ref(), point.x, point.y);
+    let up = glm::normalize(&glm::lerp(
+        &glm::Vec3::z(),
+        &normal,
+        config.slope_alignment.clamp(0.0, 1.0),
+    ));
+    let axis = glm::cross(&glm::Vec3::z(), &up);
+    if axis.norm() < 1e-6 {
+        return glm::identity();
+    }
+    let angle = up.z.clamp(-1.0, 1.0).acos();
+    glm::rotation(angle, &axis)
+}
+
+/// How often [`place_at_least`] places the candidates again with a density
+/// adjusted towards the target count. Only a safeguard, the search usually
+/// meets the count or reaches the largest density within a few tries.
+const MAX_DENSITY_RETRIES: u32 = 12;
+
+/// Largest density [`place_at_least`] tries, in candidates per target instance
+/// over the area the layer may grow in, weighted by its mask, attraction and
+/// bushiness. The cost of the placement grows with the density.
+const MAX_CANDIDATES_PER_INSTANCE: f64 = 16.0;
+
+/// At least `min_count` candidate positions if they fit, with a density
+/// adjusted from the one of the `config`. Denser until there are enough
+/// candidates, then [`generate_foliage`] drops the surplus at random, which
+/// keeps the shape of the distribution.
+///
+/// Warns and settles for fewer when even the largest density doesn't give
+/// enough, see [`MAX_CANDIDATES_PER_INSTANCE`].
+fn place_at_least(
+    config: &FoliageConfig,
+    min_count: usize,
+    max_count: usize,
+    distr_seed: u32,
+    points_seed: Seed,
+) -> Vec<glm::Vec2> {
+    let area = available_area(config);
+    if area <= 0.0 {
+        if min_count > 0 {
+            eprintln!("Placing no instances, the layer has no room to grow");
+        }
+        return Vec::new();
+    }
+    let density = if config.density > 0.0 {
+        config.density
+    } else {
+        1.0
+    };
+    let max_density = (MAX_CANDIDATES_PER_INSTANCE * max_count as f64 / area).max(density);
+    let mut positions = place_positions(config, density, distr_seed, points_seed);
+    // The last two densities tried, with the candidates they gave
+    let mut last = (density, positions.len());
+    let mut before_last: Option<(f64, usize)> = None;
+    for _ in 0..MAX_DENSITY_RETRIES {
+        let (density, count) = last;
+        if positions.len() >= min_count || density >= max_density {
+            break;
+        }
+        // Aiming past the upper end, the surplus is dropped instead of
+        // needing another retry
+        let wanted = 1.25 * max_count as f64;
+        let next = match before_last {
+            // The count grows about linearly with the density, but doesn't
+            // start at zero, so the ratio is only a first guess
+            Some((other_density, other_count)) if count > other_count => {
+                density
+                    + (wanted - count as f64) * (density - other_density)
+                        / (count - other_count) as f64
+            }
+            _ => density * wanted / count.max(1) as f64,
+        };
+        let next = next.min(max_density);
+        let denser = place_positions(config, next, distr_seed, points_seed);
+        before_last = Some(last);
+        last = (next, denser.len());
+        // A higher density can still place fewer by chance, keep the most
+        if denser.len() > positions.len() {
+            positions = denser;
+        }
+    }
+    if positions.len() < min_count {
+        eprintln!(
+            "Placed only {} of {min_count} instances, the layer has too little room",
+            positions.len()
+        );
+    }
+    positions
+}
+
+/// The area in square meters the layer may grow in, weighted by the
+/// [`layer_factors`], so a sparse bushiness or mask counts as less room.
+fn available_area(config: &FoliageConfig) -> f64 {
+    let (x_min, x_max, y_min, y_max) = config.bounds;
+    let dx = (x_max - x_min) / POINT_GRID_RESOLUTION as f32;
+    let dy = (y_max - y_min) / POINT_GRID_RESOLUTION as f32;
+    let factors = cell_densities(&layer_factors(config), config.bounds);
+    let cells =
+        (0..POINT_GRID_RESOLUTION).flat_map(|x| (0..POINT_GRID_RESOLUTION).map(move |y| (x, y)));
+    factors
+        .into_iter()
+        .zip(cells)
+        .filter(|&(_, (x, y))| {
+            let center = glm::vec2(x_min + dx * (x as f32 + 0.5), y_min + dy * (y as f32 + 0.5));
+            may_grow_at(config, &center)
+        })
+        .map(|(factor, _)| factor.max(0.0) as f64)
+        .sum::<f64>()
+        * (dx * dy) as f64
+}
+
+/// The candidate positions for `density`, already restricted to the allowed
+/// heights and biome.
+fn place_positions(
+    config: &FoliageConfig,
+    density: f64,
+    distr_seed: u32,
+    points_seed: Seed,
+) -> Vec<glm::Vec2> {
+    let distr = layer_distribution(config, density, distr_seed);
+    let mut positions = points_on_distribution(distr, config.bounds, points_seed.value());
+    if config.jitter > 0.0 {
+        let jitter_seed = points_seed.derive("jitter");
+        jitter_positions(&mut positions, config.jitter, config.bounds, jitter_seed);
+    }
+
+    positions.retain(|p| {
+        // The points spread within the sampled cells, which may reach into the mask
+        let unmasked = config
+            .density_mask
+            .as_ref()
+            .is_none_or(|mask| mask.get([p.x as f64, p.y as f64]) > 0.0);
+        may_grow_at(config, p) && unmasked
+    });
+    positions
+}
+
+/// Whether the height range and biome of the layer allow an instance at `p`.
+fn may_grow_at(config: &FoliageConfig, p: &glm::Vec2) -> bool {
+    let in_height_range = config.height_range.is_none_or(|(min, max)| {
+        let height = config.height_map.get([p.x as f64, p.y as f64]) as f32;
+        (min..=max).contains(&height)
+    });
+    let in_biome = config
+        .biome
+        .as_ref()
+        .is_none_or(|(map, biome)| map.biome_at(p.x, p.y) == *biome);
+    in_height_range && in_biome
+}
+
+/// The [`probability_distribution`] for `density`, multiplied with the mask,
+/// attraction and bushiness of the layer.
+fn layer_distribution(
+    config: &FoliageConfig,
+    density: f64,
+    distr_seed: u32,
+) -> Box<dyn NoiseFn<f64, 2> + '_> {
+    Box::new(noise::Multiply::new(
+        probability_distribution(density, config.clumping, distr_seed),
+        layer_factors(config),
+    ))
+}
+
+/// The mask, attraction and bushiness of the layer multiplied together, one
+/// where the layer has none of them.
+fn layer_factors(config: &FoliageConfig) -> Box<dyn NoiseFn<f64, 2> + '_> {
+    let mut factors: Box<dyn NoiseFn<f64, 2>> = Box::new(noise::Constant::new(1.0));
+    if let Some(mask) = &config.density_mask {
+        factors = Box::new(noise::Multiply::new(factors, mask.as_ref()));
+    }
+    if let Some(attraction) = &config.attraction {
+        factors = Box::new(noise::Multiply::new(factors, attraction));
+    }
+    if let Some(bushiness) = &config.bushiness {
+        let bushiness = noise::Power::new(bushiness.as_ref(), noise::Constant::new(2.0));
+        let bushiness = noise::ScaleBias::new(bushiness)
+            .set_scale(2.0)
+            .set_bias(0.1);
+        factors = Box::new(noise::Multiply::new(factors, bushiness));
+    }
+    factors
+}
+
+/// Number of distribution seeds [`expected_count`] averages over.
+const EXPECTED_COUNT_SEEDS: u64 = 16;
+
+/// The number of instances `density` places on average over all seeds, for
+/// [`FoliageConfig::count_tolerance`]. Sums the distribution over the point
+/// grid like [`generate_points_on_distribution`], for a fixed set of seeds,
+/// so the large scale noise of a single seed doesn't shift the target.
+fn expected_count(config: &FoliageConfig, density: f64) -> f64 {
+    let (x_min, x_max, y_min, y_max) = config.bounds;
+    let dx = (x_max - x_min) / POINT_GRID_RESOLUTION as f32;
+    let dy = (y_max - y_min) / POINT_GRID_RESOLUTION as f32;
+    // The cell centers in the order of `cell_densities`
+    let allowed: Vec<bool> = (0..POINT_GRID_RESOLUTION)
+        .flat_map(|x| (0..POINT_GRID_RESOLUTION).map(move |y| (x, y)))
+        .map(|(x, y)| {
+            let center = glm::vec2(x_min + dx * (x as f32 + 0.5), y_min + dy * (y as f32 + 0.5));
+            may_grow_at(config, &center)
+        })
+        .collect();
+
+    let seeds = Seed::new(0).derive("expected count");
+    let total: f64 = (0..EXPECTED_COUNT_SEEDS)
+        .map(|i| {
+            let distr = layer_distribution(config, density, seeds.derive_index(i).noise_seed());
+            cell_densities(&distr, config.bounds)
+                .into_iter()
+                .zip(&allowed)
+                .filter(|(_, &allowed)| allowed)
+                .map(|(density, _)| density.max(0.0) as f64)
+                .sum::<f64>()
+        })
+        .sum();
+    total / EXPECTED_COUNT_SEEDS as f64 * (dx * dy) as f64
+}
+
+/// `generate_points_on_distribution_par` with the `parallel` feature, else
+/// the single threaded version. Both give the same points.
+fn points_on_distribution(
+    distribution: impl NoiseFn<f64, 2>,
+    bounds: (f32, f32, f32, f32),
+    seed: u64,
+) -> Vec<glm::Vec2> {
+    #[cfg(feature = "parallel")]
+    return generate_points_on_distribution_par(distribution, bounds, seed);
+    #[cfg(not(feature = "parallel"))]
+    return generate_points_on_distribution(distribution, bounds, seed);
+}
+
+/// Moves each point by up to `amount` meters in x and y, staying inside the
+/// bounds. The offsets come from their own RNG, so they don't correlate with
+/// the grid cells the points were placed in.
+fn jitter_positions(
+    positions: &mut [glm::Vec2],
+    amount: f32,
+    (x_min, x_max, y_min, y_max): (f32, f32, f32, f32),
+    seed: Seed,
+) {
+    let mut rng = seed.rng();
+    let offset = Uniform::new_inclusive(-amount, amount);
+    for p in positions {
+        p.x = (p.x + rng.sample(offset)).clamp(x_min, x_max);
+        p.y = (p.y + rng.sample(offset)).clamp(y_min, y_max);
+    }
+}
+
+/// Model matrices of flat unit quads lying on the terrain below each instance,
+/// scaled to `radius` meters times the instance scale.
+///
+/// Instances past the terrain edge at `terrain_size` are skipped, since there
+/// is no ground below them.
+pub fn contact_shadow_matrices(
+    model_mats: &[glm::Mat4],
+    height_map: &dyn NoiseFn<f64, 2>,
+    radius: f32,
+    terrain_size: f32,
+) -> Vec<glm::Mat4> {
+    let on_terrain =
+        |p: &glm::Vec3| (0.0..=terrain_size).contains(&p.x) && (0.0..=terrain_size).contains(&p.y);
+    model_mats
+        .iter()
+        .filter(|model_mat| on_terrain(&model_mat.column(3).xyz()))
+        .map(|model_mat| {
+            let base = model_mat.column(3).xyz();
+            let scale = model_mat.column(0).xyz().norm();
+            let normal = terrain_normal(height_map, base.x, base.y);
+            // Lifted slightly, so the flat quad doesn't sink into small bumps
+            let translation = glm::translation(&(base + 0.02 * normal));
+            let rotation = glm::quat_to_mat4(&glm::quat_rotation(&glm::Vec3::z(), &normal));
+            translation * rotation * glm::scaling(&glm::vec3(radius * scale, radius * scale, 1.0))
+        })
+        .collect()
+}
+
+/// Samples the noise at the center of each cell of a `resolution` squared grid
+/// over the bounds. The result is row major, starting at the minimum corner.
+pub fn height_grid(
+    noise: &(impl NoiseFn<f64, 2> + ?Sized),
+    (x_min, x_max, y_min, y_max): (f32, f32, f32, f32),
+    resolution: u32,
+) -> Vec<f32> {
+    // Not using [`noise::utils::PlaneMapBuilder`], since I
+    // want to get the vector directly.
+
+    let mut result_map = vec![0.0f32; (resolution * resolution) as usize];
+
+    let x_step = (x_max - x_min) / resolution as f32;
+    let y_step = (y_max - y_min) / resolution as f32;
+
+    for y in 0..resolution {
+        for x in 0..resolution {
+            let current_x = x_min + x_step * (x as f32 + 0.5);
+            let current_y = y_min + y_step * (y as f32 + 0.5);
+
+            result_map[(y * resolution + x) as usize] =
+                noise.get([current_x as f64, current_y as f64]) as f32;
+        }
+    }
+
+    result_map
+}
+
+/// Estimates the surface normal of the height map with finite differences.
+pub fn terrain_normal(height_map: &dyn NoiseFn<f64, 2>, x: f32, y: f32) -> glm::Vec3 {
+    const DX: f64 = 0.05;
+    let (x, y) = (x as f64, y as f64);
+    let height = height_map.get([x, y]);
+    let du = (height_map.get([x + DX, y]) - height) / DX;
+    let dv = (height_map.get([x, y + DX]) - height) / DX;
+    glm::normalize(&glm::vec3(-du as f32, -dv as f32, 1.0))
+}
+
+/// Note that the `density` might not actually be the average, since
+/// this is too difficult to enforce. Just some scale approximately in the same
+/// order as the average.
+///
+/// Large scale randomness has a big influence on the number of points. A
+/// higher clumping frequency reduces it per layer, and
+/// [`FoliageConfig::count_tolerance`] evens out the total afterwards.
+fn probability_distribution(
+    density: f64,
+    (frequency, octaves): (f64, usize),
+    seed: u32,
+) -> impl NoiseFn<f64, 2> {
+    let noise = noise::Fbm::<noise::Perlin>::new(seed)
+        .set_octaves(octaves)
+        .set_frequency(frequency);
+
+    // Transform from [-1, 1] to [0, density]
+    let noise = noise::ScaleBias::new(noise)
+        .set_bias(1.0)
+        .set_scale(density / 2.0);
+
+    // // Make it less uniform.
+    // let noise = noise::Power::new(noise, noise::Constant::new(5.0));
+    noise
+}
+
+/// Number of cells per side of the grid the distribution is sampled on.
+const POINT_GRID_RESOLUTION: u32 = 100;
+
+/// Generates random points in a rectangle according to the given density distribution.
+///
+/// It does this sampling the distribution at discrete locations and then drawing
+/// from a poission variable N with expected value equal to the density times the
+/// area of the chunk. In this tiny chunk the N points are uniformly distributed.
+///
+/// Given the same seed and distribution, this function is deterministic. Every
+/// chunk draws from its own RNG seeded by its position, so the result doesn't
+/// depend on the order the chunks are visited in, see
+/// `generate_points_on_distribution_par`.
+///
+/// The distribution is assumed to be normalized, ie the value of an integral over a
+/// unit area should be the number of points in this area.
+/// The unit is therefore [number of points / area].
+pub fn generate_points_on_distribution(
+    distribution: impl NoiseFn<f64, 2>,
+    bounds: (f32, f32, f32, f32),
+    seed: u64,
+) -> Vec<glm::Vec2> {
+    let densities = cell_densities(&distribution, bounds);
+    // Y is going front to back. Potentially reducing double drawing.
+    (0..POINT_GRID_RESOLUTION)
+        .flat_map(|x| (0..POINT_GRID_RESOLUTION).map(move |y| (x, y)))
+        .flat_map(|cell| points_in_cell(&densities, bounds, cell, seed))
+        .collect()
+}
+
+/// Multithreaded version of [`generate_points_on_distribution`], with the exact
+/// same result for the same inputs, regardless of the number of threads.
+///
+/// The noise functions of a [`FoliageConfig`] aren't `Sync`, so the
+/// distribution is still sampled on the calling thread. Only the points of the
+/// cells are drawn in parallel.
+#[cfg(feature = "parallel")]
+pub fn generate_points_on_distribution_par(
+    distribution: impl NoiseFn<f64, 2>,
+    bounds: (f32, f32, f32, f32),
+    seed: u64,
+) -> Vec<glm::Vec2> {
+    use rayon::prelude::*;
+
+    let densities = cell_densities(&distribution, bounds);
+    // Collecting keeps the order of the columns, thus the same order as above
+    (0..POINT_GRID_RESOLUTION)
+        .into_par_iter()
+        .flat_map_iter(|x| {
+            let densities = &densities;
+            (0..POINT_GRID_RESOLUTION)
+                .flat_map(move |y| points_in_cell(densities, bounds, (x, y), seed))
+        })
+        .collect()
+}
+
+/// The distribution at the center of each cell of the point grid, column by
+/// column.
+fn cell_densities(
+    distribution: &impl NoiseFn<f64, 2>,
+    (x_min, x_max, y_min, y_max): (f32, f32, f32, f32),
+) -> Vec<f32> {
+    let dx = (x_max - x_min) / POINT_GRID_RESOLUTION as f32;
+    let dy = (y_max - y_min) / POINT_GRID_RESOLUTION as f32;
+    (0..POINT_GRID_RESOLUTION)
+        .flat_map(|x| (0..POINT_GRID_RESOLUTION).map(move |y| (x, y)))
+        .map(|(x, y)| {
+            let fx = x_min + dx * x as f32;
+            let fy = y_min + dy * y as f32;
+            distribution.get([(fx + dx / 2.).into(), (fy + dy / 2.).into()]) as f32
+        })
+        .collect()
+}
+
+/// The points of a single chunk of the grid in [`generate_points_on_distribution`].
+fn points_in_cell(
+    densities: &[f32],
+    (x_min, x_max, y_min, y_max): (f32, f32, f32, f32),
+    (x, y): (u32, u32),
+    seed: u64,
+) -> Vec<glm::Vec2> {
+    let dx = (x_max - x_min) / POINT_GRID_RESOLUTION as f32;
+    let dy = (y_max - y_min) / POINT_GRID_RESOLUTION as f32;
+    let area = dx * dy;
+
+    let fx = x_min + dx * x as f32;
+    let fy = y_min + dy * y as f32;
+
+    let density = densities[(x * POINT_GRID_RESOLUTION + y) as usize] * area;
+    if density <= 0.0 {
+        return Vec::new();
+    }
+
+    let cell = (x as u64) << 32 | y as u64;
+    let mut rng = Seed::new(seed).derive_index(cell).rng();
+    let num_points_in_chunk = (density + rng.gen::<f32>()).floor() as usize;
+
+    (0..num_points_in_chunk)
+        .map(|_| glm::vec2(fx + dx * rng.gen::<f32>(), fy + dy * rng.gen::<f32>()))
+        .collect()
+}
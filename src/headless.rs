@@ -0,0 +1,98 @@
+//! An OpenGL context without a window, for exercising the rendering code in
+//! tests and tools.
+//!
+//! Uses the first EGL device, which works on headless machines with Mesa.
+
+use std::error::Error;
+use std::num::NonZeroU32;
+
+use glutin::api::egl::context::PossiblyCurrentContext;
+use glutin::api::egl::device::Device;
+use glutin::api::egl::display::Display;
+use glutin::api::egl::surface::Surface;
+use glutin::config::{ConfigSurfaceTypes, ConfigTemplateBuilder};
+use glutin::context::{ContextApi, ContextAttributesBuilder, Version};
+use glutin::prelude::*;
+use glutin::surface::{PbufferSurface, SurfaceAttributesBuilder};
+
+use crate::renderer::DEFAULT_DEPTH_BITS;
+
+/// A current GL context rendering into an offscreen pbuffer of a fixed size.
+///
+/// The GL function pointers are loaded once the context is passed to
+/// [`crate::renderer::Renderer::new`], or by calling [`HeadlessContext::load_gl`]
+/// when testing single components.
+pub struct HeadlessContext {
+    // Dropped in this order, the context before the surface and display
+    _context: PossiblyCurrentContext,
+    _surface: Surface<PbufferSurface>,
+    display: Display,
+    width: u32,
+    height: u32,
+}
+
+impl HeadlessContext {
+    /// Creates an OpenGL 4.1 context with the default depth precision and makes
+    /// it current on this thread.
+    pub fn new(width: u32, height: u32) -> Result<Self, Box<dyn Error>> {
+        Self::with_depth_bits(width, height, DEFAULT_DEPTH_BITS)
+    }
+
+    /// Like [`HeadlessContext::new`], with a depth buffer of at least `depth_bits`.
+    pub fn with_depth_bits(
+        width: u32,
+        height: u32,
+        depth_bits: u8,
+    ) -> Result<Self, Box<dyn Error>> {
+        let device = Device::query_devices()?
+            .next()
+            .ok_or("No EGL device available")?;
+        // SAFETY: no native display is passed, so there is nothing to outlive.
+        let display = unsafe { Display::with_device(&device, None) }?;
+
+        let template = ConfigTemplateBuilder::default()
+            .with_surface_type(ConfigSurfaceTypes::PBUFFER)
+            .with_alpha_size(8)
+            .with_depth_size(depth_bits)
+            .build();
+        let config = unsafe { display.find_configs(template) }?
+            .next()
+            .ok_or("No EGL config with pbuffer support")?;
+
+        let size = |value| NonZeroU32::new(value).ok_or("The size must not be zero");
+        let surface_attributes =
+            SurfaceAttributesBuilder::<PbufferSurface>::new().build(size(width)?, size(height)?);
+        let surface = unsafe { display.create_pbuffer_surface(&config, &surface_attributes) }?;
+
+        let context_attributes = ContextAttributesBuilder::new()
+            .with_context_api(ContextApi::OpenGl(Some(Version::new(4, 1))))
+            .build(None);
+        let context = unsafe { display.create_context(&config, &context_attributes) }?
+            .make_current(&surface)?;
+
+        Ok(HeadlessContext {
+            _context: context,
+            _surface: surface,
+            display,
+            width,
+            height,
+        })
+    }
+
+    /// The display to pass on to [`crate::renderer::Renderer::new`].
+    pub fn display(&self) -> &Display {
+        &self.display
+    }
+
+    pub fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    /// Loads the GL function pointers, without creating a whole renderer.
+    pub fn load_gl(&self) {
+        gl::load_with(|symbol| {
+            let symbol = std::ffi::CString::new(symbol).unwrap();
+            self.display.get_proc_address(symbol.as_c_str()).cast()
+        });
+    }
+}
@@ -0,0 +1,54 @@
+//! Procedural but deterministic renderings of the flora underneath a forest's
+//! canopy.
+//!
+//! The [`renderer::Renderer`] draws a [`scene::Scene`], which is generated from a
+//! seed and the shared [`assets::Assets`]. Most things needed to use it in another
+//! application are re-exported in the [`prelude`]. The CPU side generation in
+//! [`generation`] works without an OpenGL context.
+
+pub mod assets;
+pub mod biome;
+mod bloom;
+pub mod camera;
+mod error;
+pub mod favorites;
+pub mod foliage;
+pub mod framebuffer;
+pub mod generation;
+// EGL devices aren't available through glutin on Apple platforms
+#[cfg(not(any(target_os = "macos", target_os = "ios")))]
+pub mod headless;
+pub mod lighting;
+pub mod mesh;
+pub mod paint;
+pub mod palette;
+mod panorama;
+pub mod quality;
+pub mod renderer;
+pub mod scene;
+pub mod seed;
+pub mod shader;
+mod sky;
+pub mod state;
+pub mod terrain;
+pub mod texture;
+
+/// The types most applications need, to `use undergrowth::prelude::*`.
+pub mod prelude {
+    pub use crate::assets::{Assets, ImageNoiseFnWrapper};
+    pub use crate::biome::{BiomeId, BiomeMap};
+    pub use crate::camera::{Camera, CameraMode, FreeCamera, OrbitCamera};
+    pub use crate::foliage::ShrubEntitiesBuilder;
+    pub use crate::generation::{generate_foliage, FoliageConfig};
+    pub use crate::lighting::DirectionalLight;
+    pub use crate::mesh::Mesh;
+    pub use crate::palette::Palette;
+    pub use crate::quality::{QualityPreset, QualitySettings};
+    pub use crate::renderer::{
+        DebugView, FrameStats, ProjectionMode, Renderable, Renderer, Winding,
+    };
+    pub use crate::scene::{Scene, SceneVariation, SCENE_SIZE};
+    pub use crate::shader::{Shader, ShaderBuilder, ShaderError, ShaderType};
+    pub use crate::terrain::{bush_heights, height_map, variant_map};
+    pub use crate::texture::{CubeTexture, Texture, TextureParams};
+}
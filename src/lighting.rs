@@ -0,0 +1,74 @@
+//! Directional lights, summed up in the fragment shaders.
+
+use nalgebra_glm as glm;
+use serde::{Deserialize, Serialize};
+
+use crate::palette::Palette;
+use crate::shader::Shader;
+
+/// Size of the light arrays in the shaders, more lights are ignored.
+pub const MAX_LIGHTS: usize = 4;
+
+/// A light infinitely far away, like the sun.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct DirectionalLight {
+    /// Normalized direction towards the light.
+    pub direction: glm::Vec3,
+    /// Color multiplied with the intensity.
+    pub color: glm::Vec3,
+}
+
+impl DirectionalLight {
+    /// The direction points towards the light and doesn't need to be normalized.
+    pub fn new(direction: glm::Vec3, color: glm::Vec3) -> Self {
+        DirectionalLight {
+            direction: glm::normalize(&direction),
+            color,
+        }
+    }
+
+    /// A key light in the color of the sun and a weaker, cooler fill light
+    /// from the opposite side, so the shadowed sides don't go flat.
+    pub fn defaults(palette: &Palette) -> Vec<DirectionalLight> {
+        vec![
+            DirectionalLight::new(glm::vec3(0.5, 0.5, 1.0), 0.5 * palette.sun),
+            DirectionalLight::new(
+                glm::vec3(-0.6, -0.3, 0.6),
+                0.2 * glm::mix(&palette.ambient, &glm::vec3(0.6, 0.75, 1.0), 0.5),
+            ),
+        ]
+    }
+}
+
+/// Sets `light_count`, `light_directions` and `light_colors` of the active shader.
+///
+/// # Safety
+/// The shader has to be active and declare these uniforms.
+pub unsafe fn set_light_uniforms(shader: &Shader, lights: &[DirectionalLight]) {
+    let lights = &lights[..lights.len().min(MAX_LIGHTS)];
+    let directions: Vec<f32> = lights
+        .iter()
+        .flat_map(|l| l.direction.iter().copied())
+        .collect();
+    let colors: Vec<f32> = lights
+        .iter()
+        .flat_map(|l| l.color.iter().copied())
+        .collect();
+
+    gl::Uniform1i(
+        shader.get_uniform_location("light_count"),
+        lights.len() as i32,
+    );
+    if !lights.is_empty() {
+        gl::Uniform3fv(
+            shader.get_uniform_location("light_directions"),
+            lights.len() as i32,
+            directions.as_ptr(),
+        );
+        gl::Uniform3fv(
+            shader.get_uniform_location("light_colors"),
+            lights.len() as i32,
+            colors.as_ptr(),
+        );
+    }
+}
@@ -1,3 +1,4 @@
+use std::collections::HashSet;
 use std::error::Error;
 use std::num::NonZeroU32;
 
@@ -5,29 +6,27 @@ use glutin::config::{Config, ConfigTemplateBuilder};
 use glutin::context::{ContextApi, ContextAttributesBuilder, Version};
 use glutin::display::GetGlDisplay;
 use glutin::prelude::*;
-use glutin::surface::SwapInterval;
+use glutin::surface::{SurfaceAttributesBuilder, SwapInterval};
 use glutin_winit::{self, DisplayBuilder, GlWindow};
-use renderer::Renderer;
-use winit::event::{ElementState, Event, KeyEvent, WindowEvent};
+use nalgebra_glm as glm;
+use undergrowth::paint::{BaseMapChannel, Brush};
+use undergrowth::renderer::{Renderer, DEFAULT_DEPTH_BITS, DEFAULT_SEED};
+use undergrowth::scene::Scene;
+use winit::event::{ElementState, Event, KeyEvent, MouseButton, MouseScrollDelta, WindowEvent};
 use winit::event_loop::EventLoopBuilder;
-use winit::keyboard::{Key, NamedKey};
+use winit::keyboard::{Key, KeyCode, NamedKey, PhysicalKey};
 use winit::window::WindowBuilder;
 
-mod assets;
-mod error;
-mod foliage;
-mod mesh;
-mod renderer;
-mod scene;
-mod shader;
-mod terrain;
-mod texture;
-
 /// This main function and the renderer architecture have been adapted and somewhat
 /// slimmed down from
 /// <https://github.com/rust-windowing/glutin/blob/e1bf1e22a3e2f0e3dc4213f85c10f33049ce8d77/glutin_examples/examples/window.rs>.
-/// The better place to start reading is in [`renderer`] or [`scene`].
+/// The better place to start reading is in [`undergrowth::renderer`] or [`undergrowth::scene`].
 pub fn main() -> Result<(), Box<dyn Error>> {
+    let args = Args::parse().unwrap_or_else(|err| {
+        eprintln!("{err}");
+        std::process::exit(2);
+    });
+
     let event_loop = EventLoopBuilder::new().build().unwrap();
 
     // Only Windows requires the window to be present before creating the display.
@@ -44,6 +43,7 @@ pub fn main() -> Result<(), Box<dyn Error>> {
     // with transparency ourselves inside the `reduce`.
     let template = ConfigTemplateBuilder::new()
         .with_alpha_size(8)
+        .with_depth_size(DEFAULT_DEPTH_BITS)
         .with_transparency(cfg!(cgl_backend));
 
     let display_builder = DisplayBuilder::new().with_window_builder(Some(window_builder));
@@ -65,9 +65,57 @@ pub fn main() -> Result<(), Box<dyn Error>> {
         unsafe { gl_display.create_context(&gl_config, &context_attributes) }.ok();
 
     let mut state = None;
-    let mut renderer = None;
+    let mut renderer: Option<Renderer> = None;
+
+    // Painting into the base map: toggled with P, drawn with the left mouse
+    // button and erased with the right one.
+    let mut paint_mode = false;
+    let mut brush = Brush::default();
+    let mut cursor_position = (0.0, 0.0);
+    let mut stroke: Option<bool> = None;
+
+    // The free camera, toggled with C: moved with WASD, space and shift while
+    // held, turned by dragging with the left mouse button. O toggles orbiting
+    // the scene instead, dragged around in the same way and zoomed by scrolling.
+    let mut movement_keys = HashSet::new();
+    let mut looking = false;
+
+    // Taken when the next frame is drawn, before it is swapped to the screen.
+    let mut screenshot_requested = false;
 
     event_loop.run(move |event, window_target| {
+        // By physical key, so the layout of WASD stays the same everywhere.
+        // Tracked before the bindings below, which still get the event, since
+        // these keys produce their characters on other layouts, e.g. the "q"
+        // of the quality on AZERTY.
+        if let Event::WindowEvent {
+            event:
+                WindowEvent::KeyboardInput {
+                    event:
+                        KeyEvent {
+                            physical_key: PhysicalKey::Code(code),
+                            state: key_state,
+                            ..
+                        },
+                    ..
+                },
+            ..
+        } = &event
+        {
+            if let (Some(renderer), Some(_)) = (renderer.as_mut(), movement_direction(*code)) {
+                if *key_state == ElementState::Pressed {
+                    movement_keys.insert(*code);
+                } else {
+                    movement_keys.remove(code);
+                }
+                let direction = movement_keys
+                    .iter()
+                    .filter_map(|&code| movement_direction(code))
+                    .fold(glm::Vec3::zeros(), |sum, direction| sum + direction);
+                renderer.set_movement(direction);
+            }
+        }
+
         match event {
             Event::Resumed => {
                 let window = window.take().unwrap_or_else(|| {
@@ -78,7 +126,10 @@ pub fn main() -> Result<(), Box<dyn Error>> {
                         .unwrap()
                 });
 
-                let attrs = window.build_surface_attributes(Default::default());
+                // Encodes the linear colors, see `Renderer::new`
+                let attrs = window.build_surface_attributes(
+                    SurfaceAttributesBuilder::new().with_srgb(Some(true)),
+                );
                 let gl_surface = unsafe {
                     gl_config
                         .display()
@@ -96,7 +147,7 @@ pub fn main() -> Result<(), Box<dyn Error>> {
                 // The context needs to be current for the Renderer to set up shaders and
                 // buffers. It also performs function loading, which needs a current context on
                 // WGL.
-                renderer.get_or_insert_with(|| Renderer::new(&gl_display));
+                renderer.get_or_insert_with(|| Renderer::new(&gl_display, args.seed));
 
                 // Try setting vsync.
                 if let Err(res) = gl_surface
@@ -108,21 +159,19 @@ pub fn main() -> Result<(), Box<dyn Error>> {
                 assert!(state.replace((gl_context, gl_surface, window)).is_none());
             }
             Event::WindowEvent { event, .. } => match event {
-                WindowEvent::Resized(size) => {
-                    if size.width != 0 && size.height != 0 {
-                        // Some platforms like EGL require resizing GL surface to update the size
-                        // Notable platforms here are Wayland and macOS, other don't require it
-                        // and the function is no-op, but it's wise to resize it for portability
-                        // reasons.
-                        if let Some((gl_context, gl_surface, _)) = &state {
-                            gl_surface.resize(
-                                gl_context,
-                                NonZeroU32::new(size.width).unwrap(),
-                                NonZeroU32::new(size.height).unwrap(),
-                            );
-                            let renderer = renderer.as_mut().unwrap();
-                            renderer.resize(size.width as i32, size.height as i32);
-                        }
+                WindowEvent::Resized(size) if size.width != 0 && size.height != 0 => {
+                    // Some platforms like EGL require resizing GL surface to update the size
+                    // Notable platforms here are Wayland and macOS, other don't require it
+                    // and the function is no-op, but it's wise to resize it for portability
+                    // reasons.
+                    if let Some((gl_context, gl_surface, _)) = &state {
+                        gl_surface.resize(
+                            gl_context,
+                            NonZeroU32::new(size.width).unwrap(),
+                            NonZeroU32::new(size.height).unwrap(),
+                        );
+                        let renderer = renderer.as_mut().unwrap();
+                        renderer.resize(size.width as i32, size.height as i32);
                     }
                 }
                 WindowEvent::CloseRequested
@@ -138,16 +187,21 @@ pub fn main() -> Result<(), Box<dyn Error>> {
                     if let Some((gl_context, gl_surface, window)) = &state {
                         let renderer = renderer.as_mut().unwrap();
                         renderer.draw();
+                        if std::mem::take(&mut screenshot_requested) {
+                            save_screenshot(renderer);
+                        }
                         window.request_redraw();
 
                         gl_surface.swap_buffers(gl_context).unwrap();
                     }
                 }
+                // On press, so holding the key scrubs through the seeds. Only
+                // the last one requested before a frame is generated.
                 WindowEvent::KeyboardInput {
                     event:
                         KeyEvent {
                             logical_key: Key::Named(NamedKey::ArrowRight),
-                            state: ElementState::Released,
+                            state: ElementState::Pressed,
                             ..
                         },
                     ..
@@ -156,12 +210,13 @@ pub fn main() -> Result<(), Box<dyn Error>> {
                         break 'block;
                     };
                     renderer.next_scene();
+                    println!("Seed: {}", renderer.target_seed());
                 }
                 WindowEvent::KeyboardInput {
                     event:
                         KeyEvent {
                             logical_key: Key::Named(NamedKey::ArrowLeft),
-                            state: ElementState::Released,
+                            state: ElementState::Pressed,
                             ..
                         },
                     ..
@@ -170,6 +225,230 @@ pub fn main() -> Result<(), Box<dyn Error>> {
                         break 'block;
                     };
                     renderer.prev_scene();
+                    println!("Seed: {}", renderer.target_seed());
+                }
+                WindowEvent::KeyboardInput {
+                    event:
+                        KeyEvent {
+                            logical_key: Key::Character(c),
+                            state: ElementState::Released,
+                            ..
+                        },
+                    ..
+                } if c.as_str() == "t" => 'block: {
+                    let Some(renderer) = renderer.as_mut() else {
+                        break 'block;
+                    };
+                    renderer.toggle_terrain_only();
+                }
+                // How much the culling and level of detail left out
+                WindowEvent::KeyboardInput {
+                    event:
+                        KeyEvent {
+                            logical_key: Key::Character(c),
+                            state: ElementState::Released,
+                            ..
+                        },
+                    ..
+                } if c.as_str() == "i" => 'block: {
+                    let Some(renderer) = renderer.as_ref() else {
+                        break 'block;
+                    };
+                    println!("Last frame: {}", renderer.frame_stats());
+                }
+                WindowEvent::KeyboardInput {
+                    event:
+                        KeyEvent {
+                            logical_key: Key::Character(c),
+                            state: ElementState::Released,
+                            ..
+                        },
+                    ..
+                } if c.as_str() == "q" => 'block: {
+                    let Some(renderer) = renderer.as_mut() else {
+                        break 'block;
+                    };
+                    let quality = renderer.quality().next();
+                    renderer.set_quality(quality);
+                    println!("Quality: {quality:?}");
+                }
+                WindowEvent::KeyboardInput {
+                    event:
+                        KeyEvent {
+                            logical_key: Key::Character(c),
+                            state: ElementState::Released,
+                            ..
+                        },
+                    ..
+                } if c.as_str() == "v" => 'block: {
+                    let Some(renderer) = renderer.as_mut() else {
+                        break 'block;
+                    };
+                    let debug_view = renderer.debug_view().next();
+                    renderer.set_debug_view(debug_view);
+                    println!("Debug view: {debug_view:?}");
+                }
+                WindowEvent::KeyboardInput {
+                    event:
+                        KeyEvent {
+                            logical_key: Key::Character(c),
+                            state: ElementState::Released,
+                            ..
+                        },
+                    ..
+                } if matches!(c.as_str(), "p" | "1" | "2" | "3" | "g" | "m") => 'block: {
+                    let Some(renderer) = renderer.as_mut() else {
+                        break 'block;
+                    };
+                    match c.as_str() {
+                        "p" => {
+                            paint_mode = !paint_mode;
+                            println!("Paint mode: {paint_mode}");
+                        }
+                        "1" => brush.channel = BaseMapChannel::Height,
+                        "2" => brush.channel = BaseMapChannel::Bushiness,
+                        "3" => brush.channel = BaseMapChannel::Trees,
+                        "g" => renderer.regenerate_scene(),
+                        "m" => match renderer.save_base_map("base_map_edited.png".as_ref()) {
+                            Ok(()) => println!("Saved base_map_edited.png"),
+                            Err(err) => eprintln!("Failed to save the base map: {err}"),
+                        },
+                        _ => unreachable!(),
+                    }
+                }
+                WindowEvent::KeyboardInput {
+                    event:
+                        KeyEvent {
+                            logical_key: Key::Character(c),
+                            state: ElementState::Released,
+                            ..
+                        },
+                    ..
+                } if matches!(c.as_str(), "+" | "=" | "-") => 'block: {
+                    let Some(renderer) = renderer.as_mut() else {
+                        break 'block;
+                    };
+                    // "=" is "+" without shift on most layouts
+                    let factor = if c.as_str() == "-" { 1.0 / 1.25 } else { 1.25 };
+                    renderer.set_density_multiplier(renderer.density_multiplier() * factor);
+                    println!("Density multiplier: {:.2}", renderer.density_multiplier());
+                }
+                WindowEvent::KeyboardInput {
+                    event:
+                        KeyEvent {
+                            logical_key: Key::Character(c),
+                            state: ElementState::Released,
+                            ..
+                        },
+                    ..
+                } if matches!(c.as_str(), "f" | "[" | "]") => 'block: {
+                    let Some(renderer) = renderer.as_mut() else {
+                        break 'block;
+                    };
+                    match c.as_str() {
+                        "f" => match renderer.add_favorite() {
+                            Ok(()) => println!("Added the scene to the favorites"),
+                            Err(err) => eprintln!("Failed to save the favorites: {err}"),
+                        },
+                        "]" => renderer.next_favorite(),
+                        "[" => renderer.prev_favorite(),
+                        _ => unreachable!(),
+                    }
+                }
+                WindowEvent::KeyboardInput {
+                    event:
+                        KeyEvent {
+                            logical_key: Key::Character(c),
+                            state: ElementState::Released,
+                            ..
+                        },
+                    ..
+                } if matches!(c.as_str(), "c" | "o") => 'block: {
+                    let Some(renderer) = renderer.as_mut() else {
+                        break 'block;
+                    };
+                    if c.as_str() == "c" {
+                        renderer.toggle_free_camera();
+                    } else {
+                        renderer.toggle_orbit_camera();
+                    }
+                }
+                WindowEvent::KeyboardInput {
+                    event:
+                        KeyEvent {
+                            logical_key: Key::Named(NamedKey::F12),
+                            state: ElementState::Released,
+                            ..
+                        },
+                    ..
+                } => screenshot_requested = true,
+                // F5 saves everything needed to reproduce the frame, F9 restores it
+                WindowEvent::KeyboardInput {
+                    event:
+                        KeyEvent {
+                            logical_key: Key::Named(named @ (NamedKey::F5 | NamedKey::F9)),
+                            state: ElementState::Released,
+                            ..
+                        },
+                    ..
+                } => 'block: {
+                    let Some(renderer) = renderer.as_mut() else {
+                        break 'block;
+                    };
+                    let path = std::path::Path::new("scene_state.ron");
+                    if named == NamedKey::F5 {
+                        match renderer.dump_state(path) {
+                            Ok(()) => println!("Saved scene_state.ron"),
+                            Err(err) => eprintln!("Failed to save the state: {err}"),
+                        }
+                    } else if let Err(err) = renderer.load_state(path) {
+                        eprintln!("Failed to load the state: {err}");
+                    }
+                }
+                WindowEvent::CursorMoved { position, .. } => {
+                    let previous = cursor_position;
+                    cursor_position = (position.x as f32, position.y as f32);
+                    if let (true, Some(renderer)) = (looking, renderer.as_mut()) {
+                        // Radians per pixel
+                        let sensitivity = 0.004;
+                        renderer.rotate_camera(
+                            (previous.0 - cursor_position.0) * sensitivity,
+                            (previous.1 - cursor_position.1) * sensitivity,
+                        );
+                    }
+                    if let (Some(erase), Some(renderer)) = (stroke, renderer.as_mut()) {
+                        let (x, y) = cursor_position;
+                        renderer.paint_base_map(x, y, &brush, erase);
+                    }
+                }
+                WindowEvent::MouseInput { state, button, .. } if paint_mode => {
+                    let erase = match button {
+                        MouseButton::Left => false,
+                        MouseButton::Right => true,
+                        _ => return,
+                    };
+                    stroke = (state == ElementState::Pressed).then_some(erase);
+                    if let (Some(erase), Some(renderer)) = (stroke, renderer.as_mut()) {
+                        let (x, y) = cursor_position;
+                        renderer.paint_base_map(x, y, &brush, erase);
+                    }
+                }
+                WindowEvent::MouseInput {
+                    state,
+                    button: MouseButton::Left,
+                    ..
+                } => looking = state == ElementState::Pressed,
+                WindowEvent::MouseWheel { delta, .. } => 'block: {
+                    let Some(renderer) = renderer.as_mut() else {
+                        break 'block;
+                    };
+                    let lines = match delta {
+                        MouseScrollDelta::LineDelta(_, lines) => lines,
+                        // Roughly the pixels of a line on touchpads
+                        MouseScrollDelta::PixelDelta(position) => position.y as f32 / 20.0,
+                    };
+                    // Scrolling up comes closer
+                    renderer.zoom_camera(0.9_f32.powf(lines));
                 }
                 _ => (),
             },
@@ -180,11 +459,90 @@ pub fn main() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+/// Saves the frame in the back buffer, named after the time it was taken.
+fn save_screenshot(renderer: &Renderer) {
+    let seconds = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let path = format!("screenshot_{seconds}.png");
+    // Opaque, since a PNG viewer has no desktop to show through the window
+    match renderer.screenshot(path.as_ref()) {
+        Ok(()) => println!("Saved {path}"),
+        Err(err) => eprintln!("Failed to save the screenshot: {err}"),
+    }
+}
+
+/// The options given on the command line.
+struct Args {
+    /// The first scene shown.
+    seed: u32,
+}
+
+impl Args {
+    const USAGE: &'static str = "Usage: undergrowth [--seed <u32> | --scene-name <name>]";
+
+    fn parse() -> Result<Self, String> {
+        let mut seed = None;
+        let mut arguments = std::env::args().skip(1);
+        while let Some(argument) = arguments.next() {
+            let new_seed = match argument.as_str() {
+                "--seed" | "--scene-name" => {
+                    let value = arguments
+                        .next()
+                        .ok_or_else(|| format!("{argument} needs a value\n{}", Self::USAGE))?;
+                    if argument == "--seed" {
+                        value.parse().map_err(|err| {
+                            format!(
+                                "Invalid seed {value:?}: {err}, expected a whole number from 0 to {}",
+                                u32::MAX
+                            )
+                        })?
+                    } else {
+                        let seed = Scene::seed_from_str(&value);
+                        println!("Scene {value:?} has the seed {seed}");
+                        seed
+                    }
+                }
+                _ => return Err(format!("Unknown argument {argument:?}\n{}", Self::USAGE)),
+            };
+            if seed.replace(new_seed).is_some() {
+                return Err(format!(
+                    "Only one seed or scene name can be given\n{}",
+                    Self::USAGE
+                ));
+            }
+        }
+        Ok(Args {
+            seed: seed.unwrap_or(DEFAULT_SEED),
+        })
+    }
+}
+
+/// The direction a key moves the free camera, see
+/// [`undergrowth::camera::FreeCamera::advance`].
+fn movement_direction(code: KeyCode) -> Option<glm::Vec3> {
+    match code {
+        KeyCode::KeyW => Some(glm::vec3(0.0, 1.0, 0.0)),
+        KeyCode::KeyS => Some(glm::vec3(0.0, -1.0, 0.0)),
+        KeyCode::KeyD => Some(glm::vec3(1.0, 0.0, 0.0)),
+        KeyCode::KeyA => Some(glm::vec3(-1.0, 0.0, 0.0)),
+        KeyCode::Space => Some(glm::vec3(0.0, 0.0, 1.0)),
+        KeyCode::ShiftLeft | KeyCode::ShiftRight => Some(glm::vec3(0.0, 0.0, -1.0)),
+        _ => None,
+    }
+}
+
 // Find the config with the maximum number of samples, so our triangle will be
-// smooth.
+// smooth. The template can't ask for sRGB, so prefer configs capable of it:
+// the renderer writes linear colors and relies on the window encoding them.
 pub fn gl_config_picker(configs: Box<dyn Iterator<Item = Config> + '_>) -> Config {
     configs
         .reduce(|accum, config| {
+            if config.srgb_capable() != accum.srgb_capable() {
+                return if config.srgb_capable() { config } else { accum };
+            }
+
             let transparency_check = config.supports_transparency().unwrap_or(false)
                 & !accum.supports_transparency().unwrap_or(false);
 
@@ -1,26 +1,153 @@
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
 use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
 
 use gl::types::GLuint;
 use nalgebra_glm as glm;
-use tobj;
+use noise::NoiseFn;
 
 use crate::error::{clear_gl_errors, get_gl_errors};
+use crate::shader::has_extension;
 
 pub const POSITION_ATTRIB_PTR: u32 = 0;
 pub const NORMAL_ATTRIB_PTR: u32 = 1;
-#[allow(dead_code)]
 pub const TANGENT_ATTRIB_PTR: u32 = 2;
-#[allow(dead_code)]
 pub const BITANGENT_ATTRIB_PTR: u32 = 3;
 pub const UV_ATTRIB_PTR: u32 = 4;
 
-pub const MODEL_MAT_ATTRIB_PTR_1: u32 = 8;
-pub const MODEL_MAT_ATTRIB_PTR_2: u32 = 9;
-pub const MODEL_MAT_ATTRIB_PTR_3: u32 = 10;
-pub const MODEL_MAT_ATTRIB_PTR_4: u32 = 11;
-pub const MODEL_NORMAL_ATTRIB_PTR_1: u32 = 12;
-pub const MODEL_NORMAL_ATTRIB_PTR_2: u32 = 13;
-pub const MODEL_NORMAL_ATTRIB_PTR_3: u32 = 14;
+/// A float vector or matrix attribute that changes per instance instead of
+/// per vertex. Each one is stored in its own buffer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InstanceAttribute {
+    /// Name of the input in the shaders, only for error messages.
+    pub name: &'static str,
+    /// Location of the first column, matrices take one location per column.
+    pub location: u32,
+    /// Components per column, between 1 and 4.
+    pub rows: usize,
+    /// 1 for vectors.
+    pub columns: usize,
+    /// Number of instances that share a value, usually 1.
+    pub divisor: u32,
+}
+
+impl InstanceAttribute {
+    /// The `mat4 model_mat` of the instanced shaders.
+    pub const MODEL_MAT: InstanceAttribute = InstanceAttribute::matrix("model_mat", 8, 4);
+    /// The `mat3 normal_mat` of the instanced shaders.
+    pub const NORMAL_MAT: InstanceAttribute = InstanceAttribute::matrix("normal_mat", 12, 3);
+    /// The `vec3 color_jitter` of the foliage shader, offsets of the hue,
+    /// saturation and value of the albedo. At the last of the 16 locations
+    /// every implementation has.
+    pub const COLOR_JITTER: InstanceAttribute = InstanceAttribute::vector("color_jitter", 15, 3);
+
+    /// A vector with `components` floats.
+    pub const fn vector(name: &'static str, location: u32, components: usize) -> Self {
+        InstanceAttribute {
+            name,
+            location,
+            rows: components,
+            columns: 1,
+            divisor: 1,
+        }
+    }
+
+    /// A square matrix of floats.
+    pub const fn matrix(name: &'static str, location: u32, size: usize) -> Self {
+        InstanceAttribute {
+            name,
+            location,
+            rows: size,
+            columns: size,
+            divisor: 1,
+        }
+    }
+
+    pub fn floats_per_instance(&self) -> usize {
+        self.rows * self.columns
+    }
+
+    /// All locations used by this attribute.
+    pub fn locations(&self) -> std::ops::Range<u32> {
+        self.location..self.location + self.columns as u32
+    }
+
+    /// Points the attribute at the bound array buffer, starting at `first_instance`.
+    unsafe fn set_pointers(&self, first_instance: usize) {
+        let column_size = self.rows * std::mem::size_of::<f32>();
+        let stride = self.columns * column_size;
+        for column in 0..self.columns {
+            let location = self.location + column as u32;
+            gl::EnableVertexAttribArray(location);
+            gl::VertexAttribPointer(
+                location,
+                self.rows as i32,
+                gl::FLOAT,
+                gl::FALSE,
+                stride as gl::types::GLint,
+                (first_instance * stride + column * column_size) as *const _,
+            );
+            gl::VertexAttribDivisor(location, self.divisor);
+        }
+    }
+}
+
+/// Fixes applied while loading a model with [`Mesh::load_with`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MeshLoadOptions {
+    /// Turns the model inside out, see [`Mesh::flip_winding`].
+    pub flip_winding: bool,
+    /// Turns the model inside out only if it looks inside out, see
+    /// [`Mesh::fix_winding`]. Checked after `flip_winding`.
+    pub fix_winding: bool,
+    /// Replaces the normals of the file, see [`Mesh::recompute_normals`].
+    /// Models without any normals always get them computed.
+    pub recompute_normals: bool,
+}
+
+/// Why [`Mesh::load`] couldn't make a mesh of a model. Each case names the
+/// file that was loaded.
+#[derive(Debug)]
+pub enum MeshLoadError {
+    /// The file couldn't be read or isn't valid OBJ.
+    Obj {
+        path: PathBuf,
+        source: tobj::LoadError,
+    },
+    /// The file has no objects or groups at all.
+    NoMeshes { path: PathBuf },
+    /// The mesh has only this many vertices and no faces, e.g. from a point
+    /// cloud export.
+    NoFaces { path: PathBuf, vertices: usize },
+}
+
+impl std::fmt::Display for MeshLoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MeshLoadError::Obj { path, source } => {
+                write!(f, "{} is not a valid OBJ file: {source}", path.display())
+            }
+            MeshLoadError::NoMeshes { path } => {
+                write!(f, "the model {} has no meshes", path.display())
+            }
+            MeshLoadError::NoFaces { path, vertices } => write!(
+                f,
+                "the model {} has no faces, only {vertices} vertices. Was it exported as a point cloud?",
+                path.display()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MeshLoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MeshLoadError::Obj { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
 
 pub struct Mesh {
     /// Cyclic X, Y, Z components
@@ -29,12 +156,22 @@ pub struct Mesh {
     pub normals: Vec<f32>,
     /// Cyclic U, V components
     pub uvs: Vec<f32>,
+    /// Cyclic X, Y, Z components along increasing U, empty unless computed
+    /// with [`Mesh::compute_tangents`].
+    pub tangents: Vec<f32>,
+    /// Cyclic X, Y, Z components along increasing V, like the tangents.
+    pub bitangents: Vec<f32>,
 
     /// Cyclic first, second, thrid vertex index.
     pub indices: Vec<u32>,
+
+    /// The diffuse map of the model's material, relative to the working
+    /// directory like the model path itself.
+    pub diffuse_texture: Option<PathBuf>,
 }
 
 /// Some data stored on the GPU.
+#[allow(clippy::upper_case_acronyms)]
 pub struct VAO {
     id: GLuint,
     vbos: Vec<GLuint>,
@@ -44,12 +181,20 @@ pub struct VAO {
 
 pub struct ElementMeshVAO {
     index_count: usize,
+    /// `gl::UNSIGNED_SHORT` for meshes with few enough vertices, otherwise
+    /// `gl::UNSIGNED_INT`.
+    index_type: gl::types::GLenum,
+    /// Of the mesh it was created from, see [`Mesh::bounding_box`].
+    bounding_box: (glm::Vec3, glm::Vec3),
     vao: VAO,
 }
 
 pub struct InstancedMeshesVAO {
     index_count_per_instance: usize,
+    index_type: gl::types::GLenum,
     instance_count: usize,
+    /// Every attribute with the buffer it reads from.
+    instance_buffers: Vec<(InstanceAttribute, GLuint)>,
     vao: VAO,
 }
 
@@ -59,13 +204,23 @@ impl Mesh {
             positions: mesh.positions,
             normals: mesh.normals,
             uvs: mesh.texcoords,
+            tangents: Vec::new(),
+            bitangents: Vec::new(),
 
             indices: mesh.indices,
+            diffuse_texture: None,
         }
     }
 
-    pub fn load(path: &str) -> Self {
-        let (models, _materials) = tobj::load_obj(
+    /// Loads an OBJ file, merging all of its objects into one mesh with
+    /// [`Mesh::merge`]. Only the texture of the first material is kept.
+    pub fn load(path: &str) -> Result<Self, MeshLoadError> {
+        Self::load_with(path, &MeshLoadOptions::default())
+    }
+
+    /// Like [`Mesh::load`], but repairs models that were exported inside out.
+    pub fn load_with(path: &str, options: &MeshLoadOptions) -> Result<Self, MeshLoadError> {
+        let (models, materials) = tobj::load_obj(
             path,
             &tobj::LoadOptions {
                 triangulate: true,
@@ -73,45 +228,417 @@ impl Mesh {
                 ..Default::default()
             },
         )
-        .expect("Failed to load model");
+        .map_err(|source| MeshLoadError::Obj {
+            path: path.into(),
+            source,
+        })?;
 
-        if models.len() > 1 || models.len() == 0 {
-            panic!("Please use a model with a single mesh!")
-            // You could try merging the vertices and indices
-            // of the separate meshes into a single mesh.
-            // I'll leave that as an optional exercise. ;)
+        if models.is_empty() {
+            return Err(MeshLoadError::NoMeshes { path: path.into() });
+        }
+
+        // A missing material library only costs the default texture
+        let materials = materials.unwrap_or_else(|err| {
+            eprintln!("Ignoring the materials of {path}: {err}");
+            Vec::new()
+        });
+        let names: Vec<_> = models.iter().map(|model| model.name.as_str()).collect();
+        let names = names.join(", ");
+        let parts: Vec<_> = models
+            .into_iter()
+            .map(|model| {
+                let material = match model.mesh.material_id {
+                    Some(id) => materials.get(id),
+                    None if materials.len() == 1 => materials.first(),
+                    None => None,
+                };
+                // Texture paths in the material library are relative to the model
+                let diffuse_texture = material
+                    .and_then(|material| material.diffuse_texture.as_ref())
+                    .map(|texture| Path::new(path).with_file_name(texture));
+                Mesh {
+                    diffuse_texture,
+                    ..Mesh::from(model.mesh)
+                }
+            })
+            .collect();
+        let textures: HashSet<_> = parts
+            .iter()
+            .filter_map(|part| part.diffuse_texture.as_ref())
+            .collect();
+        if textures.len() > 1 {
+            eprintln!(
+                "The meshes of {path} use {} textures, only the first one is used.",
+                textures.len()
+            );
         }
 
-        let terrain = models[0].to_owned();
+        let mut mesh = Mesh::merge(parts);
+        // Would otherwise only fail much later, when creating the VAO
+        if mesh.indices.len() < 3 {
+            return Err(MeshLoadError::NoFaces {
+                path: path.into(),
+                vertices: mesh.positions.len() / 3,
+            });
+        }
         println!(
-            "Loaded {} with {} vertices and {} triangles.",
-            terrain.name,
-            terrain.mesh.positions.len() / 3,
-            terrain.mesh.indices.len() / 3,
+            "Loaded {names} with {} vertices and {} triangles.",
+            mesh.positions.len() / 3,
+            mesh.indices.len() / 3,
         );
+        if options.recompute_normals || mesh.normals.is_empty() {
+            mesh.recompute_normals();
+        }
+        if options.flip_winding {
+            mesh.flip_winding();
+        }
+        if options.fix_winding && mesh.fix_winding() {
+            println!("Turned {path} right side out, it was inside out.");
+        }
+        Ok(mesh)
+    }
 
-        Mesh::from(terrain.mesh)
+    /// Concatenates meshes into one, offsetting the indices of each part by
+    /// the vertices before it. If only some parts have normals or UVs, the
+    /// others get zeros for them. Tangents are only kept if all parts have
+    /// them. Keeps the first texture of the parts.
+    pub fn merge(parts: Vec<Mesh>) -> Mesh {
+        let has_normals = parts.iter().any(|part| !part.normals.is_empty());
+        let has_uvs = parts.iter().any(|part| !part.uvs.is_empty());
+        let has_tangents = parts.iter().all(|part| !part.tangents.is_empty());
+        let mut merged = Mesh {
+            positions: Vec::new(),
+            normals: Vec::new(),
+            uvs: Vec::new(),
+            tangents: Vec::new(),
+            bitangents: Vec::new(),
+            indices: Vec::new(),
+            diffuse_texture: parts.iter().find_map(|part| part.diffuse_texture.clone()),
+        };
+        for part in parts {
+            let vertices = part.positions.len() / 3;
+            let offset = (merged.positions.len() / 3) as u32;
+            merged
+                .indices
+                .extend(part.indices.iter().map(|index| index + offset));
+            merged.positions.extend(part.positions);
+            if has_normals {
+                if part.normals.is_empty() {
+                    merged
+                        .normals
+                        .resize(merged.normals.len() + 3 * vertices, 0.0);
+                } else {
+                    merged.normals.extend(part.normals);
+                }
+            }
+            if has_uvs {
+                if part.uvs.is_empty() {
+                    merged.uvs.resize(merged.uvs.len() + 2 * vertices, 0.0);
+                } else {
+                    merged.uvs.extend(part.uvs);
+                }
+            }
+            if has_tangents {
+                merged.tangents.extend(part.tangents);
+                merged.bitangents.extend(part.bitangents);
+            }
+        }
+        merged
+    }
+
+    /// Writes the mesh as an OBJ file with a single object, that
+    /// [`Mesh::load`] reads back. The texture is not referenced.
+    pub fn save_obj(&self, path: &Path) -> std::io::Result<()> {
+        use std::io::Write;
+
+        let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        for position in self.positions.chunks_exact(3) {
+            writeln!(file, "v {} {} {}", position[0], position[1], position[2])?;
+        }
+        for uv in self.uvs.chunks_exact(2) {
+            writeln!(file, "vt {} {}", uv[0], uv[1])?;
+        }
+        for normal in self.normals.chunks_exact(3) {
+            writeln!(file, "vn {} {} {}", normal[0], normal[1], normal[2])?;
+        }
+        // There is one UV and normal per vertex, so they share its index
+        let (has_uvs, has_normals) = (!self.uvs.is_empty(), !self.normals.is_empty());
+        for triangle in self.indices.chunks_exact(3) {
+            write!(file, "f")?;
+            for index in triangle {
+                // OBJ counts from one
+                let index = index + 1;
+                match (has_uvs, has_normals) {
+                    (true, true) => write!(file, " {index}/{index}/{index}")?,
+                    (true, false) => write!(file, " {index}/{index}")?,
+                    (false, true) => write!(file, " {index}//{index}")?,
+                    (false, false) => write!(file, " {index}")?,
+                }
+            }
+            writeln!(file)?;
+        }
+        file.flush()
+    }
+
+    /// Merges vertices whose positions, normals and UVs all differ by at most
+    /// `epsilon` per component, and returns how many were removed. The
+    /// triangles then use the first of the merged vertices.
+    pub fn weld(&mut self, epsilon: f32) -> usize {
+        let vertex_count = self.positions.len() / 3;
+        // Close vertices are in the same or a neighboring cell. Cells are at
+        // least a millionth of the extent, so tiny epsilons (even zero) don't
+        // push the cell coordinates to the limits of i64.
+        let extent = self
+            .positions
+            .iter()
+            .fold(0.0f32, |max, p| max.max(p.abs()));
+        let cell_size = epsilon.max(extent * 1e-6).max(f32::MIN_POSITIVE);
+        let cell = |vertex: usize| {
+            let component =
+                |axis: usize| (self.positions[3 * vertex + axis] / cell_size).floor() as i64;
+            (component(0), component(1), component(2))
+        };
+        let close = |a: usize, b: usize| {
+            [(&self.positions, 3), (&self.normals, 3), (&self.uvs, 2)]
+                .into_iter()
+                .filter(|(data, _)| !data.is_empty())
+                .all(|(data, components)| {
+                    (0..components).all(|i| {
+                        (data[components * a + i] - data[components * b + i]).abs() <= epsilon
+                    })
+                })
+        };
+
+        let mut cells: HashMap<(i64, i64, i64), Vec<usize>> = HashMap::new();
+        // The old index of every remaining vertex, and the new index of every old one
+        let mut kept = Vec::new();
+        let mut new_indices = Vec::with_capacity(vertex_count);
+        for vertex in 0..vertex_count {
+            let (x, y, z) = cell(vertex);
+            let neighbors = (-1..=1).flat_map(|dx| {
+                (-1..=1).flat_map(move |dy| {
+                    (-1..=1).map(move |dz| {
+                        (
+                            x.saturating_add(dx),
+                            y.saturating_add(dy),
+                            z.saturating_add(dz),
+                        )
+                    })
+                })
+            });
+            let existing = neighbors
+                .filter_map(|neighbor| cells.get(&neighbor))
+                .flatten()
+                .copied()
+                .find(|&new_index| close(kept[new_index], vertex));
+            let new_index = existing.unwrap_or_else(|| {
+                kept.push(vertex);
+                cells.entry((x, y, z)).or_default().push(kept.len() - 1);
+                kept.len() - 1
+            });
+            new_indices.push(new_index as u32);
+        }
+
+        let gather = |data: &[f32], components: usize| -> Vec<f32> {
+            if data.is_empty() {
+                return Vec::new();
+            }
+            kept.iter()
+                .flat_map(|&vertex| &data[components * vertex..components * (vertex + 1)])
+                .copied()
+                .collect()
+        };
+        self.positions = gather(&self.positions, 3);
+        self.normals = gather(&self.normals, 3);
+        self.uvs = gather(&self.uvs, 2);
+        self.tangents = gather(&self.tangents, 3);
+        self.bitangents = gather(&self.bitangents, 3);
+        for index in &mut self.indices {
+            *index = new_indices[*index as usize];
+        }
+        vertex_count - kept.len()
+    }
+
+    /// Smooth normals from the triangles around each vertex, weighted by their
+    /// area. Vertices that aren't part of a proper triangle get a zero normal.
+    pub fn recompute_normals(&mut self) {
+        self.normals.clear();
+        self.normals.resize(self.positions.len(), 0.0);
+        let vertex = |index: u32| {
+            let i = 3 * index as usize;
+            glm::vec3(
+                self.positions[i],
+                self.positions[i + 1],
+                self.positions[i + 2],
+            )
+        };
+        for triangle in self.indices.chunks_exact(3) {
+            let (a, b, c) = (
+                vertex(triangle[0]),
+                vertex(triangle[1]),
+                vertex(triangle[2]),
+            );
+            // Twice the area long, so larger faces weigh more
+            let face_normal = glm::cross(&(b - a), &(c - a));
+            for &index in triangle {
+                let i = 3 * index as usize;
+                for axis in 0..3 {
+                    self.normals[i + axis] += face_normal[axis];
+                }
+            }
+        }
+        for normal in self.normals.chunks_exact_mut(3) {
+            let length = glm::vec3(normal[0], normal[1], normal[2]).norm();
+            if length > 0.0 {
+                normal.iter_mut().for_each(|component| *component /= length);
+            }
+        }
+    }
+
+    /// Tangents and bitangents along the UV directions, for normal mapping.
+    /// Does nothing for meshes without UVs.
+    ///
+    /// Sums the UV gradients of the triangles around each vertex, after Eric
+    /// Lengyel. The tangents are made orthogonal to the normals if there are
+    /// any, the bitangents keep the handedness of the UV mapping.
+    pub fn compute_tangents(&mut self) {
+        if self.uvs.is_empty() {
+            return;
+        }
+        let vertex_count = self.positions.len() / 3;
+        let position = |index: usize| {
+            glm::vec3(
+                self.positions[3 * index],
+                self.positions[3 * index + 1],
+                self.positions[3 * index + 2],
+            )
+        };
+        let uv = |index: usize| glm::vec2(self.uvs[2 * index], self.uvs[2 * index + 1]);
+
+        let mut tangents = vec![glm::Vec3::zeros(); vertex_count];
+        let mut bitangents = vec![glm::Vec3::zeros(); vertex_count];
+        for triangle in self.indices.chunks_exact(3) {
+            let [a, b, c] = [0, 1, 2].map(|corner| triangle[corner] as usize);
+            let (edge1, edge2) = (position(b) - position(a), position(c) - position(a));
+            let (duv1, duv2) = (uv(b) - uv(a), uv(c) - uv(a));
+            let determinant = duv1.x * duv2.y - duv2.x * duv1.y;
+            // The UVs of the triangle are degenerate, it has no direction to add
+            if determinant.abs() < f32::EPSILON {
+                continue;
+            }
+            let tangent = (edge1 * duv2.y - edge2 * duv1.y) / determinant;
+            let bitangent = (edge2 * duv1.x - edge1 * duv2.x) / determinant;
+            for index in [a, b, c] {
+                tangents[index] += tangent;
+                bitangents[index] += bitangent;
+            }
+        }
+
+        self.tangents.clear();
+        self.bitangents.clear();
+        for (index, (tangent, bitangent)) in tangents.into_iter().zip(bitangents).enumerate() {
+            let (tangent, bitangent) = if self.normals.is_empty() {
+                (tangent, bitangent)
+            } else {
+                let i = 3 * index;
+                let normal = glm::vec3(self.normals[i], self.normals[i + 1], self.normals[i + 2]);
+                let tangent = tangent - normal * glm::dot(&normal, &tangent);
+                let handedness = if glm::dot(&glm::cross(&normal, &tangent), &bitangent) < 0.0 {
+                    -1.0
+                } else {
+                    1.0
+                };
+                (tangent, glm::cross(&normal, &tangent) * handedness)
+            };
+            let normalize = |v: glm::Vec3| if v.norm() > 0.0 { v.normalize() } else { v };
+            self.tangents.extend(normalize(tangent).iter());
+            self.bitangents.extend(normalize(bitangent).iter());
+        }
+    }
+
+    /// Reverses the order of the vertices of every triangle and negates the
+    /// normals, which turns the front faces into back faces and vice versa.
+    pub fn flip_winding(&mut self) {
+        for triangle in self.indices.chunks_exact_mut(3) {
+            triangle.swap(1, 2);
+        }
+        for normal in &mut self.normals {
+            *normal = -*normal;
+        }
+    }
+
+    /// Flips the winding if the triangles mostly face inwards, and returns
+    /// whether it did.
+    ///
+    /// Sums up the area weighted face directions relative to the center,
+    /// which is the signed volume for closed meshes. Open ones like single
+    /// sided leaves have no inside, the result is arbitrary for them.
+    pub fn fix_winding(&mut self) -> bool {
+        let (min, max) = self.bounding_box();
+        let center = (min + max) / 2.0;
+        let vertex = |index: u32| {
+            let i = 3 * index as usize;
+            glm::vec3(
+                self.positions[i],
+                self.positions[i + 1],
+                self.positions[i + 2],
+            ) - center
+        };
+        let volume: f32 = self
+            .indices
+            .chunks_exact(3)
+            .map(|t| {
+                let (a, b, c) = (vertex(t[0]), vertex(t[1]), vertex(t[2]));
+                glm::dot(&a, &glm::cross(&b, &c))
+            })
+            .sum();
+        let inside_out = volume < 0.0;
+        if inside_out {
+            self.flip_winding();
+        }
+        inside_out
+    }
+
+    /// The minimum and maximum corner of the positions, both at the origin
+    /// for an empty mesh.
+    pub fn bounding_box(&self) -> (glm::Vec3, glm::Vec3) {
+        let mut points = self
+            .positions
+            .chunks_exact(3)
+            .map(|p| glm::vec3(p[0], p[1], p[2]));
+        let Some(first) = points.next() else {
+            return (glm::Vec3::zeros(), glm::Vec3::zeros());
+        };
+        points.fold((first, first), |(min, max), p| {
+            (glm::min2(&min, &p), glm::max2(&max, &p))
+        })
     }
 
     /// Checks that the mesh has same size positions, normals and uvs as well as
     /// proper stride and indices.
     pub fn check_consitency(&self) -> Result<(), &'static str> {
-        if self.positions.len() % 3 != 0 {
+        if !self.positions.len().is_multiple_of(3) {
             return Err("Positions length is not a multiple of 3 (X, Y, Z).");
         }
-        if self.normals.len() % 3 != 0 {
+        if !self.normals.len().is_multiple_of(3) {
             return Err("Normals length is not a multiple of 3 (dX, dY, dZ).");
         }
-        if self.normals.len() != 0 && self.normals.len() != self.positions.len() {
+        if !self.normals.is_empty() && self.normals.len() != self.positions.len() {
             return Err("Not as many normals as vertices.");
         }
-        if self.uvs.len() % 2 != 0 {
+        if !self.uvs.len().is_multiple_of(2) {
             return Err("UVs length is not a multiple of 2 (U, V).");
         }
-        if self.uvs.len() != 0 && self.uvs.len() != self.positions.len() / 3 * 2 {
+        if !self.uvs.is_empty() && self.uvs.len() != self.positions.len() / 3 * 2 {
             return Err("Not as many UVs as vertices.");
         }
-        if self.indices.len() % 3 != 0 {
+        if self.tangents.len() != self.bitangents.len() {
+            return Err("Not as many tangents as bitangents.");
+        }
+        if !self.tangents.is_empty() && self.tangents.len() != self.positions.len() {
+            return Err("Not as many tangents as vertices.");
+        }
+        if !self.indices.len().is_multiple_of(3) {
             return Err("Indices length is not a multiple of 3 (Vertex 1, 2, 3).");
         }
         if *self.indices.iter().max().ok_or("No indices.")? as usize >= (self.positions.len() / 3) {
@@ -121,7 +648,6 @@ impl Mesh {
     }
 
     /// A simple 2 by 2 quad on the XY plane.
-    #[allow(dead_code)]
     pub fn quad() -> Self {
         Mesh {
             positions: vec![
@@ -129,23 +655,34 @@ impl Mesh {
             ],
             normals: vec![0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0],
             uvs: vec![0.0, 0.0, 1.0, 0.0, 1.0, 1.0, 0.0, 1.0],
+            tangents: Vec::new(),
+            bitangents: Vec::new(),
             indices: vec![0, 1, 2, 0, 2, 3],
+            diffuse_texture: None,
         }
     }
 
-    /// Generates a mesh of `divisions` by `divisions` quads on the XY plane.
+    /// Generates a mesh of `num_quads` by `num_quads` quads on the XY plane.
     ///
     /// Is 1x1 large, from (0,0,0) to (1,1,0) and spans 0 to 1 in UV space.
     pub fn quad_mesh(num_quads: u32) -> Self {
+        Self::quad_mesh_rect(num_quads, num_quads)
+    }
+
+    /// Like [`Mesh::quad_mesh`], with `x_quads` along x and `y_quads` along y.
+    /// Still spans the unit square, so the quads are stretched unless they are
+    /// scaled to a matching rectangle.
+    pub fn quad_mesh_rect(x_quads: u32, y_quads: u32) -> Self {
         let mut positions = vec![];
         let mut normals = vec![];
         let mut uvs = vec![];
         let mut indices = vec![];
 
-        for y in 0..=num_quads {
-            for x in 0..=num_quads {
-                let u = x as f32 / num_quads as f32;
-                let v = y as f32 / num_quads as f32;
+        let row = x_quads + 1;
+        for y in 0..=y_quads {
+            for x in 0..=x_quads {
+                let u = x as f32 / x_quads as f32;
+                let v = y as f32 / y_quads as f32;
 
                 // Generate the vertex
                 positions.extend_from_slice(&[u, v, 0.0]);
@@ -153,15 +690,15 @@ impl Mesh {
                 uvs.extend_from_slice(&[u, v]);
 
                 // if not on the edge, generate two triangles aswell
-                if x < num_quads && y < num_quads {
-                    let this_i = x + y * (num_quads + 1);
+                if x < x_quads && y < y_quads {
+                    let this_i = x + y * row;
                     indices.extend_from_slice(&[
                         this_i,
                         this_i + 1,
-                        this_i + (num_quads + 1),
+                        this_i + row,
                         this_i + 1,
-                        this_i + (num_quads + 1) + 1,
-                        this_i + (num_quads + 1),
+                        this_i + row + 1,
+                        this_i + row,
                     ]);
                 }
             }
@@ -171,8 +708,70 @@ impl Mesh {
             positions,
             normals,
             uvs,
+            tangents: Vec::new(),
+            bitangents: Vec::new(),
             indices,
+            diffuse_texture: None,
+        }
+    }
+
+    /// Real geometry of a height map, with `resolution` by `resolution` quads
+    /// over the bounds `(x_min, x_max, y_min, y_max)` and the noise as z. The
+    /// UVs span 0 to 1 like [`Mesh::quad_mesh`].
+    ///
+    /// The normals come from the differences to the neighboring vertices, so
+    /// they match the faceted geometry rather than the exact noise.
+    pub fn from_heightmap(
+        noise: impl NoiseFn<f64, 2>,
+        (x_min, x_max, y_min, y_max): (f32, f32, f32, f32),
+        resolution: u32,
+    ) -> Self {
+        let mut mesh = Self::quad_mesh(resolution);
+        let size = glm::vec2(x_max - x_min, y_max - y_min);
+        for position in mesh.positions.chunks_exact_mut(3) {
+            position[0] = x_min + position[0] * size.x;
+            position[1] = y_min + position[1] * size.y;
+            position[2] = noise.get([position[0] as f64, position[1] as f64]) as f32;
+        }
+
+        let side = resolution as usize + 1;
+        let height = |x: usize, y: usize| mesh.positions[3 * (y * side + x) + 2];
+        let spacing = size / resolution as f32;
+        let mut normals = Vec::with_capacity(mesh.positions.len());
+        for y in 0..side {
+            for x in 0..side {
+                // Central differences, one sided at the edges
+                let (left, right) = (x.saturating_sub(1), (x + 1).min(side - 1));
+                let (down, up) = (y.saturating_sub(1), (y + 1).min(side - 1));
+                let dz_dx =
+                    (height(right, y) - height(left, y)) / ((right - left) as f32 * spacing.x);
+                let dz_dy = (height(x, up) - height(x, down)) / ((up - down) as f32 * spacing.y);
+                let normal = glm::normalize(&glm::vec3(-dz_dx, -dz_dy, 1.0));
+                normals.extend_from_slice(normal.as_slice());
+            }
         }
+        mesh.normals = normals;
+        mesh
+    }
+}
+
+impl VAO {
+    /// The attribute locations that are enabled in this VAO.
+    pub fn enabled_attributes(&self) -> Vec<u32> {
+        let mut max_attribs = 0;
+        unsafe {
+            gl::GetIntegerv(gl::MAX_VERTEX_ATTRIBS, &mut max_attribs);
+            gl::BindVertexArray(self.id);
+        }
+        (0..max_attribs as u32)
+            .filter(|&location| {
+                let mut enabled = 0;
+                unsafe {
+                    gl::GetVertexAttribiv(location, gl::VERTEX_ATTRIB_ARRAY_ENABLED, &mut enabled);
+                }
+                enabled != 0
+            })
+            .collect()
     }
 }
 
@@ -196,32 +795,66 @@ impl ElementMeshVAO {
         let position_vbo = load_attribute(&mesh.positions, POSITION_ATTRIB_PTR, 3);
         let normal_vbo = load_attribute(&mesh.normals, NORMAL_ATTRIB_PTR, 3);
         let uvs_vbo = load_attribute(&mesh.uvs, UV_ATTRIB_PTR, 2);
+        let mut vbos = vec![position_vbo, normal_vbo, uvs_vbo];
+        if !mesh.tangents.is_empty() {
+            vbos.push(load_attribute(&mesh.tangents, TANGENT_ATTRIB_PTR, 3));
+            vbos.push(load_attribute(&mesh.bitangents, BITANGENT_ATTRIB_PTR, 3));
+        }
 
+        // Half the index memory for the small meshes, which are instanced the most
+        let short_indices: Option<Vec<u16>> = (mesh.positions.len() / 3 <= u16::MAX as usize)
+            .then(|| mesh.indices.iter().map(|&index| index as u16).collect());
+        let (index_type, index_data, index_bytes) = match &short_indices {
+            Some(indices) => (
+                gl::UNSIGNED_SHORT,
+                indices.as_ptr() as *const _,
+                std::mem::size_of_val(indices.as_slice()),
+            ),
+            None => (
+                gl::UNSIGNED_INT,
+                mesh.indices.as_ptr() as *const _,
+                std::mem::size_of_val(mesh.indices.as_slice()),
+            ),
+        };
         let index_vbo = unsafe {
             let mut vbo = 0;
             gl::GenBuffers(1, &mut vbo);
             gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, vbo);
             gl::BufferData(
                 gl::ELEMENT_ARRAY_BUFFER,
-                (mesh.indices.len() * std::mem::size_of::<u32>()) as gl::types::GLsizeiptr,
-                mesh.indices.as_ptr() as *const _,
+                index_bytes as gl::types::GLsizeiptr,
+                index_data,
                 gl::STATIC_DRAW,
             );
             vbo
         };
 
+        vbos.push(index_vbo);
+
         get_gl_errors().expect("Generating the mesh buffer run into errors");
 
         ElementMeshVAO {
             index_count: mesh.indices.len(),
+            index_type,
+            bounding_box: mesh.bounding_box(),
             vao: VAO {
                 id: vao_id,
-                vbos: vec![position_vbo, normal_vbo, uvs_vbo, index_vbo],
+                vbos,
                 _marker: PhantomData,
             },
         }
     }
 
+    pub fn enabled_attributes(&self) -> Vec<u32> {
+        self.vao.enabled_attributes()
+    }
+
+    /// The minimum and maximum corner of the mesh in model space, kept from
+    /// when it was uploaded.
+    pub fn bounding_box(&self) -> (glm::Vec3, glm::Vec3) {
+        self.bounding_box
+    }
+
     pub fn render(&self) {
         // SAFETY: VAO id was created in the constructor, errors were checked,
         // and the object is on the same thread.
@@ -230,88 +863,203 @@ impl ElementMeshVAO {
             gl::DrawElements(
                 gl::TRIANGLES,
                 self.index_count as i32,
-                gl::UNSIGNED_INT,
+                self.index_type,
                 std::ptr::null(),
             );
         }
     }
-}
 
-impl InstancedMeshesVAO {
-    pub fn from_existing_with_models(mut single_vao: ElementMeshVAO, models: &[glm::Mat4]) -> Self {
-        // Matrices as attributes need a pointer for each column, ie 3 or 4 attribs.
-        unsafe fn set_vertex_attrib_pointer(attrib_ptr: u32, offset: usize, components: usize) {
-            let vec_size = components * std::mem::size_of::<f32>();
-            gl::EnableVertexAttribArray(attrib_ptr);
-            gl::VertexAttribPointer(
-                attrib_ptr,
-                components as i32,
-                gl::FLOAT,
-                gl::FALSE,
-                (components * vec_size) as gl::types::GLint,
-                (offset * vec_size) as *const _,
+    /// Draws the indices as patches of `vertices_per_patch` vertices each, for
+    /// shaders with a tessellation stage. Triangle meshes use 3.
+    pub fn render_patches(&self, vertices_per_patch: u32) {
+        let mut max_vertices = 0;
+        unsafe {
+            gl::GetIntegerv(gl::MAX_PATCH_VERTICES, &mut max_vertices);
+        }
+        assert!(
+            (1..=max_vertices as u32).contains(&vertices_per_patch),
+            "Patches must have between 1 and {max_vertices} vertices"
+        );
+        assert!(
+            self.index_count.is_multiple_of(vertices_per_patch as usize),
+            "The index count {} is not a multiple of the patch size {vertices_per_patch}",
+            self.index_count
+        );
+        unsafe {
+            gl::BindVertexArray(self.vao.id);
+            gl::PatchParameteri(gl::PATCH_VERTICES, vertices_per_patch as i32);
+            gl::DrawElements(
+                gl::PATCHES,
+                self.index_count as i32,
+                self.index_type,
+                std::ptr::null(),
             );
-            // Set as instance attribute
-            gl::VertexAttribDivisor(attrib_ptr, 1);
         }
+    }
 
-        // SAFETY: glm::Mat4 are represented as 16 densely packed floats
-        let model_mats_vbo = unsafe {
-            gl::BindVertexArray(single_vao.vao.id);
-
-            let mut vbo = 0;
-            gl::GenBuffers(1, &mut vbo);
-            gl::BindBuffer(gl::ARRAY_BUFFER, vbo);
-            gl::BufferData(
-                gl::ARRAY_BUFFER,
-                (models.len() * std::mem::size_of::<glm::Mat4>()) as gl::types::GLsizeiptr,
-                models.as_ptr() as *const _,
-                gl::STATIC_DRAW,
+    /// Draws the mesh `count` times, the shader tells the copies apart by
+    /// `gl_InstanceID`.
+    pub fn render_instanced(&self, count: usize) {
+        unsafe {
+            gl::BindVertexArray(self.vao.id);
+            gl::DrawElementsInstanced(
+                gl::TRIANGLES,
+                self.index_count as i32,
+                self.index_type,
+                std::ptr::null(),
+                count as i32,
             );
+        }
+    }
+}
 
-            set_vertex_attrib_pointer(MODEL_MAT_ATTRIB_PTR_1, 0, 4);
-            set_vertex_attrib_pointer(MODEL_MAT_ATTRIB_PTR_2, 1, 4);
-            set_vertex_attrib_pointer(MODEL_MAT_ATTRIB_PTR_3, 2, 4);
-            set_vertex_attrib_pointer(MODEL_MAT_ATTRIB_PTR_4, 3, 4);
+/// Free video memory in bytes, if the driver reports it with
+/// `GL_NVX_gpu_memory_info` or `GL_ATI_meminfo`.
+fn free_gpu_memory() -> Option<usize> {
+    // Not part of the core profile bindings, the values are from the extensions
+    const GPU_MEMORY_INFO_CURRENT_AVAILABLE_VIDMEM_NVX: gl::types::GLenum = 0x9049;
+    const VBO_FREE_MEMORY_ATI: gl::types::GLenum = 0x87FB;
 
-            vbo
-        };
+    let query = if has_extension("GL_NVX_gpu_memory_info") {
+        GPU_MEMORY_INFO_CURRENT_AVAILABLE_VIDMEM_NVX
+    } else if has_extension("GL_ATI_meminfo") {
+        VBO_FREE_MEMORY_ATI
+    } else {
+        return None;
+    };
+    // Both start with the free kilobytes, ATI adds three more values
+    let mut values = [0; 4];
+    unsafe { gl::GetIntegerv(query, values.as_mut_ptr()) };
+    Some(values[0].max(0) as usize * 1024)
+}
+
+impl InstancedMeshesVAO {
+    /// How many instances with one value of each of `attributes` still fit into
+    /// the free GPU memory and a single draw call. Callers can drop the surplus
+    /// up front, instead of having the upload fail.
+    pub fn max_instances(attributes: &[InstanceAttribute]) -> usize {
+        let bytes_per_instance: usize = attributes
+            .iter()
+            .map(|attribute| attribute.floats_per_instance() * std::mem::size_of::<f32>())
+            .sum();
+        free_gpu_memory()
+            .map_or(usize::MAX, |free| free / bytes_per_instance.max(1))
+            .min(i32::MAX as usize)
+    }
 
-        // Generate and load normal transformation matrices
-        let mut normal_mats: Vec<glm::Mat3> = Vec::with_capacity(models.len());
-        for model_mat in models {
-            let model_normal: glm::Mat3 =
-                glm::mat4_to_mat3(&glm::transpose(&glm::inverse(&model_mat)));
-            normal_mats.push(model_normal);
+    /// Uploads a model matrix and the matching normal matrix for every
+    /// instance, along with its [`InstanceAttribute::COLOR_JITTER`].
+    ///
+    /// Fails with a readable message if the instance count can't be drawn or the
+    /// instance buffers don't fit into GPU memory.
+    pub fn from_existing_with_models(
+        single_vao: ElementMeshVAO,
+        models: &[glm::Mat4],
+        color_jitter: &[glm::Vec3],
+    ) -> Result<Self, String> {
+        let normal_mats: Vec<glm::Mat3> = models.iter().map(normal_matrix).collect();
+
+        Self::from_existing_with_attributes(
+            single_vao,
+            models.len(),
+            &[
+                (InstanceAttribute::MODEL_MAT, mat_floats(models)),
+                (InstanceAttribute::NORMAL_MAT, mat_floats(&normal_mats)),
+                (InstanceAttribute::COLOR_JITTER, mat_floats(color_jitter)),
+            ],
+        )
+    }
+
+    /// Uploads each attribute into its own buffer. Every data slice must hold
+    /// [`InstanceAttribute::floats_per_instance`] values per instance.
+    ///
+    /// Fails with a readable message if the instance count can't be drawn or the
+    /// instance buffers don't fit into GPU memory.
+    pub fn from_existing_with_attributes(
+        mut single_vao: ElementMeshVAO,
+        instance_count: usize,
+        attributes: &[(InstanceAttribute, &[f32])],
+    ) -> Result<Self, String> {
+        // The draw call takes the instance count as a GLsizei
+        if instance_count > i32::MAX as usize {
+            return Err(format!(
+                "{} instances exceed the limit of {} per draw call",
+                instance_count,
+                i32::MAX
+            ));
+        }
+        for (attribute, data) in attributes {
+            let expected = instance_count * attribute.floats_per_instance();
+            if data.len() != expected {
+                return Err(format!(
+                    "Instance attribute {} has {} values instead of {expected}",
+                    attribute.name,
+                    data.len(),
+                ));
+            }
+        }
+        let bytes_per_instance: usize = attributes
+            .iter()
+            .map(|(attribute, _)| attribute.floats_per_instance() * std::mem::size_of::<f32>())
+            .sum();
+        let bytes = instance_count * bytes_per_instance;
+        // Drivers tend to accept too large buffers and fail later, if at all
+        if let Some(free) = free_gpu_memory().filter(|&free| bytes > free) {
+            return Err(format!(
+                "{} instances ({} MB of instance data) exceed the {} MB of free GPU memory",
+                instance_count,
+                bytes / 1_000_000,
+                free / 1_000_000
+            ));
         }
 
-        let normal_mats_vbo = unsafe {
-            gl::BindVertexArray(single_vao.vao.id);
+        clear_gl_errors();
 
-            let mut vbo = 0;
-            gl::GenBuffers(1, &mut vbo);
-            gl::BindBuffer(gl::ARRAY_BUFFER, vbo);
-            gl::BufferData(
-                gl::ARRAY_BUFFER,
-                (normal_mats.len() * std::mem::size_of::<glm::Mat3>()) as gl::types::GLsizeiptr,
-                normal_mats.as_ptr() as *const _,
-                gl::STATIC_DRAW,
-            );
+        let mut instance_buffers = Vec::with_capacity(attributes.len());
+        for &(attribute, data) in attributes {
+            let vbo = unsafe {
+                gl::BindVertexArray(single_vao.vao.id);
 
-            set_vertex_attrib_pointer(MODEL_NORMAL_ATTRIB_PTR_1, 0, 3);
-            set_vertex_attrib_pointer(MODEL_NORMAL_ATTRIB_PTR_2, 1, 3);
-            set_vertex_attrib_pointer(MODEL_NORMAL_ATTRIB_PTR_3, 2, 3);
+                let mut vbo = 0;
+                gl::GenBuffers(1, &mut vbo);
+                gl::BindBuffer(gl::ARRAY_BUFFER, vbo);
+                gl::BufferData(
+                    gl::ARRAY_BUFFER,
+                    std::mem::size_of_val(data) as gl::types::GLsizeiptr,
+                    data.as_ptr() as *const _,
+                    gl::STATIC_DRAW,
+                );
+                attribute.set_pointers(0);
 
-            vbo
-        };
-        single_vao.vao.vbos.push(model_mats_vbo);
-        single_vao.vao.vbos.push(normal_mats_vbo);
+                vbo
+            };
+            // Dropping the VAO on error also frees the buffers pushed here.
+            single_vao.vao.vbos.push(vbo);
+            instance_buffers.push((attribute, vbo));
+        }
 
-        Self {
+        if let Err(errors) = get_gl_errors() {
+            return Err(if errors.contains(&"GL_OUT_OF_MEMORY") {
+                format!(
+                    "{} instances ({} MB of instance data) exceed the GPU memory",
+                    instance_count,
+                    bytes / 1_000_000
+                )
+            } else {
+                format!(
+                    "Creating the instance buffers for {} instances run into errors: {:?}",
+                    instance_count, errors
+                )
+            });
+        }
+
+        Ok(Self {
             index_count_per_instance: single_vao.index_count,
-            instance_count: models.len(),
+            index_type: single_vao.index_type,
+            instance_count,
+            instance_buffers,
             vao: single_vao.vao,
-        }
+        })
     }
 
     pub fn render(&self) {
@@ -320,12 +1068,102 @@ impl InstancedMeshesVAO {
             gl::DrawElementsInstanced(
                 gl::TRIANGLES,
                 self.index_count_per_instance as i32,
-                gl::UNSIGNED_INT,
+                self.index_type,
                 std::ptr::null(),
                 self.instance_count as i32,
             );
         }
     }
+
+    pub fn enabled_attributes(&self) -> Vec<u32> {
+        self.vao.enabled_attributes()
+    }
+
+    pub fn instance_count(&self) -> usize {
+        self.instance_count
+    }
+
+    /// Draws only the instances `first_instance..first_instance + count`.
+    ///
+    /// This uses `glDrawElementsInstancedBaseInstance`, which requires OpenGL 4.2.
+    /// On older contexts (like the 4.1 one on macOS) the instance attributes are
+    /// temporarily pointed at the first instance instead, which is slower but
+    /// gives the same result.
+    pub fn render_range(&self, first_instance: usize, count: usize) {
+        assert!(
+            first_instance + count <= self.instance_count,
+            "Instance range out of bounds"
+        );
+        unsafe {
+            gl::BindVertexArray(self.vao.id);
+            if supports_base_instance() {
+                gl::DrawElementsInstancedBaseInstance(
+                    gl::TRIANGLES,
+                    self.index_count_per_instance as i32,
+                    self.index_type,
+                    std::ptr::null(),
+                    count as i32,
+                    first_instance as u32,
+                );
+            } else {
+                self.offset_instance_attributes(first_instance);
+                gl::DrawElementsInstanced(
+                    gl::TRIANGLES,
+                    self.index_count_per_instance as i32,
+                    self.index_type,
+                    std::ptr::null(),
+                    count as i32,
+                );
+                self.offset_instance_attributes(0);
+            }
+        }
+    }
+
+    /// Makes the first drawn instance read the attributes of `first_instance`.
+    /// The VAO must be bound.
+    unsafe fn offset_instance_attributes(&self, first_instance: usize) {
+        for (attribute, vbo) in &self.instance_buffers {
+            gl::BindBuffer(gl::ARRAY_BUFFER, *vbo);
+            attribute.set_pointers(first_instance / attribute.divisor.max(1) as usize);
+        }
+    }
+}
+
+/// The inverse transpose of the upper 3x3 part, which keeps normals
+/// perpendicular to the surface under non-uniform scaling. The result still
+/// needs to be normalized after transforming.
+///
+/// A degenerate model, e.g. one flattened to zero height, keeps its normals.
+pub fn normal_matrix(model: &glm::Mat4) -> glm::Mat3 {
+    glm::mat4_to_mat3(model)
+        .try_inverse()
+        .map(|inverse| inverse.transpose())
+        .unwrap_or_else(glm::Mat3::identity)
+}
+
+/// The floats of densely packed glm matrices, column by column.
+pub fn mat_floats<const R: usize, const C: usize>(mats: &[glm::TMat<f32, R, C>]) -> &[f32] {
+    // SAFETY: glm matrices are represented as R * C densely packed floats
+    unsafe { std::slice::from_raw_parts(mats.as_ptr() as *const f32, mats.len() * R * C) }
+}
+
+/// Whether the current context is at least OpenGL 4.2. Only queried once.
+fn supports_base_instance() -> bool {
+    thread_local! {
+        static SUPPORTED: Cell<Option<bool>> = const { Cell::new(None) };
+    }
+    SUPPORTED.with(|supported| {
+        supported.get().unwrap_or_else(|| {
+            let (mut major, mut minor) = (0, 0);
+            unsafe {
+                gl::GetIntegerv(gl::MAJOR_VERSION, &mut major);
+                gl::GetIntegerv(gl::MINOR_VERSION, &mut minor);
+            }
+            let result = (major, minor) >= (4, 2);
+            supported.set(Some(result));
+            result
+        })
+    })
 }
 
 impl Drop for VAO {
@@ -344,7 +1182,7 @@ fn load_attribute(data: &[f32], attrib_ptr: u32, components: usize) -> GLuint {
         gl::BindBuffer(gl::ARRAY_BUFFER, vbo);
         gl::BufferData(
             gl::ARRAY_BUFFER,
-            (data.len() * std::mem::size_of::<f32>()) as gl::types::GLsizeiptr,
+            std::mem::size_of_val(data) as gl::types::GLsizeiptr,
             data.as_ptr() as *const _,
             gl::STATIC_DRAW,
         );
@@ -0,0 +1,78 @@
+//! Painting into the base map, to sculpt the terrain and place trees by hand.
+
+use nalgebra_glm as glm;
+
+/// The channels of the base map, see [`crate::assets::Assets::base_map`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BaseMapChannel {
+    Height = 0,
+    Bushiness = 1,
+    Trees = 2,
+}
+
+/// A round brush with a soft edge.
+#[derive(Clone, Copy, Debug)]
+pub struct Brush {
+    pub channel: BaseMapChannel,
+    /// In meters.
+    pub radius: f32,
+    /// Change in the center per stroke, where 1 is the full range of the channel.
+    pub strength: f32,
+}
+
+impl Default for Brush {
+    fn default() -> Self {
+        Brush {
+            channel: BaseMapChannel::Height,
+            radius: 1.0,
+            strength: 0.05,
+        }
+    }
+}
+
+impl Brush {
+    /// Adds to the channel around `center` in world coordinates, or removes
+    /// from it when `erase` is set. The base map covers a scene of `scene_size`
+    /// meters.
+    pub fn paint(
+        &self,
+        base_map: &mut image::RgbaImage,
+        scene_size: f32,
+        center: glm::Vec2,
+        erase: bool,
+    ) {
+        let (width, height) = base_map.dimensions();
+        // The inverse of the lookup in `ImageNoiseFnWrapper`, which has
+        // the rows along x and the columns along y.
+        let row_size = scene_size / width as f32;
+        let column_size = scene_size / height as f32;
+        let sign = if erase { -1.0 } else { 1.0 };
+
+        let rows = pixel_range(center.x, self.radius, row_size, height);
+        let columns = pixel_range(center.y, self.radius, column_size, width);
+        for row in rows {
+            for column in columns.clone() {
+                let world = glm::vec2(
+                    (row as f32 + 0.5) * row_size,
+                    (column as f32 + 0.5) * column_size,
+                );
+                let distance = glm::distance(&world, &center) / self.radius;
+                if distance >= 1.0 {
+                    continue;
+                }
+                let falloff = (1.0 - distance * distance).powi(2);
+
+                let value = &mut base_map.get_pixel_mut(column, row)[self.channel as usize];
+                let change = sign * self.strength * falloff * 255.0;
+                *value = (*value as f32 + change).round().clamp(0.0, 255.0) as u8;
+            }
+        }
+    }
+}
+
+/// The pixels along one axis that the brush might touch.
+fn pixel_range(center: f32, radius: f32, pixel_size: f32, count: u32) -> std::ops::Range<u32> {
+    let first = ((center - radius) / pixel_size).floor().max(0.0) as u32;
+    let last = ((center + radius) / pixel_size).ceil().max(0.0) as u32;
+    first.min(count)..last.min(count)
+}
@@ -0,0 +1,88 @@
+//! Seeded color grading, so that each scene gets its own mood.
+
+use nalgebra_glm as glm;
+use rand::Rng;
+
+use crate::seed::Seed;
+
+/// The colors of a scene, derived from a few shared hue rotations so they stay
+/// coherent with each other.
+#[derive(Clone, Debug)]
+pub struct Palette {
+    /// Clear color behind everything, and the sky at the horizon.
+    pub sky: glm::Vec3,
+    /// The sky straight up, see [`crate::texture::CubeTexture::from_gradient`].
+    pub zenith: glm::Vec3,
+    /// Color of the direct sunlight.
+    pub sun: glm::Vec3,
+    /// Color of the indirect light from the sky and canopy.
+    pub ambient: glm::Vec3,
+    /// Color distant things fade towards, the sky at the horizon so that the
+    /// far clip boundary blends into it.
+    pub fog: glm::Vec3,
+}
+
+impl Palette {
+    /// Slight variations around the default sky blue with a warm sun. The same
+    /// scene seed always gives the same palette.
+    ///
+    /// The colors are picked as sRGB and returned linear, since the window
+    /// encodes them again, see [`crate::renderer::Renderer::with_quality`].
+    pub fn from_seed(seed: u32) -> Self {
+        let mut rng = Seed::from(seed).derive("palette").rng();
+
+        // One rotation shared by all colors keeps them harmonious
+        let hue_shift: f32 = rng.gen_range(-25.0..25.0);
+        // Golden hour or overcast
+        let warmth: f32 = rng.gen_range(0.0..1.0);
+        let haze: f32 = rng.gen_range(0.0..1.0);
+
+        let sky = hsv_to_rgb(184.0 + hue_shift, 0.1 + 0.12 * haze, 0.8 + 0.1 * warmth);
+        // Deeper blue overhead, hazier days wash it out
+        let zenith = hsv_to_rgb(205.0 + hue_shift, 0.45 - 0.2 * haze, 0.7 + 0.1 * warmth);
+        let sun = hsv_to_rgb(40.0 + 0.5 * hue_shift, 0.2 * warmth, 1.0);
+        // Complementary to the sun, slightly cooler
+        let ambient = hsv_to_rgb(200.0 + hue_shift, 0.15 * (1.0 - warmth), 0.95);
+        let fog = sky;
+
+        Palette {
+            sky: srgb_to_linear(&sky),
+            zenith: srgb_to_linear(&zenith),
+            sun: srgb_to_linear(&sun),
+            ambient: srgb_to_linear(&ambient),
+            fog: srgb_to_linear(&fog),
+        }
+    }
+}
+
+/// Hue in degrees, saturation and value in [0, 1].
+pub fn hsv_to_rgb(hue: f32, saturation: f32, value: f32) -> glm::Vec3 {
+    let hue = hue.rem_euclid(360.0) / 60.0;
+    let chroma = value * saturation;
+    let x = chroma * (1.0 - (hue % 2.0 - 1.0).abs());
+    let (r, g, b) = match hue as u32 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let m = value - chroma;
+    glm::vec3(r + m, g + m, b + m)
+}
+
+/// Decodes an sRGB encoded color to linear values, like the GPU does when
+/// sampling sRGB textures.
+pub fn srgb_to_linear(color: &glm::Vec3) -> glm::Vec3 {
+    color.map(srgb_channel_to_linear)
+}
+
+/// A single channel of [`srgb_to_linear`], in [0, 1].
+pub fn srgb_channel_to_linear(encoded: f32) -> f32 {
+    if encoded <= 0.04045 {
+        encoded / 12.92
+    } else {
+        ((encoded + 0.055) / 1.055).powf(2.4)
+    }
+}
@@ -0,0 +1,135 @@
+//! Stitching six cube faces rendered from one point into an equirectangular
+//! panorama.
+//!
+//! Each face is rendered with a one texel border beyond its 90° field of view,
+//! so that the bilinear lookup at a face edge still has neighbors on both
+//! sides. That way the seams between the faces don't show.
+
+use nalgebra_glm as glm;
+
+/// A face of the cube, seen from its center.
+pub(crate) struct CubeFace {
+    pub view_proj: glm::Mat4,
+    forward: glm::Vec3,
+}
+
+/// Side length in texels of the rendered faces, including the border, for a
+/// panorama `resolution` pixels high.
+pub(crate) fn face_size(resolution: u32) -> u32 {
+    // A face spans a quarter of the panorama width, twice its height
+    (resolution / 2).max(1) + 2
+}
+
+/// The six faces around `position`, for rendering into square targets of
+/// [`face_size`] texels.
+pub(crate) fn cube_faces(position: glm::Vec3, face_size: u32) -> Vec<CubeFace> {
+    let inner = (face_size - 2) as f32;
+    // Widened so the inner texels span exactly 90°
+    let fov = 2.0 * (face_size as f32 / inner).atan();
+    let projection = glm::perspective(1.0, fov, 0.1, 50.0);
+
+    let (x, y, z) = (glm::Vec3::x(), glm::Vec3::y(), glm::Vec3::z());
+    [(x, z), (-x, z), (y, z), (-y, z), (z, -x), (-z, x)]
+        .into_iter()
+        .map(|(forward, up)| CubeFace {
+            view_proj: projection * glm::look_at(&position, &(position + forward), &up),
+            forward,
+        })
+        .collect()
+}
+
+/// Samples the faces, given in the order of [`cube_faces`] as read back with
+/// the top row first, into a panorama twice as wide as high.
+///
+/// The center column looks along +x, the left and right edges along -x.
+pub(crate) fn stitch(
+    faces: &[CubeFace],
+    images: &[image::RgbaImage],
+    resolution: u32,
+) -> image::RgbaImage {
+    let (width, height) = (2 * resolution, resolution);
+    image::RgbaImage::from_fn(width, height, |column, row| {
+        let longitude = ((column as f32 + 0.5) / width as f32 - 0.5) * std::f32::consts::TAU;
+        let latitude = (0.5 - (row as f32 + 0.5) / height as f32) * std::f32::consts::PI;
+        // Turning right in the image turns clockwise seen from above
+        let direction = glm::vec3(
+            latitude.cos() * longitude.cos(),
+            -latitude.cos() * longitude.sin(),
+            latitude.sin(),
+        );
+
+        // The face the direction points into most directly
+        let (index, face) = faces
+            .iter()
+            .enumerate()
+            .max_by(|(_, a), (_, b)| {
+                glm::dot(&a.forward, &direction).total_cmp(&glm::dot(&b.forward, &direction))
+            })
+            .expect("There are six faces");
+        // Only the direction matters, so the view is applied without translation
+        let clip = face.view_proj * glm::vec4(direction.x, direction.y, direction.z, 0.0);
+        let ndc = clip.xy() / clip.w;
+        sample_bilinear(&images[index], ndc)
+    })
+}
+
+/// The inverse of [`stitch`], resamples a panorama into the six faces of a
+/// cube map with `face_size` texels, in the order and orientation OpenGL
+/// expects them.
+pub(crate) fn cube_map_faces(panorama: &image::RgbaImage, face_size: u32) -> Vec<image::RgbaImage> {
+    (0..6)
+        .map(|face| {
+            image::RgbaImage::from_fn(face_size, face_size, |column, row| {
+                let direction = cube_map_direction(face, face_size, column, row);
+
+                let longitude = (-direction.y).atan2(direction.x);
+                let latitude = direction.z.clamp(-1.0, 1.0).asin();
+                // The panorama spans the longitudes and latitudes like the screen spans NDC
+                let ndc = glm::vec2(
+                    longitude / std::f32::consts::PI,
+                    2.0 * latitude / std::f32::consts::PI,
+                );
+                sample_bilinear(panorama, ndc)
+            })
+        })
+        .collect()
+}
+
+/// The normalized direction through the texel in `column` and `row` of a cube
+/// map face, numbered in the OpenGL order.
+pub(crate) fn cube_map_direction(face: usize, face_size: u32, column: u32, row: u32) -> glm::Vec3 {
+    let s = 2.0 * (column as f32 + 0.5) / face_size as f32 - 1.0;
+    let t = 2.0 * (row as f32 + 0.5) / face_size as f32 - 1.0;
+    // From the cube map section of the OpenGL specification
+    match face {
+        0 => glm::vec3(1.0, -t, -s),
+        1 => glm::vec3(-1.0, -t, s),
+        2 => glm::vec3(s, 1.0, t),
+        3 => glm::vec3(s, -1.0, -t),
+        4 => glm::vec3(s, -t, 1.0),
+        _ => glm::vec3(-s, -t, -1.0),
+    }
+    .normalize()
+}
+
+/// Looks up normalized device coordinates in an image with the top row first.
+fn sample_bilinear(image: &image::RgbaImage, ndc: glm::Vec2) -> image::Rgba<u8> {
+    let (width, height) = image.dimensions();
+    let x = (ndc.x + 1.0) / 2.0 * width as f32 - 0.5;
+    let y = (1.0 - ndc.y) / 2.0 * height as f32 - 0.5;
+    let x = x.clamp(0.0, (width - 1) as f32);
+    let y = y.clamp(0.0, (height - 1) as f32);
+
+    let (x0, y0) = (x.floor() as u32, y.floor() as u32);
+    let (x1, y1) = ((x0 + 1).min(width - 1), (y0 + 1).min(height - 1));
+    let (fx, fy) = (x.fract(), y.fract());
+
+    let mut result = [0u8; 4];
+    for (channel, value) in result.iter_mut().enumerate() {
+        let texel = |x, y| image.get_pixel(x, y)[channel] as f32;
+        let top = texel(x0, y0) * (1.0 - fx) + texel(x1, y0) * fx;
+        let bottom = texel(x0, y1) * (1.0 - fx) + texel(x1, y1) * fx;
+        *value = (top * (1.0 - fy) + bottom * fy).round() as u8;
+    }
+    image::Rgba(result)
+}
@@ -0,0 +1,79 @@
+//! Presets trading image quality for speed, from weak laptops to screenshots.
+
+use serde::{Deserialize, Serialize};
+
+/// The knobs a [`QualityPreset`] sets together.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct QualitySettings {
+    /// Quads per side of the terrain mesh, which the height map displaces.
+    pub terrain_tessellation: u32,
+    /// Texels per side of the generated terrain maps, for a scene of
+    /// [`crate::scene::SCENE_SIZE`]. Larger scenes get proportionally more.
+    pub noise_texture_resolution: u32,
+    /// Factor on the density of all foliage.
+    pub foliage_density_scale: f32,
+    /// Soft contact shadows below shrubs and trees.
+    pub shadows: bool,
+    /// Post processing, i.e. the bloom pass. Without it the bloom settings are
+    /// ignored.
+    pub post_processing: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum QualityPreset {
+    Low,
+    Medium,
+    /// What the scenes were designed with.
+    #[default]
+    High,
+    /// For screenshots rather than real time.
+    Ultra,
+    Custom(QualitySettings),
+}
+
+impl QualityPreset {
+    pub fn settings(self) -> QualitySettings {
+        match self {
+            QualityPreset::Low => QualitySettings {
+                terrain_tessellation: 64,
+                noise_texture_resolution: 128,
+                foliage_density_scale: 0.4,
+                shadows: false,
+                post_processing: false,
+            },
+            QualityPreset::Medium => QualitySettings {
+                terrain_tessellation: 128,
+                noise_texture_resolution: 256,
+                foliage_density_scale: 0.7,
+                shadows: true,
+                post_processing: false,
+            },
+            QualityPreset::High => QualitySettings {
+                terrain_tessellation: 256,
+                noise_texture_resolution: 256,
+                foliage_density_scale: 1.0,
+                shadows: true,
+                post_processing: true,
+            },
+            QualityPreset::Ultra => QualitySettings {
+                terrain_tessellation: 512,
+                noise_texture_resolution: 512,
+                foliage_density_scale: 1.5,
+                shadows: true,
+                post_processing: true,
+            },
+            QualityPreset::Custom(settings) => settings,
+        }
+    }
+
+    /// The next higher preset, wrapping around from [`QualityPreset::Ultra`]
+    /// to [`QualityPreset::Low`]. Custom settings continue with the lowest.
+    pub fn next(self) -> Self {
+        match self {
+            QualityPreset::Low => QualityPreset::Medium,
+            QualityPreset::Medium => QualityPreset::High,
+            QualityPreset::High => QualityPreset::Ultra,
+            QualityPreset::Ultra | QualityPreset::Custom(_) => QualityPreset::Low,
+        }
+    }
+}
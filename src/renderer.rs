@@ -1,24 +1,421 @@
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::error::Error;
 use std::ffi::{CStr, CString};
+use std::path::Path;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 
 use glutin::display::GlDisplay;
 use nalgebra_glm as glm;
+use serde::{Deserialize, Serialize};
 
 use crate::assets::Assets;
-use crate::scene::Scene;
+use crate::bloom::Bloom;
+use crate::camera::{Camera, CameraMode, FreeCamera, OrbitCamera};
+use crate::favorites::Favorites;
+use crate::foliage::{FoliageModel, ShrubEntities};
+use crate::framebuffer::{read_window_pixels, Framebuffer, MultisampleFramebuffer};
+use crate::generation::SpatialGrid;
+use crate::lighting::{DirectionalLight, MAX_LIGHTS};
+use crate::mesh::{ElementMeshVAO, InstancedMeshesVAO, Mesh};
+use crate::paint::Brush;
+use crate::palette::Palette;
+use crate::panorama;
+use crate::quality::QualityPreset;
+use crate::scene::{Scene, SceneVariation, SCENE_SIZE};
+use crate::shader::{Shader, ShaderType};
+use crate::sky::Sky;
+use crate::state::RendererState;
+use crate::texture::{CubeTexture, Texture};
+
+/// Depth buffer bits to ask for when creating a context. Less than 24 bits make
+/// the terrain layers fight in the distance.
+pub const DEFAULT_DEPTH_BITS: u8 = 24;
+
+/// How close in meters the free camera gets to the ground below it.
+const MIN_CAMERA_CLEARANCE: f32 = 0.3;
+
+/// The scene shown first, unless another seed is asked for.
+pub const DEFAULT_SEED: u32 = 13;
+
+/// How far the base plane lies below the lowest point of the terrain, in meters.
+const BASE_PLANE_DEPTH: f32 = 0.05;
+
+/// Everything the entities need to know about the current frame.
+pub struct RenderContext<'a> {
+    pub view_proj_mat: glm::Mat4,
+    /// Position of the camera.
+    pub eye: glm::Vec3,
+    pub palette: &'a Palette,
+    pub lights: &'a [DirectionalLight],
+    /// Surroundings reflected by wet surfaces, if any.
+    pub environment: Option<&'a CubeTexture>,
+    /// Replaces the shaded color of all entities.
+    pub debug_view: DebugView,
+    /// For [`DebugView::Depth`], see [`depth_plane`].
+    pub depth_plane: glm::Vec4,
+    /// Of `view_proj_mat`, to skip what is out of view.
+    pub frustum: Frustum,
+    /// Of the fog towards [`Palette::fog`], see [`Scene::fog_density`].
+    pub fog_density: f32,
+    /// Seconds since the scene was created, for animations.
+    pub time: f32,
+    /// Counters the entities add their draw calls to.
+    pub stats: &'a Cell<FrameStats>,
+    /// Set for the second pass over the transparent entities, which only
+    /// blends their faded edges. Alpha tested otherwise, see [`render_entities`].
+    pub soft_edges: bool,
+}
+
+impl RenderContext<'_> {
+    /// Sets `fog_color` and `fog_density` of the active shader.
+    ///
+    /// # Safety
+    /// The shader has to be active and declare these uniforms.
+    pub unsafe fn set_fog_uniforms(&self, shader: &Shader) {
+        gl::Uniform3fv(
+            shader.get_uniform_location("fog_color"),
+            1,
+            self.palette.fog.as_ptr(),
+        );
+        gl::Uniform1f(shader.get_uniform_location("fog_density"), self.fog_density);
+    }
+
+    /// Sets `debug_view` and `depth_plane` of the active shader.
+    ///
+    /// # Safety
+    /// The shader has to be active and declare these uniforms.
+    pub unsafe fn set_debug_uniforms(&self, shader: &Shader) {
+        self.debug_view.set_uniform(shader);
+        gl::Uniform4fv(
+            shader.get_uniform_location("depth_plane"),
+            1,
+            self.depth_plane.as_ptr(),
+        );
+    }
+
+    pub fn record_stats(&self, record: impl FnOnce(&mut FrameStats)) {
+        let mut stats = self.stats.get();
+        record(&mut stats);
+        self.stats.set(stats);
+    }
+}
+
+/// Instance counts of a single frame, to judge whether culling and level of
+/// detail pay off.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FrameStats {
+    /// Instances sent to the GPU in draw calls.
+    pub instances_submitted: usize,
+    /// Instances skipped, because their chunk was outside of the view frustum.
+    pub instances_culled: usize,
+    /// Submitted instances in chunks far enough to be thinned, see
+    /// [`crate::foliage::DensityLod`].
+    pub instances_far_lod: usize,
+    /// Instances left out, because they were thinned with the distance.
+    pub instances_thinned: usize,
+}
+
+impl std::fmt::Display for FrameStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} instances drawn, {} of them in far LOD, {} culled, {} thinned",
+            self.instances_submitted,
+            self.instances_far_lod,
+            self.instances_culled,
+            self.instances_thinned
+        )
+    }
+}
+
+/// Orientation of the front faces of triangles, as seen from the camera.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Winding {
+    Clockwise,
+    /// The OpenGL default.
+    CounterClockwise,
+}
+
+impl From<Winding> for gl::types::GLenum {
+    fn from(value: Winding) -> Self {
+        match value {
+            Winding::Clockwise => gl::CW,
+            Winding::CounterClockwise => gl::CCW,
+        }
+    }
+}
+
+/// The plane giving the depth of a world position along the view axis of
+/// `view_proj`, as `dot(plane, (position, 1))`. 0 at the near and 1 at the far
+/// clip plane, for any projection with the view axis through the center.
+pub fn depth_plane(view_proj: &glm::Mat4) -> glm::Vec4 {
+    let inverse = glm::inverse(view_proj);
+    let unproject = |depth: f32| {
+        let point = inverse * glm::vec4(0.0, 0.0, depth, 1.0);
+        point.xyz() / point.w
+    };
+    let near = unproject(-1.0);
+    let axis = unproject(1.0) - near;
+    let normal = axis / axis.norm_squared();
+    glm::vec4(normal.x, normal.y, normal.z, -normal.dot(&near))
+}
+
+/// The six clip planes of a view projection, facing inwards.
+#[derive(Clone, Copy, Debug)]
+pub struct Frustum {
+    planes: [glm::Vec4; 6],
+}
+
+impl Frustum {
+    /// Extracts the planes from the rows of the matrix, after Gribb and
+    /// Hartmann.
+    pub fn from_view_proj(view_proj: &glm::Mat4) -> Self {
+        let row = |i: usize| view_proj.row(i).transpose();
+        let planes = [
+            row(3) + row(0),
+            row(3) - row(0),
+            row(3) + row(1),
+            row(3) - row(1),
+            row(3) + row(2),
+            row(3) - row(2),
+        ];
+        Frustum { planes }
+    }
+
+    /// Whether any of the box can be in view. Conservative, boxes near the
+    /// corners of the frustum may pass without being visible.
+    pub fn intersects_box(&self, min: &glm::Vec3, max: &glm::Vec3) -> bool {
+        self.planes.iter().all(|plane| {
+            // The corner furthest along the plane normal
+            let corner = glm::vec3(
+                if plane.x >= 0.0 { max.x } else { min.x },
+                if plane.y >= 0.0 { max.y } else { min.y },
+                if plane.z >= 0.0 { max.z } else { min.z },
+            );
+            plane.xyz().dot(&corner) + plane.w >= 0.0
+        })
+    }
+}
+
+/// How the camera space is projected onto the screen.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ProjectionMode {
+    /// Vertical field of view in degrees.
+    Perspective { fov_y: f32 },
+    /// Parallel projection showing `height` meters vertically, centered on
+    /// the view direction. The width follows from the aspect ratio.
+    Ortho { height: f32 },
+}
+
+impl ProjectionMode {
+    const NEAR: f32 = 0.1; // 10 cm
+    pub(crate) const FAR: f32 = 50.0; // 50 m
+
+    pub fn matrix(&self, aspect_ratio: f32) -> glm::Mat4 {
+        match *self {
+            ProjectionMode::Perspective { fov_y } => {
+                glm::perspective(aspect_ratio, fov_y.to_radians(), Self::NEAR, Self::FAR)
+            }
+            ProjectionMode::Ortho { height } => {
+                let (left, right, bottom, top) = Self::ortho_bounds(height, aspect_ratio);
+                glm::ortho(left, right, bottom, top, Self::NEAR, Self::FAR)
+            }
+        }
+    }
+
+    /// The `(left, right, bottom, top)` edges of the view in meters, relative to
+    /// the view direction. Use a `height` of [`crate::scene::SCENE_SIZE`] to frame
+    /// the whole scene from above on a landscape screen.
+    pub fn ortho_bounds(height: f32, aspect_ratio: f32) -> (f32, f32, f32, f32) {
+        let half_height = height / 2.0;
+        let half_width = half_height * aspect_ratio;
+        (-half_width, half_width, -half_height, half_height)
+    }
+}
+
+impl Default for ProjectionMode {
+    fn default() -> Self {
+        ProjectionMode::Perspective { fov_y: 65.0 }
+    }
+}
+
+/// Intermediate values drawn instead of the shaded colors, to track down
+/// lighting and material bugs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DebugView {
+    #[default]
+    Off = 0,
+    /// World space normals, mapped from `[-1, 1]` to RGB.
+    Normals = 1,
+    /// Depth along the view axis as grayscale, black at the near and white
+    /// at the far plane, in perspective and orthographic projections alike.
+    Depth = 2,
+    /// Texture coordinates as red and green, repeating every unit. The terrain
+    /// shows the coordinates of its top down projection instead.
+    Uv = 3,
+}
+
+impl DebugView {
+    /// The view after this one, wrapping around to [`DebugView::Off`].
+    pub fn next(self) -> Self {
+        match self {
+            DebugView::Off => DebugView::Normals,
+            DebugView::Normals => DebugView::Depth,
+            DebugView::Depth => DebugView::Uv,
+            DebugView::Uv => DebugView::Off,
+        }
+    }
+
+    /// Sets `debug_view` of the active shader.
+    ///
+    /// # Safety
+    /// The shader has to be active and declare the uniform.
+    pub unsafe fn set_uniform(self, shader: &Shader) {
+        gl::Uniform1i(shader.get_uniform_location("debug_view"), self as i32);
+    }
+}
 
 pub trait Renderable {
-    fn render(&self, view_proj_mat: &glm::Mat4);
+    fn render(&self, ctx: &RenderContext);
+
+    /// Drawn after all entities were rendered, to blend onto them.
+    fn render_decals(&self, _ctx: &RenderContext) {}
+
+    /// Whether this is a terrain layer, as opposed to objects placed on it.
+    fn is_terrain(&self) -> bool {
+        false
+    }
+
+    /// Transparent entities are drawn twice, see [`render_entities`]. The
+    /// faded edges of the second pass don't write depth, so they should sort
+    /// their own parts back to front as well.
+    fn is_transparent(&self) -> bool {
+        false
+    }
+
+    /// Representative point for sorting by the distance to the camera.
+    fn center(&self) -> glm::Vec3 {
+        glm::vec3(SCENE_SIZE / 2.0, SCENE_SIZE / 2.0, 0.0)
+    }
+}
+
+/// Draws the `entities` into the bound framebuffer, the transparent ones in
+/// two passes. First everything is drawn alpha tested, so the opaque cores of
+/// leaves write depth and hide what lies behind them in any order. Then the
+/// decals, if enabled. Last the faded edges of the transparent entities are
+/// blended on top, back to front, without writing depth.
+pub fn render_entities(ctx: &RenderContext, entities: &[&dyn Renderable], decals: bool) {
+    for entity in entities {
+        entity.render(ctx);
+    }
+    // Decals darken the ground, so they come after the opaque geometry
+    if decals {
+        for entity in entities {
+            entity.render_decals(ctx);
+        }
+    }
+
+    let mut transparent: Vec<_> = entities
+        .iter()
+        .filter(|entity| entity.is_transparent())
+        .collect();
+    // Terrain layers lie below everything placed on them, so they go first
+    transparent.sort_by(|a, b| {
+        let distance_a = glm::distance2(&a.center(), &ctx.eye);
+        let distance_b = glm::distance2(&b.center(), &ctx.eye);
+        b.is_terrain()
+            .cmp(&a.is_terrain())
+            .then(distance_b.total_cmp(&distance_a))
+    });
+    let edges = RenderContext {
+        soft_edges: true,
+        ..*ctx
+    };
+    unsafe {
+        gl::Enable(gl::BLEND);
+        // Keep the alpha of the opaque pass, the window might be transparent
+        gl::BlendFuncSeparate(gl::SRC_ALPHA, gl::ONE_MINUS_SRC_ALPHA, gl::ZERO, gl::ONE);
+        // The edges are thin, their order matters less than the cores behind them
+        gl::DepthMask(gl::FALSE);
+    }
+    for entity in transparent {
+        entity.render(&edges);
+    }
+    unsafe {
+        gl::DepthMask(gl::TRUE);
+        gl::Disable(gl::BLEND);
+    }
 }
 
 pub struct Renderer {
+    width: i32,
+    height: i32,
     aspect_ratio: f32,
     assets: Assets,
     scene: Scene,
+    /// The seed of the current scene.
     seed: u32,
+    /// The scene to create before the next frame. Requests in between replace
+    /// each other, so scrubbing through seeds only builds the last one.
+    pending_seed: Option<u32>,
+    variation: SceneVariation,
+    /// Scales the density of all foliage in the following scenes.
+    density_multiplier: f32,
+    bloom: Bloom,
+    /// The scene is rendered into this instead of the window when post-processing
+    /// is enabled. Created on demand.
+    hdr_target: Option<Framebuffer>,
+    /// MSAA samples of the window, which the offscreen rendering should match.
+    samples: i32,
+    /// Depth precision of the window, which the offscreen targets match as well.
+    depth_bits: u8,
+    /// Rendered into before being resolved into `hdr_target`, if the window
+    /// is multisampled.
+    msaa_target: Option<MultisampleFramebuffer>,
+    /// Only draw the terrain, to inspect its silhouette against the sky.
+    terrain_only: bool,
+    /// Give every scene a base plane below the terrain.
+    base_plane: bool,
+    /// Front face winding while back faces are culled, see
+    /// [`Renderer::set_backface_culling`].
+    backface_culling: Option<Winding>,
+    /// Clear to transparent black instead of the sky, see
+    /// [`Renderer::set_transparent_background`].
+    transparent_background: bool,
+    /// Overrides the default lights of the scene palette.
+    lights: Option<Vec<DirectionalLight>>,
+    /// Drawn as the sky and reflected by wet surfaces, see
+    /// [`Renderer::set_environment`].
+    environment: Option<CubeTexture>,
+    /// Kept across scenes, see [`Renderer::set_sky_image`].
+    sky_image: Option<Rc<Texture>>,
+    sky: Sky,
+    projection: ProjectionMode,
+    camera: Camera,
+    camera_mode: CameraMode,
+    /// Direction the free camera moves in, see [`Renderer::set_movement`].
+    movement: glm::Vec3,
+    /// When the last frame was drawn, to move the camera by the time passed.
+    last_frame: Option<Instant>,
+    debug_view: DebugView,
+    quality: QualityPreset,
+    /// Counters of the last drawn frame.
+    frame_stats: FrameStats,
+    favorites: Favorites,
 }
 
 impl Renderer {
-    pub fn new<D: GlDisplay>(gl_display: &D) -> Self {
+    /// Loads the GL functions and all assets, starting with the scene of
+    /// `seed`, usually [`DEFAULT_SEED`].
+    pub fn new<D: GlDisplay>(gl_display: &D, seed: u32) -> Self {
+        Self::with_quality(gl_display, seed, QualityPreset::default())
+    }
+
+    /// Like [`Renderer::new`], but starts with another quality than
+    /// [`QualityPreset::High`], e.g. to skip loading the finer terrain mesh.
+    pub fn with_quality<D: GlDisplay>(gl_display: &D, seed: u32, quality: QualityPreset) -> Self {
         // Haha that seems like the only truely unsafe thing here, yet it is the only
         // one not marked as unsafe xD
         gl::load_with(|symbol| {
@@ -47,70 +444,832 @@ impl Renderer {
         }
         println!("Max attrib pointers: {max_attrib_pointers}");
 
+        // Reported up front, features using these stages get a
+        // `ShaderError::Unsupported` when linking and have to fall back
+        for (stage, feature) in [
+            (ShaderType::Geometry, "geometry shaders"),
+            (ShaderType::TessellationControl, "tessellation"),
+        ] {
+            if !stage.is_supported() {
+                println!("No {feature} on this context, features using them are disabled");
+            }
+        }
+
         let mut viewport: [gl::types::GLint; 4] = [0; 4];
         unsafe {
             gl::GetIntegerv(gl::VIEWPORT, viewport.as_mut_ptr());
         }
-        let aspect_ratio = viewport[2] as f32 / viewport[3] as f32;
+        let (width, height) = (viewport[2], viewport[3]);
+        let aspect_ratio = width as f32 / height as f32;
+
+        let mut samples: gl::types::GLint = 0;
+        unsafe {
+            gl::GetIntegerv(gl::SAMPLES, &mut samples);
+        }
+        println!("MSAA samples: {samples}");
+
+        let mut depth_bits: gl::types::GLint = 0;
+        unsafe {
+            gl::GetFramebufferAttachmentParameteriv(
+                gl::FRAMEBUFFER,
+                gl::DEPTH,
+                gl::FRAMEBUFFER_ATTACHMENT_DEPTH_SIZE,
+                &mut depth_bits,
+            );
+        }
+        println!("Depth buffer: {depth_bits} bit");
+        if depth_bits < DEFAULT_DEPTH_BITS as i32 {
+            eprintln!("Warning: less than {DEFAULT_DEPTH_BITS} depth bits, expect z-fighting");
+        }
+        // Offscreen targets can always have at least the default precision
+        let depth_bits = depth_bits.clamp(DEFAULT_DEPTH_BITS as i32, 32) as u8;
 
         unsafe {
             gl::Enable(gl::DEPTH_TEST);
             gl::DepthFunc(gl::LESS);
+            // On by default, but the thin terrain ridges shimmer badly without it
+            gl::Enable(gl::MULTISAMPLE);
+            // The albedo textures are decoded to linear values, see
+            // `Texture::from_file_srgb`, so the result is encoded again when
+            // the window is sRGB. Compared to lighting the encoded colors, the
+            // textures look less muddy, with brighter lit sides and softer
+            // falloff into the shade. Colors given directly are taken as
+            // linear as well, so the palettes are decoded, see
+            // `Palette::from_seed`.
+            gl::Enable(gl::FRAMEBUFFER_SRGB);
         }
 
         // Does all the I/O operations and loading to the GPU.
-        let assets = Assets::load();
-        let scene = Scene::create(13, &assets);
+        let assets = Assets::load_with_quality(quality.settings());
+        let scene = Scene::create(seed, &assets);
 
         Self {
+            width,
+            height,
             aspect_ratio,
             assets,
             scene,
-            seed: 13,
+            seed,
+            pending_seed: None,
+            variation: SceneVariation::default(),
+            density_multiplier: 1.0,
+            bloom: Bloom::new(),
+            hdr_target: None,
+            samples,
+            depth_bits,
+            msaa_target: None,
+            terrain_only: false,
+            transparent_background: false,
+            base_plane: false,
+            backface_culling: None,
+            lights: None,
+            environment: None,
+            sky_image: None,
+            sky: Sky::new(),
+            projection: ProjectionMode::default(),
+            camera: Camera::default(),
+            camera_mode: CameraMode::default(),
+            movement: glm::Vec3::zeros(),
+            last_frame: None,
+            debug_view: DebugView::Off,
+            quality,
+            frame_stats: FrameStats::default(),
+            favorites: Favorites::load(),
         }
     }
 
     pub fn draw(&mut self) {
-        let (red, green, blue, alpha) = self.scene.background_color();
+        #[cfg(feature = "trace")]
+        let _span = tracing::info_span!("draw").entered();
+
+        if let Some(seed) = self.pending_seed.take() {
+            self.create_scene(seed);
+        }
+
+        let now = Instant::now();
+        // Capped, so a stall doesn't throw the camera across the scene
+        let dt = self
+            .last_frame
+            .map_or(0.0, |last| (now - last).as_secs_f32().min(0.1));
+        self.last_frame = Some(now);
+        if let CameraMode::Free(camera) = &mut self.camera_mode {
+            camera.advance(self.movement, dt);
+            // Flying into the hills would only show their insides
+            let ground = self.scene.height_at(camera.position.x, camera.position.y);
+            camera.position.z = camera.position.z.max(ground + MIN_CAMERA_CLEARANCE);
+        }
+
+        let post_processing = self.post_processing();
+        if post_processing {
+            let (width, height, samples) = (self.width, self.height, self.samples);
+            let depth_bits = self.depth_bits;
+            let hdr_target = self
+                .hdr_target
+                .get_or_insert_with(|| Framebuffer::new(width, height, Some(depth_bits)));
+            // Rendering straight into the texture would lose the antialiasing.
+            if samples > 1 {
+                self.msaa_target
+                    .get_or_insert_with(|| {
+                        MultisampleFramebuffer::new(width, height, samples, depth_bits)
+                    })
+                    .bind();
+            } else {
+                hdr_target.bind();
+            }
+        }
+
+        let (eye, _) = self.eye_and_target();
+        self.frame_stats = self.render_scene(self.view_proj(), eye);
+
+        if let (true, Some(hdr_target)) = (post_processing, &self.hdr_target) {
+            if let Some(msaa_target) = &self.msaa_target {
+                msaa_target.resolve_into(hdr_target);
+            }
+            self.bloom
+                .apply(hdr_target.color(), self.width, self.height);
+        }
+    }
+
+    fn create_scene(&mut self, seed: u32) {
+        self.seed = seed;
+        self.scene = Scene::create_with_variation(
+            seed,
+            &self.assets,
+            &self.variation,
+            self.density_multiplier,
+        );
+        self.update_base_plane();
+        self.scene.sky = self.sky_image.clone();
+        if let CameraMode::Orbit(camera) = &mut self.camera_mode {
+            camera.target = orbit_target(&self.scene);
+        }
+    }
+
+    /// Whether the scene goes through the bloom pass. Debug views skip it, so
+    /// their values arrive on the screen unchanged.
+    fn post_processing(&self) -> bool {
+        self.bloom.is_enabled()
+            && self.assets.quality.post_processing
+            && self.debug_view == DebugView::Off
+    }
+
+    /// Clears the bound framebuffer and draws all entities seen from `eye`.
+    fn render_scene(&self, view_proj: glm::Mat4, eye: glm::Vec3) -> FrameStats {
+        let debugging = self.debug_view != DebugView::Off;
+        let (red, green, blue, alpha) = if self.transparent_background {
+            (0.0, 0.0, 0.0, 0.0)
+        } else if debugging {
+            (0.0, 0.0, 0.0, 1.0)
+        } else {
+            self.scene.background_color()
+        };
         unsafe {
             gl::ClearColor(red, green, blue, alpha);
             gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
         }
+        let draw_sky = !self.transparent_background && !debugging;
+        if draw_sky {
+            if let Some(sky_image) = &self.scene.sky {
+                self.sky.render_equirectangular(sky_image, &view_proj);
+            } else if let Some(environment) = &self.environment {
+                self.sky.render(environment, &view_proj);
+            } else {
+                self.sky.render(&self.scene.sky_box, &view_proj);
+            }
+        }
+        // The fog fades to the palette, which only the generated sky box matches
+        let fogged = draw_sky && self.scene.sky.is_none() && self.environment.is_none();
 
-        let projection: glm::Mat4 = glm::perspective(
-            self.aspect_ratio,
-            65.0 * std::f32::consts::PI / 180.,
-            0.1,  // 10 cm
-            50.0, // 50 m
-        );
-        let camera_transform = glm::look_at(
-            &self.scene.eye_position(),
-            &self.scene.look_at(),
-            &glm::Vec3::z_axis(),
+        let lights = self.lights();
+        let stats = Cell::new(FrameStats::default());
+        let ctx = RenderContext {
+            view_proj_mat: view_proj,
+            eye,
+            palette: &self.scene.palette,
+            lights: &lights,
+            environment: self.environment.as_ref(),
+            debug_view: self.debug_view,
+            depth_plane: depth_plane(&view_proj),
+            frustum: Frustum::from_view_proj(&view_proj),
+            fog_density: if fogged {
+                self.scene.fog_density()
+            } else {
+                0.0
+            },
+            stats: &stats,
+            time: self.scene.start_time.elapsed().as_secs_f32(),
+            soft_edges: false,
+        };
+
+        let visible: Vec<&dyn Renderable> = self
+            .scene
+            .entities
+            .iter()
+            .chain(&self.scene.base_plane)
+            .filter(|entity| !self.terrain_only || entity.is_terrain())
+            .map(|entity| entity.as_ref())
+            .collect();
+        // They would only smudge the debug views
+        render_entities(&ctx, &visible, !debugging);
+        stats.get()
+    }
+
+    /// The lights set by [`Renderer::set_lights`], or the defaults of the scene.
+    fn lights(&self) -> Cow<'_, [DirectionalLight]> {
+        match &self.lights {
+            Some(lights) => Cow::Borrowed(lights),
+            None => Cow::Owned(DirectionalLight::defaults(&self.scene.palette)),
+        }
+    }
+
+    fn view_proj(&self) -> glm::Mat4 {
+        let projection = self.projection.matrix(self.aspect_ratio);
+        let (eye, target) = self.eye_and_target();
+        projection * self.camera.view_matrix(&eye, &target)
+    }
+
+    fn eye_and_target(&self) -> (glm::Vec3, glm::Vec3) {
+        match &self.camera_mode {
+            CameraMode::Scene => (self.scene.eye_position(), self.scene.look_at()),
+            CameraMode::Free(camera) => (camera.position, camera.target()),
+            CameraMode::Orbit(camera) => (camera.eye_position(), camera.target),
+        }
+    }
+
+    /// The point on the ground below a pixel of the window, if the ground is
+    /// visible there. The pixel coordinates start in the top left corner.
+    pub fn pick_terrain(&self, x: f32, y: f32) -> Option<glm::Vec3> {
+        // Marching is cheaper than it sounds, the height map is only sampled
+        // every STEP meters until the ray first goes below the ground.
+        const STEP: f32 = 0.1;
+        const MAX_DISTANCE: f32 = 40.0;
+
+        let ndc = glm::vec2(
+            2.0 * x / self.width as f32 - 1.0,
+            1.0 - 2.0 * y / self.height as f32,
         );
-        let view_proj_mat = projection * camera_transform;
+        let inverse = glm::inverse(&self.view_proj());
+        let unproject = |depth: f32| {
+            let point = inverse * glm::vec4(ndc.x, ndc.y, depth, 1.0);
+            point.xyz() / point.w
+        };
+        let start = unproject(-1.0);
+        let direction = glm::normalize(&(unproject(1.0) - start));
+
+        let below_ground = |t: f32| {
+            let point = start + t * direction;
+            point.z < self.scene.height_at(point.x, point.y)
+        };
+        let mut t = 0.0;
+        while t < MAX_DISTANCE {
+            if below_ground(t + STEP) {
+                // Refine by bisection
+                let (mut above, mut below) = (t, t + STEP);
+                for _ in 0..8 {
+                    let middle = 0.5 * (above + below);
+                    if below_ground(middle) {
+                        below = middle;
+                    } else {
+                        above = middle;
+                    }
+                }
+                return Some(start + below * direction);
+            }
+            t += STEP;
+        }
+        None
+    }
 
-        for entity in &self.scene.entities {
-            entity.render(&view_proj_mat);
+    /// Paints into the base map at the ground below a window pixel. Only
+    /// visible after [`Renderer::regenerate_scene`].
+    pub fn paint_base_map(&mut self, x: f32, y: f32, brush: &Brush, erase: bool) {
+        if let Some(point) = self.pick_terrain(x, y) {
+            // Copies the map on the first stroke after a regeneration, the scene
+            // keeps reading the old one
+            let base_map = Rc::make_mut(&mut self.assets.base_map);
+            brush.paint(base_map, self.scene.size, point.xy(), erase);
         }
     }
 
+    /// Creates the current scene anew on the next frame, to pick up changes
+    /// to the base map.
+    pub fn regenerate_scene(&mut self) {
+        self.request_scene(self.target_seed());
+    }
+
+    pub fn save_base_map(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        self.assets.base_map.save(path)?;
+        Ok(())
+    }
+
     pub fn resize(&mut self, width: i32, height: i32) {
         unsafe {
             gl::Viewport(0, 0, width, height);
         }
+        self.width = width;
+        self.height = height;
         self.aspect_ratio = width as f32 / height as f32;
+        // Recreated with the new size on the next frame
+        self.hdr_target = None;
+        self.msaa_target = None;
+    }
+
+    /// Saves the current frame as a PNG, like the F12 key does. Same as
+    /// [`Renderer::capture_screenshot_opaque`].
+    pub fn screenshot(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        self.capture_screenshot_opaque(path)
+    }
+
+    /// Saves the current frame as a PNG, including the alpha channel of the
+    /// window. Must be called after [`Renderer::draw`] but before swapping.
+    pub fn capture_screenshot(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        read_window_pixels(self.width, self.height).save(path)?;
+        Ok(())
+    }
+
+    /// Like [`Renderer::capture_screenshot`], but ignores the alpha of the window.
+    /// Since the window is requested with transparency, it would otherwise leak
+    /// into the image wherever the alpha isn't exactly one.
+    pub fn capture_screenshot_opaque(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let mut image = read_window_pixels(self.width, self.height);
+        for pixel in image.pixels_mut() {
+            pixel[3] = u8::MAX;
+        }
+        image.save(path)?;
+        Ok(())
+    }
+
+    /// The offscreen target the scene was last drawn into, before the post
+    /// processing composited it into the window. Only exists while post
+    /// processing is enabled, see [`Renderer::set_bloom`].
+    ///
+    /// Its color texture can be sampled by other GL code on the same context,
+    /// for example to show the scene inside a UI.
+    pub fn offscreen_target(&self) -> Option<&Framebuffer> {
+        self.hdr_target.as_ref().filter(|_| self.post_processing())
+    }
+
+    /// Renders the full sphere around `position` and saves it as an
+    /// equirectangular PNG, `resolution` pixels high and twice as wide.
+    ///
+    /// The six cube faces are rendered offscreen without post processing or
+    /// antialiasing, the window content is left alone.
+    pub fn capture_panorama(
+        &self,
+        position: glm::Vec3,
+        path: &Path,
+        resolution: u32,
+    ) -> Result<(), Box<dyn Error>> {
+        if resolution == 0 {
+            return Err("The panorama resolution must not be zero".into());
+        }
+        let face_size = panorama::face_size(resolution);
+        let target = Framebuffer::new(face_size as i32, face_size as i32, Some(self.depth_bits));
+        target.bind();
+
+        let faces = panorama::cube_faces(position, face_size);
+        let images: Vec<_> = faces
+            .iter()
+            .map(|face| {
+                self.render_scene(face.view_proj, position);
+                target.read_pixels()
+            })
+            .collect();
+        Framebuffer::bind_default(self.width, self.height);
+
+        panorama::stitch(&faces, &images, resolution).save(path)?;
+        Ok(())
+    }
+
+    /// Renders a single model on a neutral background, framed from a fixed
+    /// angle above the front right, e.g. for the thumbnails of an asset
+    /// browser. The image is `size` pixels square, with the top row first.
+    ///
+    /// The `shader` must work with the foliage VAOs, like
+    /// [`Assets::foliage_shader`]. The model is lit like the current scene.
+    /// Fails for an inconsistent mesh, e.g. one without faces, or if it
+    /// doesn't fit into the GPU memory.
+    pub fn render_asset_thumbnail(
+        &self,
+        mesh: &Mesh,
+        texture: Rc<Texture>,
+        shader: Rc<Shader>,
+        size: u32,
+    ) -> Result<image::RgbaImage, Box<dyn Error>> {
+        // Rendered larger and scaled down, since offscreen targets have no MSAA
+        const SUPERSAMPLING: u32 = 2;
+        const FOV_Y: f32 = 30.0;
+
+        if size == 0 {
+            return Err("The thumbnail size must not be zero".into());
+        }
+        mesh.check_consitency()
+            .map_err(|err| format!("Can't show the thumbnail model: {err}"))?;
+
+        let (min, max) = mesh.bounding_box();
+        let center = (min + max) / 2.0;
+        let radius = (glm::distance(&min, &max) / 2.0).max(1e-3);
+        // Far enough that the bounding sphere fits into the view
+        let distance = radius / (FOV_Y.to_radians() / 2.0).sin();
+        let eye = center + distance * glm::normalize(&glm::vec3(1.0, -1.0, 0.6));
+        let projection = glm::perspective(
+            1.0,
+            FOV_Y.to_radians(),
+            (distance - radius) * 0.5,
+            distance + radius,
+        );
+        let view_proj = projection * Camera::default().view_matrix(&eye, &center);
+
+        let vao = InstancedMeshesVAO::from_existing_with_models(
+            ElementMeshVAO::new_from_mesh(mesh),
+            &[glm::identity()],
+            &[glm::Vec3::zeros()],
+        )
+        .map_err(|err| format!("Failed to upload the thumbnail model: {err}"))?;
+        let entity = ShrubEntities {
+            models: vec![FoliageModel {
+                albedo: texture,
+                vao: Rc::new(vao),
+                density_lod: None,
+            }],
+            emissive: None,
+            emissive_color: glm::Vec3::zeros(),
+            shader,
+            contact_shadows: None,
+            wind_strength: 0.0,
+            transparent: false,
+            center,
+            instances: Rc::new(SpatialGrid::new(
+                [(glm::Vec2::zeros(), glm::identity())],
+                1.0,
+            )),
+        };
+
+        let target_size = (size * SUPERSAMPLING) as i32;
+        let target = Framebuffer::new(target_size, target_size, Some(self.depth_bits));
+        target.bind();
+        let lights = self.lights();
+        let stats = Cell::new(FrameStats::default());
+        let ctx = RenderContext {
+            view_proj_mat: view_proj,
+            eye,
+            palette: &self.scene.palette,
+            lights: &lights,
+            environment: None,
+            debug_view: DebugView::Off,
+            depth_plane: depth_plane(&view_proj),
+            frustum: Frustum::from_view_proj(&view_proj),
+            fog_density: 0.0,
+            stats: &stats,
+            time: 0.0,
+            soft_edges: false,
+        };
+        unsafe {
+            gl::ClearColor(0.5, 0.5, 0.5, 1.0);
+            gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
+        }
+        entity.render(&ctx);
+        let image = target.read_pixels();
+        Framebuffer::bind_default(self.width, self.height);
+
+        Ok(image::imageops::resize(
+            &image,
+            size,
+            size,
+            image::imageops::FilterType::Triangle,
+        ))
+    }
+
+    /// Instance counts of the last call to [`Renderer::draw`].
+    pub fn frame_stats(&self) -> FrameStats {
+        self.frame_stats
+    }
+
+    pub fn set_projection(&mut self, projection: ProjectionMode) {
+        self.projection = projection;
+    }
+
+    pub fn debug_view(&self) -> DebugView {
+        self.debug_view
+    }
+
+    /// Draws normals, depth or texture coordinates instead of the shaded
+    /// scene. The sky, decals and bloom are left out while debugging.
+    pub fn set_debug_view(&mut self, debug_view: DebugView) {
+        self.debug_view = debug_view;
+    }
+
+    pub fn quality(&self) -> QualityPreset {
+        self.quality
+    }
+
+    /// Switches the tessellation and resolution of the terrain, the foliage
+    /// density, shadows and post processing together. Recreates the current
+    /// scene with them on the next frame.
+    pub fn set_quality(&mut self, quality: QualityPreset) {
+        self.quality = quality;
+        self.assets.set_quality(quality.settings());
+        self.regenerate_scene();
+    }
+
+    /// Rolls the camera around its view direction, positive angles in radians
+    /// to the right.
+    pub fn set_roll(&mut self, radians: f32) {
+        self.camera.roll = radians;
+    }
+
+    /// The world direction shown upwards on the screen, `+z` by default.
+    pub fn set_camera_up(&mut self, up: glm::Vec3) {
+        self.camera.up = up;
+    }
+
+    pub fn camera_mode(&self) -> CameraMode {
+        self.camera_mode
+    }
+
+    /// Switches between the framing of the scene, a free and an orbiting
+    /// camera. The free camera is kept when the scene changes, the orbit moves
+    /// its target along to the center of the new scene.
+    pub fn set_camera_mode(&mut self, mode: CameraMode) {
+        self.camera_mode = mode;
+    }
+
+    /// Takes over the current view with a free camera, or returns to the
+    /// bobbing view of the scene.
+    pub fn toggle_free_camera(&mut self) {
+        self.camera_mode = match self.camera_mode {
+            CameraMode::Scene => {
+                let (eye, target) = self.eye_and_target();
+                CameraMode::Free(FreeCamera::looking_at(eye, target))
+            }
+            CameraMode::Free(_) | CameraMode::Orbit(_) => CameraMode::Scene,
+        };
+    }
+
+    /// Starts orbiting the center of the scene from the side of the current
+    /// view, or returns to the bobbing view of the scene.
+    pub fn toggle_orbit_camera(&mut self) {
+        self.camera_mode = match self.camera_mode {
+            CameraMode::Orbit(_) => CameraMode::Scene,
+            CameraMode::Scene | CameraMode::Free(_) => {
+                let (eye, _) = self.eye_and_target();
+                let target = orbit_target(&self.scene);
+                CameraMode::Orbit(OrbitCamera::around(target, eye, self.scene.size, 0.5))
+            }
+        };
+    }
+
+    /// The direction the free camera keeps moving in every frame, see
+    /// [`FreeCamera::advance`]. Zero stops it.
+    pub fn set_movement(&mut self, direction: glm::Vec3) {
+        self.movement = direction;
+    }
+
+    /// Turns the free camera, see [`FreeCamera::rotate`], or moves the orbiting
+    /// one around its target, where looking down means going higher up. Does
+    /// nothing for the framing of the scene.
+    pub fn rotate_camera(&mut self, yaw: f32, pitch: f32) {
+        match &mut self.camera_mode {
+            CameraMode::Scene => {}
+            CameraMode::Free(camera) => camera.rotate(yaw, pitch),
+            CameraMode::Orbit(camera) => camera.rotate(yaw, -pitch),
+        }
+    }
+
+    /// Changes the distance of the orbiting camera, see [`OrbitCamera::zoom`].
+    pub fn zoom_camera(&mut self, factor: f32) {
+        if let CameraMode::Orbit(camera) = &mut self.camera_mode {
+            camera.zoom(factor);
+        }
+    }
+
+    /// Replaces the default key and fill light of the scene. At most
+    /// [`MAX_LIGHTS`] are used, the rest is ignored.
+    pub fn set_lights(&mut self, lights: &[DirectionalLight]) {
+        if lights.len() > MAX_LIGHTS {
+            eprintln!(
+                "Only {MAX_LIGHTS} lights are supported, ignoring the last {}",
+                lights.len() - MAX_LIGHTS
+            );
+        }
+        self.lights = Some(lights.iter().take(MAX_LIGHTS).copied().collect());
+    }
+
+    /// Skips the back faces of all triangles, given the winding of the front
+    /// faces. `None` draws both sides, which is the default, since the leaves
+    /// are single sided quads.
+    ///
+    /// With culling on, models with flipped faces disappear, which helps
+    /// finding broken exports.
+    pub fn set_backface_culling(&mut self, front_face: Option<Winding>) {
+        self.backface_culling = front_face;
+        unsafe {
+            match front_face {
+                Some(winding) => {
+                    gl::Enable(gl::CULL_FACE);
+                    gl::CullFace(gl::BACK);
+                    gl::FrontFace(winding.into());
+                }
+                None => gl::Disable(gl::CULL_FACE),
+            }
+        }
+    }
+
+    /// Clears the background to fully transparent instead of the opaque sky
+    /// color, for overlay style windows. The sky is left out entirely, since
+    /// compositors expect premultiplied alpha.
+    ///
+    /// Only has a visible effect if the window was created with an alpha
+    /// channel and the platform supports transparent windows.
+    pub fn set_transparent_background(&mut self, transparent: bool) {
+        self.transparent_background = transparent;
+    }
+
+    /// Replaces the plain sky color with the cube map, which wet surfaces
+    /// reflect as well. Load it for example from a panorama captured with
+    /// [`Renderer::capture_panorama`], see [`CubeTexture::from_equirectangular`].
+    pub fn set_environment(&mut self, environment: CubeTexture) {
+        self.environment = Some(environment);
+    }
+
+    /// Draws the equirectangular image around this and all following scenes,
+    /// see [`Scene::with_sky_image`].
+    pub fn set_sky_image(&mut self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let sky_image = Rc::new(Texture::from_file_srgb(path)?);
+        self.scene.sky = Some(sky_image.clone());
+        self.sky_image = Some(sky_image);
+        Ok(())
+    }
+
+    /// Draws a flat ground slightly below the lowest point of each scene, see
+    /// [`Scene::with_base_plane`].
+    pub fn set_base_plane(&mut self, enabled: bool) {
+        self.base_plane = enabled;
+        self.update_base_plane();
+    }
+
+    fn update_base_plane(&mut self) {
+        if self.base_plane {
+            let height = self.scene.min_height() - BASE_PLANE_DEPTH;
+            self.scene.set_base_plane(true, height, &self.assets);
+        } else {
+            self.scene.base_plane = None;
+        }
+    }
+
+    /// Hides everything but the terrain, to judge the quality of its edges
+    /// against the sky.
+    pub fn toggle_terrain_only(&mut self) {
+        self.terrain_only = !self.terrain_only;
+    }
+
+    /// Makes pixels brighter than `threshold` glow, scaled by `intensity`.
+    /// An intensity of zero disables the bloom pass.
+    pub fn set_bloom(&mut self, threshold: f32, intensity: f32) {
+        self.bloom.threshold = threshold;
+        self.bloom.intensity = intensity;
     }
 
     pub fn next_scene(&mut self) {
-        self.seed = self.seed.wrapping_add(1);
-        self.scene = Scene::create(self.seed, &self.assets);
+        self.request_scene(self.target_seed().wrapping_add(1));
     }
 
     pub fn prev_scene(&mut self) {
-        self.seed = self.seed.wrapping_sub(1);
-        self.scene = Scene::create(self.seed, &self.assets);
+        self.request_scene(self.target_seed().wrapping_sub(1));
+    }
+
+    /// Creates the scene for `seed` before the next frame, dropping any
+    /// earlier request that wasn't built yet.
+    pub fn request_scene(&mut self, seed: u32) {
+        self.pending_seed = Some(seed);
+    }
+
+    /// Lets the size and density of the following scenes vary with their
+    /// seed. Recreates the current scene with it on the next frame.
+    pub fn set_scene_variation(&mut self, variation: SceneVariation) {
+        self.variation = variation;
+        self.regenerate_scene();
+    }
+
+    pub fn density_multiplier(&self) -> f32 {
+        self.density_multiplier
+    }
+
+    /// Scales the density of all foliage and recreates the current scene with
+    /// it on the next frame.
+    pub fn set_density_multiplier(&mut self, multiplier: f32) {
+        self.density_multiplier = multiplier.max(0.0);
+        self.regenerate_scene();
+    }
+
+    /// The seed of the scene shown after the pending request, if any.
+    pub fn target_seed(&self) -> u32 {
+        self.pending_seed.unwrap_or(self.seed)
+    }
+
+    /// The seed and all settings of the current frame, see
+    /// [`Renderer::dump_state`].
+    pub fn state(&self) -> RendererState {
+        RendererState {
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            seed: self.seed,
+            time: self.scene.start_time.elapsed().as_secs_f32(),
+            scene_size: self.scene.size,
+            variation: self.variation.clone(),
+            density_multiplier: self.density_multiplier,
+            projection: self.projection,
+            camera: self.camera,
+            camera_mode: self.camera_mode,
+            lights: self.lights.clone(),
+            bloom_threshold: self.bloom.threshold,
+            bloom_intensity: self.bloom.intensity,
+            terrain_only: self.terrain_only,
+            base_plane: self.base_plane,
+            transparent_background: self.transparent_background,
+            backface_culling: self.backface_culling,
+            debug_view: self.debug_view,
+            quality: self.quality,
+        }
+    }
+
+    /// Restores all settings and recreates the scene right away, wound back
+    /// to the time of the state, so the next frame matches the one it was
+    /// taken from.
+    pub fn set_state(&mut self, state: &RendererState) {
+        if state.version != env!("CARGO_PKG_VERSION") {
+            eprintln!(
+                "Warning: the state was saved by version {}, scenes may differ in {}",
+                state.version,
+                env!("CARGO_PKG_VERSION")
+            );
+        }
+        self.variation = state.variation.clone();
+        self.density_multiplier = state.density_multiplier;
+        self.projection = state.projection;
+        self.camera = state.camera;
+        self.camera_mode = state.camera_mode;
+        self.lights = state.lights.clone();
+        self.set_bloom(state.bloom_threshold, state.bloom_intensity);
+        self.terrain_only = state.terrain_only;
+        self.base_plane = state.base_plane;
+        self.transparent_background = state.transparent_background;
+        self.set_backface_culling(state.backface_culling);
+        self.debug_view = state.debug_view;
+        self.quality = state.quality;
+        self.assets.set_quality(state.quality.settings());
+
+        self.pending_seed = None;
+        self.create_scene(state.seed);
+        let elapsed = Duration::try_from_secs_f32(state.time).unwrap_or_default();
+        self.scene.start_time = Instant::now()
+            .checked_sub(elapsed)
+            .unwrap_or(self.scene.start_time);
+        if self.scene.size != state.scene_size {
+            eprintln!(
+                "Warning: the scene is {} m large instead of {} m",
+                self.scene.size, state.scene_size
+            );
+        }
     }
+
+    /// Saves the seed, camera and all tunables as RON, to reproduce the
+    /// current frame with [`Renderer::load_state`], e.g. for bug reports.
+    pub fn dump_state(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        self.state().save(path)
+    }
+
+    /// Restores a state saved by [`Renderer::dump_state`].
+    pub fn load_state(&mut self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let state = RendererState::load(path)?;
+        self.set_state(&state);
+        Ok(())
+    }
+
+    /// Bookmarks the seed of the current scene, or of the requested one if
+    /// it isn't shown yet.
+    pub fn add_favorite(&mut self) -> Result<(), Box<dyn Error>> {
+        self.favorites.add(self.target_seed())
+    }
+
+    /// Shows the next bookmarked scene, if there are any.
+    pub fn next_favorite(&mut self) {
+        if let Some(seed) = self.favorites.next_seed() {
+            self.request_scene(seed);
+        }
+    }
+
+    /// Shows the previous bookmarked scene, if there are any.
+    pub fn prev_favorite(&mut self) {
+        if let Some(seed) = self.favorites.prev_seed() {
+            self.request_scene(seed);
+        }
+    }
+}
+
+/// The center of the scene on the ground, which the orbit circles.
+fn orbit_target(scene: &Scene) -> glm::Vec3 {
+    let half = scene.size / 2.0;
+    glm::vec3(half, half, scene.height_at(half, half))
 }
 
 fn get_gl_string(variant: gl::types::GLenum) -> Option<&'static CStr> {
@@ -1,20 +1,26 @@
 use crate::assets::{Assets, ImageNoiseFnWrapper};
+use crate::biome::{BiomeId, BiomeMap};
 use crate::foliage::ShrubEntitiesBuilder;
-use crate::renderer::Renderable;
-use crate::terrain::TerrainEntity;
+use crate::palette::Palette;
+use crate::renderer::{ProjectionMode, Renderable};
+use crate::seed::Seed;
+use crate::terrain::{TerrainEntity, TerrainTile};
+use crate::texture::{CubeTexture, Texture};
 
 use nalgebra_glm as glm;
 use noise::NoiseFn;
-use rand::{Rng, SeedableRng};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::ops::{Range, RangeInclusive};
 use std::rc::Rc;
 use std::time::Instant;
 
+/// Wraps the generation in a `tracing` span with the `trace` feature, does nothing otherwise.
 macro_rules! time {
     ($name:expr, $block:expr) => {{
-        let start = Instant::now();
-        let result = $block;
-        println!("Creating {} took {}ms", $name, start.elapsed().as_millis());
-        result
+        #[cfg(feature = "trace")]
+        let _span = tracing::info_span!("create", part = %$name).entered();
+        $block
     }};
 }
 
@@ -23,21 +29,136 @@ macro_rules! time {
 /// One corner of the scene is at (0, 0), the opposite at (+SCENE_SIZE, +SCENE_SIZE).
 pub const SCENE_SIZE: f32 = 15.0;
 
+/// How much scenes may differ from each other beyond their terrain, picked
+/// per seed within these ranges.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SceneVariation {
+    /// Side length of the scene in meters.
+    pub size_range: RangeInclusive<f32>,
+    /// Factor on the density of all foliage. Densities are per square meter,
+    /// so larger scenes get proportionally more instances either way.
+    pub density_multiplier_range: RangeInclusive<f32>,
+    /// Frames the scene from a random corner, looking slightly to either
+    /// side, instead of always along the same diagonal.
+    pub camera_rotation: bool,
+    /// Rings of mirrored copies of the ground around the scene, repeating it
+    /// to the horizon behind the fog, see [`TerrainTile::mirrored_grid`].
+    /// Missing in states saved before the tiles existed.
+    #[serde(default)]
+    pub ground_tile_rings: i32,
+}
+
+impl Default for SceneVariation {
+    /// Always [`SCENE_SIZE`] with the regular density and framing.
+    fn default() -> Self {
+        SceneVariation {
+            size_range: SCENE_SIZE..=SCENE_SIZE,
+            density_multiplier_range: 1.0..=1.0,
+            camera_rotation: false,
+            ground_tile_rings: 0,
+        }
+    }
+}
+
 pub struct Scene {
     pub entities: Vec<Box<dyn Renderable>>,
+    pub palette: Palette,
     pub start_time: Instant,
+    /// Height of the ground at a point in the XY plane, including the base
+    /// map, rocks and detail. Prefer [`Scene::height_at`] for single points.
+    pub height_map: Rc<dyn NoiseFn<f64, 2>>,
+    /// The terrain spans from (0, 0) to (size, size).
+    pub size: f32,
+    /// Optional flat ground below the terrain, see [`Scene::with_base_plane`].
+    pub base_plane: Option<Box<dyn Renderable>>,
+    /// Equirectangular image drawn as the background instead of the plain
+    /// sky color, see [`Scene::with_sky_image`].
+    pub sky: Option<Rc<Texture>>,
+    /// Drawn as the background when there is neither a sky image nor an
+    /// environment, a gradient of the palette.
+    pub sky_box: CubeTexture,
+    /// Rotation of the camera framing about the scene center, in radians.
+    pub camera_angle: f32,
+    /// Turn of the view direction about the camera, in radians.
+    pub look_angle: f32,
+    /// See [`Scene::fog_density`].
+    fog_density: f32,
+}
+
+/// How far [`SceneVariation::camera_rotation`] turns the view to either side.
+/// The camera itself only moves to other corners, since rotating it any
+/// further would take it past the terrain edge.
+const MAX_LOOK_ANGLE: f32 = 0.3;
+
+/// How far the base plane reaches past the scene on every side, in meters.
+const BASE_PLANE_MARGIN: f32 = 50.0;
+
+/// Texels per face of [`Scene::sky_box`]. The gradient is smooth, so few
+/// are enough.
+const SKY_BOX_SIZE: u32 = 32;
+
+/// The most of the terrain that shows through the fog at the far plane, so
+/// the clip boundary doesn't cut visibly into the ground.
+const FAR_PLANE_TRANSMITTANCE: f32 = 0.02;
+
+/// How much hazier than the clearest ones the scenes get.
+const MAX_FOG_SCALE: f32 = 1.5;
+
+/// Of [`Scene::fog_density`]. Even the clearest scenes fade to at most
+/// [`FAR_PLANE_TRANSMITTANCE`] at the far plane.
+fn fog_density_range() -> Range<f32> {
+    // Solved exp(-(density * far)^2) = transmittance for the density
+    let min = (-FAR_PLANE_TRANSMITTANCE.ln()).sqrt() / ProjectionMode::FAR;
+    min..min * MAX_FOG_SCALE
 }
 
 impl Scene {
+    /// The seed of a scene named by a string, e.g. "mossy-hollow". The same
+    /// name gives the same seed on every run and platform.
+    pub fn seed_from_str(name: &str) -> u32 {
+        Seed::new(0).derive(name).value() as u32
+    }
+
     pub fn create(seed: u32, assets: &Assets) -> Self {
+        Self::create_with_variation(seed, assets, &SceneVariation::default(), 1.0)
+    }
+
+    /// Like [`Scene::create`], but also derives the size and foliage density
+    /// from the seed. All foliage densities are additionally scaled by
+    /// `density_multiplier`, for tuning.
+    pub fn create_with_variation(
+        seed: u32,
+        assets: &Assets,
+        variation: &SceneVariation,
+        density_multiplier: f32,
+    ) -> Self {
         time!(format!("SCENE {}", seed), {
-            let mut rng = rand::rngs::SmallRng::seed_from_u64(seed as u64);
+            // Every part draws from its own branch, see `crate::seed`
+            let root = Seed::from(seed);
+            let mut variation_rng = root.derive("variation").rng();
+            let size = variation_rng.gen_range(variation.size_range.clone());
+            let density = variation_rng.gen_range(variation.density_multiplier_range.clone())
+                as f64
+                * density_multiplier as f64
+                * assets.quality.foliage_density_scale as f64;
+            // Limits are absolute counts, so they grow with the area
+            let area_factor = density * (size as f64 / SCENE_SIZE as f64).powi(2);
+            let (camera_angle, look_angle) = if variation.camera_rotation {
+                let quarter_turns = variation_rng.gen_range(0..4) as f32;
+                (
+                    quarter_turns * std::f32::consts::FRAC_PI_2,
+                    variation_rng.gen_range(-MAX_LOOK_ANGLE..=MAX_LOOK_ANGLE),
+                )
+            } else {
+                (0.0, 0.0)
+            };
 
             let height_map: Rc<dyn NoiseFn<f64, 2>> = time!(
                 "height map",
                 Rc::new(crate::terrain::height_map(
                     assets.base_map.clone(),
-                    rng.gen()
+                    size,
+                    root.derive("height").noise_seed()
                 ))
             );
 
@@ -45,93 +166,238 @@ impl Scene {
                 "variant map",
                 Rc::new(crate::terrain::variant_map(
                     assets.base_map.clone(),
-                    rng.gen()
+                    size,
+                    root.derive("variant").noise_seed()
                 ))
             );
 
+            let biome_map = Rc::new(BiomeMap::new(height_map.clone(), variant_map.clone()));
+
             let ground_entity = time!(
                 "terrain",
-                TerrainEntity::ground(height_map.as_ref(), variant_map.as_ref(), assets)
+                TerrainEntity::ground(
+                    height_map.as_ref(),
+                    variant_map.as_ref(),
+                    &biome_map,
+                    size,
+                    assets
+                )
             );
 
             let blueberry_bushes = time!(
                 "blueberry terrain",
                 TerrainEntity::bushes(
-                    &noise::Add::new(height_map.as_ref(), crate::terrain::bush_heights(rng.gen())),
-                    variant_map.as_ref(),
-                    assets
+                    &noise::Add::new(
+                        height_map.as_ref(),
+                        crate::terrain::bush_heights(root.derive("bush heights").noise_seed())
+                    ),
+                    &ground_entity,
+                    size,
+                    assets,
+                    1.0
                 )
             );
 
             // Accepting that the VAO is loaded anew
             let saplings = time!("saplings", {
                 ShrubEntitiesBuilder::new()
-                    .with_density(50.)
+                    .with_density(50. * density)
+                    // Otherwise some seeds get a third of the saplings of others
+                    .with_count_normalization(0.15)
                     .on_height_map(&height_map)
-                    .with_bushiness(ImageNoiseFnWrapper::new_green(assets.base_map.clone()))
+                    .with_terrain_size(size)
+                    .with_bushiness(
+                        ImageNoiseFnWrapper::new_green(assets.base_map.clone())
+                            .with_scene_size(size),
+                    )
                     .with_texture(assets.sapling_tex.clone())
+                    .with_transparency()
                     .with_model(assets.sapling_model.clone())
                     .with_shader(assets.foliage_shader.clone())
                     .with_z_scale_range(0.4, 1.2)
-                    .load(rng.gen())
+                    .with_wind_strength(0.05)
+                    .with_slope_alignment(0.5)
+                    .load(root.derive("saplings").noise_seed())
             });
 
             let bushes = time!("bushes", {
                 ShrubEntitiesBuilder::new()
-                    .with_density(30.)
+                    .with_density(30. * density)
                     .on_height_map(&height_map)
+                    .with_terrain_size(size)
+                    .on_biome_map(&biome_map)
+                    .with_biome(BiomeId::Meadow)
                     .with_texture(assets.bush_tex.clone())
+                    .with_transparency()
                     .with_model(assets.bush1_model.clone())
                     .with_shader(assets.foliage_shader.clone())
                     .with_z_scale_range(0.9, 1.0)
-                    .load(rng.gen())
+                    .with_wind_strength(0.03)
+                    .with_slope_alignment(0.5)
+                    .with_color_jitter(glm::vec3(0.02, 0.1, 0.1))
+                    .load(root.derive("bushes").noise_seed())
             });
 
-            let shrubs = time!("shrubs", {
+            let mut shrubs = time!("shrubs", {
                 ShrubEntitiesBuilder::new()
-                    .with_density(5.)
+                    .with_density(5. * density)
                     .on_height_map(&height_map)
-                    .with_texture(assets.shrub_side_tex.clone())
-                    .with_model(assets.shrub_model.clone())
+                    .with_terrain_size(size)
+                    .with_transparency()
+                    // Leafy bushes among the bare ones
+                    .with_textured_models(vec![
+                        (assets.shrub_model.clone(), assets.shrub_side_tex.clone()),
+                        (assets.bush1_model.clone(), assets.bush_tex.clone()),
+                    ])
                     .with_shader(assets.foliage_shader.clone())
-                    .with_bushiness(ImageNoiseFnWrapper::new_green(assets.base_map.clone()))
+                    .with_bushiness(
+                        ImageNoiseFnWrapper::new_green(assets.base_map.clone())
+                            .with_scene_size(size),
+                    )
                     .with_z_scale_range(0.7, 1.0)
                     .with_scale_range(1.5, 3.0)
-                    .load(rng.gen())
+                    .with_contact_shadow(0.5, 0.4, assets.decal_shader.clone())
+                    .with_wind_strength(0.02)
+                    .with_slope_alignment(0.5)
+                    .with_color_jitter(glm::vec3(0.03, 0.1, 0.15))
+                    .load(root.derive("shrubs").noise_seed())
             });
 
-            let trees = time!("trees", {
+            let mut trees = time!("trees", {
                 ShrubEntitiesBuilder::new()
-                    .with_density(1.)
-                    .with_entitiy_limit(60)
+                    .with_density(density)
+                    .with_entitiy_limit((60. * area_factor).round() as usize)
                     .on_height_map(&height_map)
-                    .with_bounds(0., 1.5 * SCENE_SIZE, 0., 1.5 * SCENE_SIZE)
+                    .with_terrain_size(size)
+                    .with_bounds(0., 1.5 * size, 0., 1.5 * size)
                     .with_texture(assets.bark_tex.clone())
                     .with_model(assets.tree_model.clone())
                     .with_shader(assets.foliage_shader.clone())
-                    .with_bushiness(ImageNoiseFnWrapper::new_blue(assets.base_map.clone()))
+                    .with_bushiness(
+                        ImageNoiseFnWrapper::new_blue(assets.base_map.clone())
+                            .with_scene_size(size),
+                    )
                     .with_scale_range(0.5, 1.0)
-                    .load(rng.gen())
+                    .with_contact_shadow(0.8, 0.6, assets.decal_shader.clone())
+                    .with_wind_strength(0.008)
+                    .with_slope_alignment(0.2)
+                    .with_color_jitter(glm::vec3(0.01, 0.05, 0.15))
+                    .load(root.derive("trees").noise_seed())
             });
 
+            // Cheap to generate, but the decals cost fill rate on every frame
+            if !assets.quality.shadows {
+                shrubs.contact_shadows = None;
+                trees.contact_shadows = None;
+            }
+
             let entities: Vec<Box<dyn Renderable>> = vec![
                 Box::new(trees),
                 Box::new(saplings),
                 Box::new(bushes),
                 Box::new(shrubs),
                 Box::new(blueberry_bushes),
-                Box::new(ground_entity),
+                Box::new(ground_entity.with_tiles(TerrainTile::mirrored_grid(
+                    size,
+                    variation.ground_tile_rings.max(0),
+                ))),
             ];
 
+            let palette = Palette::from_seed(seed);
+            let fog_density = Seed::from(seed)
+                .derive("fog")
+                .rng()
+                .gen_range(fog_density_range());
+            let sky_box = CubeTexture::from_gradient(SKY_BOX_SIZE, palette.sky, palette.zenith);
+
             Scene {
                 entities,
+                palette,
                 start_time: Instant::now(),
+                height_map,
+                size,
+                base_plane: None,
+                sky: None,
+                sky_box,
+                camera_angle,
+                look_angle,
+                fog_density,
             }
         })
     }
 
+    /// Adds a flat ground with the ground material at `height` below the
+    /// terrain, far past its edges, or removes it. Keeps the sky from showing
+    /// through the world where the camera sees below or past the terrain.
+    ///
+    /// [`Scene::min_height`] is a good height, slightly lowered so the plane
+    /// doesn't fight with the lowest terrain.
+    pub fn with_base_plane(mut self, enabled: bool, height: f32, assets: &Assets) -> Self {
+        self.set_base_plane(enabled, height, assets);
+        self
+    }
+
+    /// See [`Scene::with_base_plane`].
+    pub fn set_base_plane(&mut self, enabled: bool, height: f32, assets: &Assets) {
+        self.base_plane = enabled.then(|| -> Box<dyn Renderable> {
+            Box::new(TerrainEntity::base_plane(
+                height,
+                self.size,
+                BASE_PLANE_MARGIN,
+                assets,
+            ))
+        });
+    }
+
+    /// Draws the equirectangular image, like a panorama capture or an HDR
+    /// photo, around the scene instead of the plain sky color. The top row is
+    /// straight up and the center column looks along +x.
+    ///
+    /// For reflections of the same sky, load it as an environment with
+    /// [`crate::texture::CubeTexture::from_equirectangular`].
+    pub fn with_sky_image(
+        mut self,
+        path: impl AsRef<std::path::Path>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        self.sky = Some(Rc::new(Texture::from_file_srgb(path)?));
+        Ok(self)
+    }
+
+    /// The final height of the ground at a point, the same the terrain shader
+    /// displaces to (up to the resolution of its height texture). Everything
+    /// that sits on the ground should agree with this.
+    pub fn height_at(&self, x: f32, y: f32) -> f32 {
+        self.height_map.get([x as f64, y as f64]) as f32
+    }
+
+    /// The upwards surface normal of the ground at a point, from the same
+    /// finite differences as the terrain shader.
+    pub fn normal_at(&self, x: f32, y: f32) -> glm::Vec3 {
+        crate::generation::terrain_normal(self.height_map.as_ref(), x, y)
+    }
+
+    /// The lowest point of the terrain, sampled on a grid.
+    pub fn min_height(&self) -> f32 {
+        crate::generation::height_grid(self.height_map.as_ref(), (0., self.size, 0., self.size), 64)
+            .into_iter()
+            .fold(f32::INFINITY, f32::min)
+    }
+
+    /// The opaque sky color, see [`crate::renderer::Renderer::set_transparent_background`]
+    /// for windows that should show through.
     pub fn background_color(&self) -> (f32, f32, f32, f32) {
-        (186. / 255., 219. / 255., 222. / 255., 1.0) // A sky blue
+        let sky = self.palette.sky;
+        (sky.x, sky.y, sky.z, 1.0)
+    }
+
+    /// How quickly distant things fade into [`Palette::fog`], per meter. Some
+    /// scenes are hazier than others.
+    ///
+    /// Only applies in front of the generated sky. A sky image or an
+    /// environment drawn as the background has colors of its own, which the
+    /// fog wouldn't blend into, so it is left out there.
+    pub fn fog_density(&self) -> f32 {
+        self.fog_density
     }
 
     pub fn eye_position(&self) -> glm::Vec3 {
@@ -144,11 +410,25 @@ impl Scene {
         );
         // Stand in a corner of the scene the scene somewhat above the ground
         let base = glm::vec3(1.0, 1.0, 2.0);
-        base + bob
+        self.rotate_framing(base) + bob
     }
 
     pub fn look_at(&self) -> glm::Vec3 {
         // Look at the floor in the direction of the opposite corner
-        glm::vec3(3.5, 3.5, 0.3)
+        let target = self.rotate_framing(glm::vec3(3.5, 3.5, 0.3));
+        let eye = self.rotate_framing(glm::vec3(1.0, 1.0, 2.0));
+        eye + glm::rotate_z_vec3(&(target - eye), self.look_angle)
+    }
+
+    /// Turns a point of the default framing about the vertical axis through
+    /// the scene center, keeping its height above the ground.
+    fn rotate_framing(&self, point: glm::Vec3) -> glm::Vec3 {
+        if self.camera_angle == 0.0 {
+            return point;
+        }
+        let center = glm::vec3(self.size / 2.0, self.size / 2.0, 0.0);
+        let mut rotated = center + glm::rotate_z_vec3(&(point - center), self.camera_angle);
+        rotated.z += self.height_at(rotated.x, rotated.y) - self.height_at(point.x, point.y);
+        rotated
     }
 }
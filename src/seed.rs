@@ -0,0 +1,69 @@
+//! The seeding scheme shared by all generators.
+//!
+//! A scene seed is the root of a tree of seeds. Each generator derives its own
+//! branch by hashing a label into the seed of its parent, for example
+//! `root.derive("terrain").derive("rocks")`. Branches are independent of each
+//! other, so adding a generator or drawing more numbers in one of them never
+//! shifts the results of the others.
+//!
+//! Random streams are all [`StdRng`], which gives the same numbers on every
+//! platform, unlike `SmallRng`. Noise functions take the 32 bit
+//! [`Seed::noise_seed`] instead.
+
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Seed(u64);
+
+impl Seed {
+    pub fn new(seed: u64) -> Self {
+        Seed(seed)
+    }
+
+    /// The seed of the part named `label`. The same parent and label always
+    /// give the same seed, different labels unrelated ones.
+    pub fn derive(self, label: &str) -> Seed {
+        Seed(mix(mix(self.0) ^ fnv1a(label.as_bytes())))
+    }
+
+    /// Like [`Seed::derive`], for numbered parts like the cells of a grid.
+    pub fn derive_index(self, index: u64) -> Seed {
+        Seed(mix(mix(self.0) ^ index))
+    }
+
+    pub fn value(self) -> u64 {
+        self.0
+    }
+
+    /// The lower half, since the noise functions take 32 bit seeds. The
+    /// bits are well mixed, so that doesn't make neighbouring seeds collide.
+    pub fn noise_seed(self) -> u32 {
+        self.0 as u32
+    }
+
+    pub fn rng(self) -> StdRng {
+        StdRng::seed_from_u64(self.0)
+    }
+}
+
+impl From<u32> for Seed {
+    fn from(seed: u32) -> Self {
+        Seed(seed as u64)
+    }
+}
+
+/// Finalizer of SplitMix64, spreads similar inputs over the whole range.
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// FNV-1a, written out instead of [`std::hash::Hash`], since that isn't
+/// guaranteed to be stable across Rust versions, which would change the scenes.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
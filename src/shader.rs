@@ -3,13 +3,23 @@
 //! Can load and compile a shader from file.
 
 use gl::types::GLuint;
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::ffi::CStr;
 use std::marker::PhantomData;
+use std::path::PathBuf;
+use std::rc::Rc;
 use std::{ffi::CString, path::Path};
 use std::{ptr, str};
 
 pub struct Shader {
     program_id: GLuint,
+    /// Vertex inputs of the linked program, queried once after linking.
+    attributes: Vec<ActiveAttribute>,
+    /// Whether there is a tessellation stage, see [`Shader::has_tessellation`].
+    has_tessellation: bool,
+    /// Locations already looked up, see [`Shader::get_uniform_location`].
+    uniform_locations: RefCell<HashMap<String, i32>>,
 
     /// Mark the vao as !Send and !Sync, since OpenGL is not thread safe
     _marker: PhantomData<*const ()>,
@@ -18,12 +28,28 @@ pub struct Shader {
 pub struct ShaderBuilder {
     program_id: GLuint,
     shaders: Vec<GLuint>,
+    /// The files of the stages, for the link errors.
+    paths: Vec<String>,
+    has_tessellation: bool,
+    /// Injected into every following stage, see [`ShaderBuilder::with_define`].
+    defines: Vec<(String, String)>,
+    /// The first stage that failed to compile, returned by [`ShaderBuilder::link`].
+    error: Option<ShaderError>,
 
     /// Mark the vao as !Send and !Sync, since OpenGL is not thread safe
     _marker: PhantomData<*const ()>,
 }
 
-#[allow(dead_code)]
+/// A vertex shader input that survived the compiler's optimizations.
+#[derive(Clone, Debug)]
+pub struct ActiveAttribute {
+    pub name: String,
+    /// The first location, given by `layout(location = ...)`.
+    pub location: u32,
+    pub gl_type: gl::types::GLenum,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ShaderType {
     Vertex,
     Fragment,
@@ -32,25 +58,169 @@ pub enum ShaderType {
     Geometry,
 }
 
+/// Why a shader program couldn't be built, with the full log of the driver.
+#[derive(Debug)]
+pub enum ShaderError {
+    Compile {
+        path: String,
+        stage: ShaderType,
+        log: String,
+    },
+    Link {
+        /// The files of all stages.
+        paths: Vec<String>,
+        log: String,
+    },
+    /// An `#include` directive that couldn't be resolved, in the file `path`.
+    Include { path: String, reason: String },
+    /// A stage the context can't compile, see [`ShaderType::is_supported`].
+    /// Features built on it should fall back or be disabled.
+    Unsupported { path: String, stage: ShaderType },
+}
+
+impl std::fmt::Display for ShaderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ShaderError::Compile { path, stage, log } => {
+                write!(
+                    f,
+                    "failed to compile {} shader `{path}`:\n{log}",
+                    stage.name()
+                )
+            }
+            ShaderError::Link { paths, log } => {
+                write!(f, "failed to link {}:\n{log}", paths.join(", "))
+            }
+            ShaderError::Include { path, reason } => {
+                write!(f, "failed to resolve an include in `{path}`: {reason}")
+            }
+            ShaderError::Unsupported { path, stage } => {
+                write!(
+                    f,
+                    "`{path}` is a {} shader, which this OpenGL context doesn't support",
+                    stage.name()
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ShaderError {}
+
 impl Shader {
     /// Make sure the shader is active before calling this
+    ///
+    /// Only the first lookup of a name asks OpenGL, later ones are cached.
     pub fn get_uniform_location(&self, name: &str) -> i32 {
+        if let Some(&id) = self.uniform_locations.borrow().get(name) {
+            return id;
+        }
         let name_cstr = CString::new(name).expect("CString::new failed");
         let id = unsafe { gl::GetUniformLocation(self.program_id, name_cstr.as_ptr()) };
         if id == -1 {
             panic!("get_uniform_location: Uniform `{}` not found.", name);
         }
+        self.uniform_locations
+            .borrow_mut()
+            .insert(name.to_owned(), id);
         id
     }
 
     pub fn activate(&self) {
         unsafe { gl::UseProgram(self.program_id) };
     }
+
+    /// Programs with a tessellation stage can only draw patches, see
+    /// [`crate::mesh::ElementMeshVAO::render_patches`].
+    pub fn has_tessellation(&self) -> bool {
+        self.has_tessellation
+    }
+
+    pub fn attributes(&self) -> &[ActiveAttribute] {
+        &self.attributes
+    }
+
+    /// Prints a warning for every attribute location the shader reads, but
+    /// which is not enabled in the VAO. These read a constant instead, which
+    /// silently produces garbage geometry.
+    ///
+    /// Returns whether everything matched.
+    pub fn check_attributes(&self, enabled_locations: &[u32], vao_name: &str) -> bool {
+        let mut matches = true;
+        for attribute in &self.attributes {
+            let locations = attribute.location..attribute.location + attribute.columns();
+            for location in locations {
+                if !enabled_locations.contains(&location) {
+                    eprintln!(
+                        "Warning: shader expects `{}` at location {}, but {} doesn't provide it",
+                        attribute.name, location, vao_name
+                    );
+                    matches = false;
+                }
+            }
+        }
+        matches
+    }
+
+    unsafe fn query_attributes(program_id: GLuint) -> Vec<ActiveAttribute> {
+        let mut count = 0;
+        gl::GetProgramiv(program_id, gl::ACTIVE_ATTRIBUTES, &mut count);
+        let mut max_name_length = 0;
+        gl::GetProgramiv(
+            program_id,
+            gl::ACTIVE_ATTRIBUTE_MAX_LENGTH,
+            &mut max_name_length,
+        );
+
+        let mut attributes = Vec::with_capacity(count as usize);
+        for index in 0..count as u32 {
+            let mut name = vec![0u8; max_name_length.max(1) as usize];
+            let mut length = 0;
+            let mut size = 0;
+            let mut gl_type = 0;
+            gl::GetActiveAttrib(
+                program_id,
+                index,
+                max_name_length,
+                &mut length,
+                &mut size,
+                &mut gl_type,
+                name.as_mut_ptr() as *mut gl::types::GLchar,
+            );
+            name.truncate(length as usize);
+            let name = String::from_utf8_lossy(&name).into_owned();
+
+            let name_cstr = CString::new(name.as_str()).expect("CString::new failed");
+            let location = gl::GetAttribLocation(program_id, name_cstr.as_ptr());
+            // Builtins like gl_VertexID have no location
+            if location < 0 {
+                continue;
+            }
+            attributes.push(ActiveAttribute {
+                name,
+                location: location as u32,
+                gl_type,
+            });
+        }
+        attributes
+    }
 }
 
-impl Into<gl::types::GLenum> for ShaderType {
-    fn into(self) -> gl::types::GLenum {
-        match self {
+impl ActiveAttribute {
+    /// Matrices take up one location per column.
+    pub fn columns(&self) -> u32 {
+        match self.gl_type {
+            gl::FLOAT_MAT2 | gl::FLOAT_MAT3x2 | gl::FLOAT_MAT4x2 => 2,
+            gl::FLOAT_MAT3 | gl::FLOAT_MAT2x3 | gl::FLOAT_MAT4x3 => 3,
+            gl::FLOAT_MAT4 | gl::FLOAT_MAT2x4 | gl::FLOAT_MAT3x4 => 4,
+            _ => 1,
+        }
+    }
+}
+
+impl From<ShaderType> for gl::types::GLenum {
+    fn from(shader_type: ShaderType) -> Self {
+        match shader_type {
             ShaderType::Vertex => gl::VERTEX_SHADER,
             ShaderType::Fragment => gl::FRAGMENT_SHADER,
             ShaderType::TessellationControl => gl::TESS_CONTROL_SHADER,
@@ -61,6 +231,35 @@ impl Into<gl::types::GLenum> for ShaderType {
 }
 
 impl ShaderType {
+    /// Whether the current context can compile this stage. Geometry shaders
+    /// need OpenGL 3.2, tessellation 4.0 or the `GL_ARB_tessellation_shader`
+    /// extension.
+    pub fn is_supported(&self) -> bool {
+        let (mut major, mut minor) = (0, 0);
+        unsafe {
+            gl::GetIntegerv(gl::MAJOR_VERSION, &mut major);
+            gl::GetIntegerv(gl::MINOR_VERSION, &mut minor);
+        }
+        let version = (major, minor);
+        match self {
+            ShaderType::Vertex | ShaderType::Fragment => true,
+            ShaderType::Geometry => version >= (3, 2),
+            ShaderType::TessellationControl | ShaderType::TessellationEvaluation => {
+                version >= (4, 0) || has_extension("GL_ARB_tessellation_shader")
+            }
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ShaderType::Vertex => "vertex",
+            ShaderType::Fragment => "fragment",
+            ShaderType::TessellationControl => "tessellation control",
+            ShaderType::TessellationEvaluation => "tessellation evaluation",
+            ShaderType::Geometry => "geometry",
+        }
+    }
+
     fn from_ext(ext: &std::ffi::OsStr) -> Result<ShaderType, String> {
         match ext.to_str().expect("Failed to read extension") {
             "vert" => Ok(ShaderType::Vertex),
@@ -74,24 +273,70 @@ impl ShaderType {
 }
 
 impl ShaderBuilder {
+    // Not `Default`, since this already creates the program on the GPU
+    #[allow(clippy::new_without_default)]
     pub fn new() -> ShaderBuilder {
         ShaderBuilder {
             // Works if OpenGL has been properly set up.
             program_id: unsafe { gl::CreateProgram() },
             shaders: vec![],
+            paths: vec![],
+            has_tessellation: false,
+            defines: vec![],
+            error: None,
             _marker: PhantomData,
         }
     }
 
+    /// Adds `#define name value` right after the `#version` directive of the
+    /// stages added after this, to compile specialized variants of a shader.
+    /// Stages added before are already compiled and don't see it.
+    pub fn with_define(mut self, name: &str, value: &str) -> ShaderBuilder {
+        self.defines.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// Compiles the stage given by the extension of the file. Compile errors
+    /// are returned by [`ShaderBuilder::link`], after which the following
+    /// stages are skipped. So are stages the context doesn't support, see
+    /// [`ShaderError::Unsupported`].
+    ///
+    /// Lines like `#include "lib/lighting.glsl"` are replaced by that file,
+    /// relative to the including one. Each file is its own source string, so
+    /// the compiler errors give its number and line, listed below the log.
     pub fn with_shader_file(self, shader_path: &str) -> ShaderBuilder {
+        if self.error.is_some() {
+            return self;
+        }
         let path = Path::new(shader_path);
         if let Some(extension) = path.extension() {
             let shader_type =
                 ShaderType::from_ext(extension).expect("Failed to parse file extension.");
+            // Fails clearly here, instead of with whatever the compiler makes of it
+            if !shader_type.is_supported() {
+                return self.unsupported(shader_path, shader_type);
+            }
             let shader_src = std::fs::read_to_string(path)
-                .expect(&format!("Failed to read shader source `{}`", shader_path));
-            self.with_shader(&shader_src, shader_type)
-                .expect(&format!("Failed to compile shader `{}`", shader_path))
+                .unwrap_or_else(|_| panic!("Failed to read shader source `{}`", shader_path));
+            let mut sources = vec![path.to_owned()];
+            match resolve_includes(&shader_src, &mut sources, &mut vec![path.to_owned()]) {
+                Ok(shader_src) => {
+                    let mut builder = self.with_shader(&shader_src, shader_type, shader_path);
+                    if let Some(ShaderError::Compile { log, .. }) = &mut builder.error {
+                        if sources.len() > 1 {
+                            log.push_str("Source strings:\n");
+                            for (index, source) in sources.iter().enumerate() {
+                                log.push_str(&format!("{index}: {}\n", source.display()));
+                            }
+                        }
+                    }
+                    builder
+                }
+                Err(error) => ShaderBuilder {
+                    error: Some(error),
+                    ..self
+                },
+            }
         } else {
             panic!(
                 "Failed to read extension of file with path: {}",
@@ -100,97 +345,281 @@ impl ShaderBuilder {
         }
     }
 
+    /// Like [`ShaderBuilder::with_shader_file`], for sources that are already
+    /// in memory, e.g. embedded with `include_str!`. There is no file to
+    /// resolve `#include` directives against, so they are left to the
+    /// compiler.
+    pub fn with_shader_source(self, shader_src: &str, shader_type: ShaderType) -> ShaderBuilder {
+        if self.error.is_some() {
+            return self;
+        }
+        let name = format!("<{} shader source>", shader_type.name());
+        if !shader_type.is_supported() {
+            return self.unsupported(&name, shader_type);
+        }
+        self.with_shader(shader_src, shader_type, &name)
+    }
+
+    fn unsupported(self, path: &str, stage: ShaderType) -> ShaderBuilder {
+        ShaderBuilder {
+            error: Some(ShaderError::Unsupported {
+                path: path.to_owned(),
+                stage,
+            }),
+            ..self
+        }
+    }
+
     fn with_shader(
         mut self,
         shader_src: &str,
         shader_type: ShaderType,
-    ) -> Result<ShaderBuilder, ()> {
+        path: &str,
+    ) -> ShaderBuilder {
+        self.has_tessellation |= matches!(
+            shader_type,
+            ShaderType::TessellationControl | ShaderType::TessellationEvaluation
+        );
+        let shader_src = inject_defines(shader_src, &self.defines);
         let shader = unsafe {
             let shader = gl::CreateShader(shader_type.into());
             let c_str_shader = CString::new(shader_src.as_bytes()).unwrap();
             gl::ShaderSource(shader, 1, &c_str_shader.as_ptr(), ptr::null());
             gl::CompileShader(shader);
-
-            if !self.check_shader_errors(shader) {
-                return Err(());
-            }
             shader
         };
 
+        self.paths.push(path.to_owned());
         self.shaders.push(shader);
-
-        Ok(self)
-    }
-
-    unsafe fn check_shader_errors(&self, shader_id: u32) -> bool {
-        let mut success = i32::from(gl::FALSE);
-        let mut info_log = Vec::with_capacity(512);
-        info_log.set_len(512 - 1);
-        gl::GetShaderiv(shader_id, gl::COMPILE_STATUS, &mut success);
-        if success != i32::from(gl::TRUE) {
-            gl::GetShaderInfoLog(
-                shader_id,
-                512,
-                ptr::null_mut(),
-                info_log.as_mut_ptr() as *mut gl::types::GLchar,
-            );
-            println!("ERROR:: Shader Compilation Failed!");
-            println!(
-                "{}",
-                CStr::from_bytes_until_nul(&info_log)
-                    .expect("Shader error was too long")
-                    .to_string_lossy()
-            );
-            return false;
-        }
-        true
-    }
-
-    unsafe fn check_linker_errors(&self) -> bool {
-        let mut success = i32::from(gl::FALSE);
-        let mut info_log = Vec::with_capacity(512);
-        info_log.set_len(512 - 1);
-        gl::GetProgramiv(self.program_id, gl::LINK_STATUS, &mut success);
-        if success != i32::from(gl::TRUE) {
-            gl::GetProgramInfoLog(
-                self.program_id,
-                512,
-                ptr::null_mut(),
-                info_log.as_mut_ptr() as *mut gl::types::GLchar,
-            );
-            println!(
-                "ERROR::SHADER::PROGRAM::COMPILATION_FAILED\n{}",
-                String::from_utf8_lossy(&info_log)
-            );
-            return false;
+        if let Some(log) = unsafe { compile_errors(shader) } {
+            self.error = Some(ShaderError::Compile {
+                path: path.to_owned(),
+                stage: shader_type,
+                log,
+            });
         }
-        true
+
+        self
     }
 
     #[must_use = "The shader program is useless if not stored in a variable."]
-    pub fn link(self) -> Result<Shader, ()> {
-        unsafe {
-            for &shader in &self.shaders {
-                gl::AttachShader(self.program_id, shader);
-            }
-            gl::LinkProgram(self.program_id);
-
-            if !self.check_linker_errors() {
-                return Err(());
+    pub fn link(mut self) -> Result<Shader, ShaderError> {
+        let error = unsafe {
+            match self.error.take() {
+                Some(error) => Some(error),
+                None => {
+                    for &shader in &self.shaders {
+                        gl::AttachShader(self.program_id, shader);
+                    }
+                    gl::LinkProgram(self.program_id);
+                    link_errors(self.program_id).map(|log| ShaderError::Link {
+                        paths: self.paths.clone(),
+                        log,
+                    })
+                }
             }
+        };
 
+        unsafe {
             for &shader in &self.shaders {
                 gl::DeleteShader(shader);
             }
+            if let Some(error) = error {
+                gl::DeleteProgram(self.program_id);
+                return Err(error);
+            }
         }
 
         Ok(Shader {
             program_id: self.program_id,
+            attributes: unsafe { Shader::query_attributes(self.program_id) },
+            has_tessellation: self.has_tessellation,
+            uniform_locations: RefCell::new(HashMap::new()),
             _marker: PhantomData,
         })
     }
 }
 
+/// The info log if the shader failed to compile.
+unsafe fn compile_errors(shader_id: GLuint) -> Option<String> {
+    let mut success = i32::from(gl::FALSE);
+    gl::GetShaderiv(shader_id, gl::COMPILE_STATUS, &mut success);
+    if success == i32::from(gl::TRUE) {
+        return None;
+    }
+    let mut length = 0;
+    gl::GetShaderiv(shader_id, gl::INFO_LOG_LENGTH, &mut length);
+    let mut info_log = vec![0u8; length.max(1) as usize];
+    let mut written = 0;
+    gl::GetShaderInfoLog(
+        shader_id,
+        info_log.len() as i32,
+        &mut written,
+        info_log.as_mut_ptr() as *mut gl::types::GLchar,
+    );
+    info_log.truncate(written as usize);
+    Some(String::from_utf8_lossy(&info_log).into_owned())
+}
+
+/// The info log if the program failed to link.
+unsafe fn link_errors(program_id: GLuint) -> Option<String> {
+    let mut success = i32::from(gl::FALSE);
+    gl::GetProgramiv(program_id, gl::LINK_STATUS, &mut success);
+    if success == i32::from(gl::TRUE) {
+        return None;
+    }
+    let mut length = 0;
+    gl::GetProgramiv(program_id, gl::INFO_LOG_LENGTH, &mut length);
+    let mut info_log = vec![0u8; length.max(1) as usize];
+    let mut written = 0;
+    gl::GetProgramInfoLog(
+        program_id,
+        info_log.len() as i32,
+        &mut written,
+        info_log.as_mut_ptr() as *mut gl::types::GLchar,
+    );
+    info_log.truncate(written as usize);
+    Some(String::from_utf8_lossy(&info_log).into_owned())
+}
+
+/// Whether the current context lists the extension.
+pub(crate) fn has_extension(name: &str) -> bool {
+    let mut count = 0;
+    unsafe { gl::GetIntegerv(gl::NUM_EXTENSIONS, &mut count) };
+    (0..count.max(0) as u32).any(|index| {
+        let extension = unsafe { gl::GetStringi(gl::EXTENSIONS, index) };
+        // SAFETY: a valid index gives a static, null terminated string
+        !extension.is_null()
+            && unsafe { CStr::from_ptr(extension as *const _) }.to_bytes() == name.as_bytes()
+    })
+}
+
+/// Replaces the `#include` lines in the `source` of the last file of `stack`,
+/// recursively. The included files are numbered by their index in `sources`
+/// with `#line` directives, and the numbering of the including file continues
+/// afterwards.
+fn resolve_includes(
+    source: &str,
+    sources: &mut Vec<PathBuf>,
+    stack: &mut Vec<PathBuf>,
+) -> Result<String, ShaderError> {
+    let current = stack
+        .last()
+        .expect("The including file is on the stack")
+        .clone();
+    let current_index = sources
+        .iter()
+        .position(|path| *path == current)
+        .expect("The including file is numbered");
+    let error = |reason: String| ShaderError::Include {
+        path: current.display().to_string(),
+        reason,
+    };
+
+    let mut result = String::with_capacity(source.len());
+    for (line_index, line) in source.lines().enumerate() {
+        let Some(argument) = line.trim_start().strip_prefix("#include") else {
+            result.push_str(line);
+            result.push('\n');
+            continue;
+        };
+        let name = argument
+            .trim()
+            .strip_prefix('"')
+            .and_then(|rest| rest.strip_suffix('"'))
+            .ok_or_else(|| error(format!("expected a quoted path in `{}`", line.trim())))?;
+        let path = current.parent().unwrap_or(Path::new("")).join(name);
+        // Compared canonically, since `a/../a.glsl` is `a.glsl` as well
+        let canonical = |path: &Path| path.canonicalize().unwrap_or_else(|_| path.to_owned());
+        if stack.iter().any(|open| canonical(open) == canonical(&path)) {
+            let chain: Vec<String> = stack
+                .iter()
+                .chain([&path])
+                .map(|path| path.display().to_string())
+                .collect();
+            return Err(error(format!("include cycle {}", chain.join(" -> "))));
+        }
+        let included = std::fs::read_to_string(&path)
+            .map_err(|err| error(format!("can't read `{}`: {err}", path.display())))?;
+
+        sources.push(path.clone());
+        result.push_str(&format!("#line 1 {}\n", sources.len() - 1));
+        stack.push(path);
+        result.push_str(&resolve_includes(&included, sources, stack)?);
+        stack.pop();
+        // The line after the directive, which counts from one
+        result.push_str(&format!("#line {} {current_index}\n", line_index + 2));
+    }
+    Ok(result)
+}
+
+/// Inserts the defines after the `#version` line, which must stay first.
+/// A `#line` directive afterwards keeps the line numbers in compiler errors
+/// matching the file.
+fn inject_defines(source: &str, defines: &[(String, String)]) -> String {
+    if defines.is_empty() {
+        return source.to_owned();
+    }
+    let (version, rest, next_line) = match source.split_once('\n') {
+        Some((first, rest)) if first.trim_start().starts_with("#version") => {
+            (format!("{first}\n"), rest, 2)
+        }
+        _ => (String::new(), source, 1),
+    };
+    let mut result = version;
+    for (name, value) in defines {
+        result.push_str(&format!("#define {name} {value}\n"));
+    }
+    result.push_str(&format!("#line {next_line}\n"));
+    result.push_str(rest);
+    result
+}
+
+/// Compiled variants of the same shader files, one per set of defines.
+///
+/// Compiling is slow, so each variant is only built the first time it is
+/// asked for, and shared afterwards.
+pub struct ShaderVariants {
+    files: Vec<String>,
+    variants: HashMap<Vec<(String, String)>, Rc<Shader>>,
+}
+
+impl ShaderVariants {
+    pub fn new(files: &[&str]) -> Self {
+        ShaderVariants {
+            files: files.iter().map(|&file| file.to_owned()).collect(),
+            variants: HashMap::new(),
+        }
+    }
+
+    /// The variant with the `defines`, regardless of their order. Panics if it
+    /// fails to compile.
+    pub fn get(&mut self, defines: &[(&str, &str)]) -> Rc<Shader> {
+        let mut key: Vec<(String, String)> = defines
+            .iter()
+            .map(|&(name, value)| (name.to_owned(), value.to_owned()))
+            .collect();
+        key.sort();
+        let files = &self.files;
+        self.variants
+            .entry(key)
+            .or_insert_with_key(|key| {
+                let builder = key
+                    .iter()
+                    .fold(ShaderBuilder::new(), |builder, (name, value)| {
+                        builder.with_define(name, value)
+                    });
+                let shader = files
+                    .iter()
+                    .fold(builder, |builder, file| builder.with_shader_file(file))
+                    .link()
+                    .unwrap_or_else(|err| panic!("Failed to build the variant {key:?}: {err}"));
+                Rc::new(shader)
+            })
+            .clone()
+    }
+}
+
 impl Drop for Shader {
     fn drop(&mut self) {
         unsafe {
@@ -201,3 +630,112 @@ impl Drop for Shader {
 // Shouldn't implement Drop for ShaderBuilder, as otherwise the
 // construction of Shader causes the deletion of the program...
 // Might have taken me 30' to figure out.
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh directory with the `files`, removed again when dropped.
+    struct ShaderDir(PathBuf);
+
+    impl ShaderDir {
+        fn new(name: &str, files: &[(&str, &str)]) -> Self {
+            let dir = std::env::temp_dir()
+                .join(format!("undergrowth-shaders-{name}-{}", std::process::id()));
+            for (file, source) in files {
+                let path = dir.join(file);
+                std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                std::fs::write(path, source).unwrap();
+            }
+            ShaderDir(dir)
+        }
+
+        fn resolve(&self, file: &str) -> Result<String, ShaderError> {
+            let path = self.0.join(file);
+            let source = std::fs::read_to_string(&path).unwrap();
+            resolve_includes(&source, &mut vec![path.clone()], &mut vec![path])
+        }
+    }
+
+    impl Drop for ShaderDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn include_reason(result: Result<String, ShaderError>) -> String {
+        match result {
+            Err(ShaderError::Include { reason, .. }) => reason,
+            other => panic!("Expected an include error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn nested_includes_are_numbered_by_file() {
+        let dir = ShaderDir::new(
+            "nested",
+            &[
+                (
+                    "main.frag",
+                    "#version 410 core\n#include \"lib/a.glsl\"\nvoid main() {}\n",
+                ),
+                ("lib/a.glsl", "float a;\n#include \"b.glsl\"\nfloat c;\n"),
+                ("lib/b.glsl", "float b;\n"),
+            ],
+        );
+        let expected = "#version 410 core\n\
+            #line 1 1\n\
+            float a;\n\
+            #line 1 2\n\
+            float b;\n\
+            #line 3 1\n\
+            float c;\n\
+            #line 3 0\n\
+            void main() {}\n";
+        assert_eq!(dir.resolve("main.frag").unwrap(), expected);
+    }
+
+    #[test]
+    fn include_cycle_reports_the_chain() {
+        let dir = ShaderDir::new(
+            "cycle",
+            &[
+                ("a.glsl", "#include \"b.glsl\"\n"),
+                ("b.glsl", "#include \"a.glsl\"\n"),
+            ],
+        );
+        let reason = include_reason(dir.resolve("a.glsl"));
+        let [a, b] = ["a.glsl", "b.glsl"].map(|file| dir.0.join(file).display().to_string());
+        assert_eq!(reason, format!("include cycle {a} -> {b} -> {a}"));
+    }
+
+    #[test]
+    fn missing_include_names_the_file() {
+        let dir = ShaderDir::new("missing", &[("main.frag", "#include \"gone.glsl\"\n")]);
+        let reason = include_reason(dir.resolve("main.frag"));
+        let gone = dir.0.join("gone.glsl").display().to_string();
+        assert!(
+            reason.starts_with(&format!("can't read `{gone}`")),
+            "{reason}"
+        );
+    }
+
+    #[test]
+    fn defines_after_the_version_keep_the_line_numbers() {
+        let defines = [
+            ("WIND".to_owned(), "1".to_owned()),
+            ("LOD".to_owned(), "2".to_owned()),
+        ];
+        let source = "#version 410 core\nvoid main() {}\n";
+        assert_eq!(
+            inject_defines(source, &defines),
+            "#version 410 core\n#define WIND 1\n#define LOD 2\n#line 2\nvoid main() {}\n"
+        );
+        // Without a version, the first line of the file is still line one
+        assert_eq!(
+            inject_defines("void main() {}\n", &defines[..1]),
+            "#define WIND 1\n#line 1\nvoid main() {}\n"
+        );
+        assert_eq!(inject_defines(source, &[]), source);
+    }
+}
@@ -0,0 +1,75 @@
+//! The background behind the scene, drawn from an environment cube map or an
+//! equirectangular sky image.
+
+use std::rc::Rc;
+
+use nalgebra_glm as glm;
+
+use crate::framebuffer::FullscreenQuad;
+use crate::shader::{Shader, ShaderVariants};
+use crate::texture::{CubeTexture, Texture};
+
+pub(crate) struct Sky {
+    shader: Rc<Shader>,
+    /// The same files, compiled with `EQUIRECTANGULAR` defined.
+    equirectangular_shader: Rc<Shader>,
+    quad: FullscreenQuad,
+}
+
+impl Sky {
+    pub fn new() -> Self {
+        let mut variants = ShaderVariants::new(&["shaders/fullscreen.vert", "shaders/sky.frag"]);
+        Sky {
+            shader: variants.get(&[]),
+            equirectangular_shader: variants.get(&[("EQUIRECTANGULAR", "1")]),
+            quad: FullscreenQuad::new(),
+        }
+    }
+
+    /// Fills the bound framebuffer with the environment as seen through
+    /// `view_proj`, without touching the depth buffer.
+    pub fn render(&self, environment: &CubeTexture, view_proj: &glm::Mat4) {
+        unsafe {
+            self.shader.activate();
+            environment.activate(0);
+            gl::Uniform1i(self.shader.get_uniform_location("environment"), 0);
+        }
+        self.draw(&self.shader, view_proj);
+    }
+
+    /// Like [`Sky::render`], for an equirectangular image with the top row
+    /// first, laid out like [`crate::renderer::Renderer::capture_panorama`].
+    pub fn render_equirectangular(&self, image: &Texture, view_proj: &glm::Mat4) {
+        let shader = &self.equirectangular_shader;
+        unsafe {
+            shader.activate();
+            image.activate(0);
+            gl::Uniform1i(shader.get_uniform_location("sky_image"), 0);
+        }
+        self.draw(shader, view_proj);
+    }
+
+    /// Draws the quad with the activated `shader`.
+    fn draw(&self, shader: &Shader, view_proj: &glm::Mat4) {
+        let inverse_view_proj = glm::inverse(view_proj);
+        // The quad would vanish with clockwise front faces
+        let culling = unsafe { gl::IsEnabled(gl::CULL_FACE) } == gl::TRUE;
+        unsafe {
+            gl::Disable(gl::DEPTH_TEST);
+            gl::Disable(gl::CULL_FACE);
+            gl::UniformMatrix4fv(
+                shader.get_uniform_location("inverse_view_proj"),
+                1,
+                gl::FALSE,
+                inverse_view_proj.as_ptr(),
+            );
+        }
+        self.quad.render();
+        unsafe {
+            gl::Enable(gl::DEPTH_TEST);
+            if culling {
+                gl::Enable(gl::CULL_FACE);
+            }
+        }
+    }
+}
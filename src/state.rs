@@ -0,0 +1,64 @@
+//! Snapshots of the renderer settings, written as RON to attach to bug
+//! reports and reproduce the exact frame from.
+
+use std::error::Error;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::camera::{Camera, CameraMode};
+use crate::lighting::DirectionalLight;
+use crate::quality::QualityPreset;
+use crate::renderer::{DebugView, ProjectionMode, Winding};
+use crate::scene::SceneVariation;
+
+/// The seed and every tunable of a [`crate::renderer::Renderer`], see
+/// [`crate::renderer::Renderer::dump_state`].
+///
+/// Only what fits into a few numbers is included. Edits to the base map, sky
+/// images and environment maps have to be attached separately.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RendererState {
+    /// Version of the crate that wrote the state. Others might generate
+    /// different scenes from the same seed.
+    pub version: String,
+    pub seed: u32,
+    /// Seconds since the scene was created, which the camera bob and the
+    /// wind depend on.
+    pub time: f32,
+    /// Side length of the scene in meters. Follows from the seed and the
+    /// variation, kept to notice when it comes out differently.
+    pub scene_size: f32,
+    pub variation: SceneVariation,
+    pub density_multiplier: f32,
+    pub projection: ProjectionMode,
+    pub camera: Camera,
+    #[serde(default)]
+    pub camera_mode: CameraMode,
+    /// `None` for the defaults of the scene palette.
+    pub lights: Option<Vec<DirectionalLight>>,
+    pub bloom_threshold: f32,
+    pub bloom_intensity: f32,
+    pub terrain_only: bool,
+    pub base_plane: bool,
+    pub transparent_background: bool,
+    /// Front face winding, if back faces are culled.
+    pub backface_culling: Option<Winding>,
+    pub debug_view: DebugView,
+    /// Missing in states saved before the presets existed.
+    #[serde(default)]
+    pub quality: QualityPreset,
+}
+
+impl RendererState {
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let ron = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())?;
+        std::fs::write(path, ron)?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let ron = std::fs::read_to_string(path)?;
+        Ok(ron::from_str(&ron)?)
+    }
+}
@@ -1,11 +1,13 @@
 use nalgebra_glm as glm;
-use rand::{Rng, SeedableRng};
 use std::rc::Rc;
 
 use crate::assets::{Assets, ImageNoiseFnWrapper};
+use crate::biome::{BiomeId, BiomeMap};
+use crate::lighting::set_light_uniforms;
 use crate::mesh::ElementMeshVAO;
-use crate::renderer::Renderable;
+use crate::renderer::{RenderContext, Renderable};
 use crate::scene::SCENE_SIZE;
+use crate::seed::Seed;
 use crate::shader::Shader;
 use crate::texture::Texture;
 
@@ -16,6 +18,8 @@ pub struct TerrainEntity {
     pub vao: Rc<ElementMeshVAO>,
     pub displacement: Rc<Texture>,
     pub variant: Rc<Texture>,
+    /// Weight of the rocky biome, which covers the top view with rock.
+    pub rocky: Rc<Texture>,
     pub albedo_xy1: Rc<Texture>,
     pub albedo_xy2: Rc<Texture>,
     pub albedo_xz: Rc<Texture>,
@@ -24,71 +28,434 @@ pub struct TerrainEntity {
     pub model: glm::Mat4,
     /// A matrix that will right multiply a world coordinate into a uv coordinate.
     pub world_to_uv: glm::Mat3,
+    /// Depth bias towards the camera, as factor and units of `glPolygonOffset`.
+    /// Layers drawn on top of another terrain need this to avoid z-fighting.
+    pub polygon_offset: f32,
+    /// Strength of the highlights on rock and wet ground, zero for a matte surface.
+    pub specular: f32,
+    /// Between 0 (mirror like) and 1 (matte), of the dry glossy parts.
+    pub roughness: f32,
+    /// Copies of the terrain drawn with instancing, just the original by default.
+    /// At most [`MAX_TILES`] are drawn.
+    pub tiles: Vec<TerrainTile>,
+    /// Layers with cut out parts, like leaves, blend their edges onto the
+    /// opaque ones, see [`crate::renderer::Renderable::is_transparent`].
+    pub transparent: bool,
 }
 
-impl TerrainEntity {
-    pub fn ground(
-        height_fn: &(impl NoiseFn<f64, 2> + ?Sized),
-        variant_fn: &(impl NoiseFn<f64, 2> + ?Sized),
-        assets: &Assets,
-    ) -> Self {
-        let model = glm::scale(&glm::identity(), &glm::vec3(SCENE_SIZE, SCENE_SIZE, 1.0));
-        let height_tex = Texture::from_noise(height_fn, (0., SCENE_SIZE, 0., SCENE_SIZE), 256);
-        let variant_tex = Texture::from_noise(variant_fn, (0., SCENE_SIZE, 0., SCENE_SIZE), 256);
+/// Subtle by default, so the ground mostly keeps its diffuse look.
+const DEFAULT_SPECULAR: f32 = 0.15;
+const DEFAULT_ROUGHNESS: f32 = 0.5;
+
+/// Must match `MAX_TILES` in `shaders/terrain.vert`.
+pub const MAX_TILES: usize = 49;
+
+/// A copy of the terrain, moved in the XY plane and optionally mirrored.
+///
+/// The maps are sampled where the original terrain is, so the copies cost no
+/// generation, but repeat visibly.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TerrainTile {
+    pub offset: glm::Vec2,
+    pub mirror_x: bool,
+    pub mirror_y: bool,
+}
+
+impl TerrainTile {
+    pub const ORIGINAL: TerrainTile = TerrainTile {
+        offset: glm::Vec2::new(0.0, 0.0),
+        mirror_x: false,
+        mirror_y: false,
+    };
+
+    /// The original terrain of `size` meters surrounded by `rings` rings of
+    /// copies. Every other copy is mirrored, so the edges of neighbors match
+    /// and there are no cracks in between.
+    pub fn mirrored_grid(size: f32, rings: i32) -> Vec<TerrainTile> {
+        let axis = |index: i32| {
+            // Mirroring odd copies at their far edge lines them up with the even ones
+            let mirrored = index.rem_euclid(2) == 1;
+            let offset = if mirrored { index + 1 } else { index } as f32 * size;
+            (offset, mirrored)
+        };
+        (-rings..=rings)
+            .flat_map(|x| (-rings..=rings).map(move |y| (x, y)))
+            .map(|(x, y)| {
+                let ((offset_x, mirror_x), (offset_y, mirror_y)) = (axis(x), axis(y));
+                TerrainTile {
+                    offset: glm::vec2(offset_x, offset_y),
+                    mirror_x,
+                    mirror_y,
+                }
+            })
+            .collect()
+    }
+
+    /// The transformation from the original terrain onto this copy.
+    pub fn transform(&self) -> glm::Mat4 {
+        let sign = |mirror| if mirror { -1.0 } else { 1.0 };
+        glm::translation(&glm::vec3(self.offset.x, self.offset.y, 0.0))
+            * glm::scaling(&glm::vec3(sign(self.mirror_x), sign(self.mirror_y), 1.0))
+    }
+
+    /// Mirrored along a single axis, the triangles of the copy turn around, so
+    /// its front faces have the opposite winding.
+    pub fn reverses_winding(&self) -> bool {
+        self.mirror_x != self.mirror_y
+    }
+
+    /// Offset and mirror signs packed for the shader.
+    fn packed(&self) -> glm::Vec4 {
+        let transform = self.transform();
+        glm::vec4(
+            self.offset.x,
+            self.offset.y,
+            transform[(0, 0)],
+            transform[(1, 1)],
+        )
+    }
+}
+
+/// The textures the terrain shader blends between.
+#[derive(Clone)]
+pub struct TerrainMaterials {
+    /// Seen from the top, where the variant map is low.
+    pub xy1: Rc<Texture>,
+    /// Seen from the top, where the variant map is high.
+    pub xy2: Rc<Texture>,
+    pub xz: Rc<Texture>,
+    pub yz: Rc<Texture>,
+}
+
+impl TerrainMaterials {
+    /// Moss and forest floor on top, rock on the slopes.
+    pub fn ground(assets: &Assets) -> Self {
+        TerrainMaterials {
+            xy1: assets.moss_tex.clone(),
+            xy2: assets.ground_tex.clone(),
+            xz: assets.rock_tex.clone(),
+            yz: assets.rock_tex.clone(),
+        }
+    }
+
+    /// Bush leaves in patches, transparent everywhere else.
+    pub fn bushes(assets: &Assets) -> Self {
+        TerrainMaterials {
+            xy1: assets.transparent_tex.clone(),
+            xy2: assets.bush_tex.clone(),
+            xz: assets.transparent_tex.clone(),
+            yz: assets.transparent_tex.clone(),
+        }
+    }
+}
+
+pub struct TerrainEntityBuilder<'a> {
+    height_fn: Option<&'a dyn NoiseFn<f64, 2>>,
+    variant_fn: Option<&'a dyn NoiseFn<f64, 2>>,
+    /// Replaces sampling the `variant_fn`.
+    variant_texture: Option<Rc<Texture>>,
+    /// Without one, no part of the terrain is rocky.
+    biome_map: Option<&'a BiomeMap>,
+    /// Replaces sampling the `biome_map`.
+    rocky_texture: Option<Rc<Texture>>,
+    size: f32,
+    displacement_scale: f32,
+    materials: Option<TerrainMaterials>,
+    polygon_offset: f32,
+    specular: f32,
+    roughness: f32,
+    tiles: Vec<TerrainTile>,
+    transparent: bool,
+}
+
+impl Default for TerrainEntityBuilder<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> TerrainEntityBuilder<'a> {
+    pub fn new() -> Self {
+        TerrainEntityBuilder {
+            height_fn: None,
+            variant_fn: None,
+            variant_texture: None,
+            biome_map: None,
+            rocky_texture: None,
+            size: SCENE_SIZE,
+            displacement_scale: 1.0,
+            materials: None,
+            polygon_offset: 0.0,
+            specular: DEFAULT_SPECULAR,
+            roughness: DEFAULT_ROUGHNESS,
+            tiles: vec![TerrainTile::ORIGINAL],
+            transparent: false,
+        }
+    }
+
+    pub fn build(self, assets: &Assets) -> TerrainEntity {
+        let height_fn = self.height_fn.expect("Height function is required");
+        let materials = self.materials.expect("Materials are required");
+
+        let size = self.size;
+        let model = glm::scale(&glm::identity(), &glm::vec3(size, size, 1.0));
+        let bounds = (0., size, 0., size);
+        let resolution = map_resolution(size, assets.quality.noise_texture_resolution);
+        let height_tex = Texture::from_noise(
+            ScaleBias::new(height_fn).set_scale(self.displacement_scale as f64),
+            bounds,
+            resolution,
+        );
+        let variant_tex = self.variant_texture.unwrap_or_else(|| {
+            let variant_fn = self.variant_fn.expect("Variant function is required");
+            Rc::new(Texture::from_noise(variant_fn, bounds, resolution))
+        });
+        let rocky_tex = self.rocky_texture.unwrap_or_else(|| {
+            Rc::new(match self.biome_map {
+                Some(biome_map) => {
+                    Texture::from_noise(biome_map.mask(BiomeId::Rocky), bounds, resolution)
+                }
+                None => Texture::from_noise(noise::Constant::new(0.0), bounds, 1),
+            })
+        });
 
         TerrainEntity {
             vao: assets.terrain_quad_mesh.clone(),
             displacement: Rc::new(height_tex),
-            variant: Rc::new(variant_tex),
-            albedo_xy1: assets.moss_tex.clone(),
-            albedo_xy2: assets.ground_tex.clone(),
-            albedo_xz: assets.rock_tex.clone(),
-            albedo_yz: assets.rock_tex.clone(),
+            variant: variant_tex,
+            rocky: rocky_tex,
+            albedo_xy1: materials.xy1,
+            albedo_xy2: materials.xy2,
+            albedo_xz: materials.xz,
+            albedo_yz: materials.yz,
             model,
-            world_to_uv: glm::scale2d(
-                &glm::identity(),
-                &glm::vec2(1.0 / SCENE_SIZE, 1.0 / SCENE_SIZE),
-            ),
+            world_to_uv: glm::scale2d(&glm::identity(), &glm::vec2(1.0 / size, 1.0 / size)),
             shader: assets.terrain_shader.clone(),
+            polygon_offset: self.polygon_offset,
+            specular: self.specular,
+            roughness: self.roughness,
+            tiles: self.tiles,
+            transparent: self.transparent,
         }
     }
 
+    /// The height of the terrain in meters, sampled in world coordinates.
+    pub fn with_height_fn(mut self, height_fn: &'a dyn NoiseFn<f64, 2>) -> Self {
+        self.height_fn = Some(height_fn);
+        self
+    }
+
+    /// Between 0 and 1, blends between the two top materials.
+    pub fn with_variant_fn(mut self, variant_fn: &'a dyn NoiseFn<f64, 2>) -> Self {
+        self.variant_fn = Some(variant_fn);
+        self
+    }
+
+    /// Uses an already sampled variant map instead of a variant function, like
+    /// the one of another layer over the same area.
+    pub fn with_variant_texture(mut self, variant: Rc<Texture>) -> Self {
+        self.variant_texture = Some(variant);
+        self
+    }
+
+    /// Uses an already sampled weight of the rocky biome instead of a biome map.
+    pub fn with_rocky_texture(mut self, rocky: Rc<Texture>) -> Self {
+        self.rocky_texture = Some(rocky);
+        self
+    }
+
+    /// Covers the rocky biome with rock from the top as well.
+    pub fn with_biome_map(mut self, biome_map: &'a BiomeMap) -> Self {
+        self.biome_map = Some(biome_map);
+        self
+    }
+
+    /// The terrain covers (0, 0) to (`size`, `size`).
+    pub fn with_size(mut self, size: f32) -> Self {
+        self.size = size;
+        self
+    }
+
+    /// Multiplies the heights. Only the rendered terrain is scaled, not the
+    /// height maps the foliage is placed on.
+    pub fn with_displacement_scale(mut self, scale: f32) -> Self {
+        self.displacement_scale = scale;
+        self
+    }
+
+    pub fn with_materials(mut self, materials: TerrainMaterials) -> Self {
+        self.materials = Some(materials);
+        self
+    }
+
+    /// See [`TerrainEntity::polygon_offset`].
+    pub fn with_polygon_offset(mut self, polygon_offset: f32) -> Self {
+        self.polygon_offset = polygon_offset;
+        self
+    }
+
+    /// See [`TerrainEntity::specular`] and [`TerrainEntity::roughness`].
+    pub fn with_specular(mut self, specular: f32, roughness: f32) -> Self {
+        self.specular = specular;
+        self.roughness = roughness;
+        self
+    }
+
+    /// See [`TerrainEntity::tiles`].
+    pub fn with_tiles(mut self, tiles: Vec<TerrainTile>) -> Self {
+        self.tiles = tiles;
+        self
+    }
+
+    /// Marks the materials as having cut out parts, see [`TerrainEntity::transparent`].
+    pub fn with_transparency(mut self) -> Self {
+        self.transparent = true;
+        self
+    }
+}
+
+impl TerrainEntity {
+    /// Covers the scene from (0, 0) to (`size`, `size`).
+    pub fn ground(
+        height_fn: &dyn NoiseFn<f64, 2>,
+        variant_fn: &dyn NoiseFn<f64, 2>,
+        biome_map: &BiomeMap,
+        size: f32,
+        assets: &Assets,
+    ) -> Self {
+        TerrainEntityBuilder::new()
+            .with_height_fn(height_fn)
+            .with_variant_fn(variant_fn)
+            .with_biome_map(biome_map)
+            .with_size(size)
+            .with_materials(TerrainMaterials::ground(assets))
+            .build(assets)
+    }
+
+    /// A bush layer on top of the `ground`, sharing its variant and rocky maps.
+    /// Where it touches the ground, the `polygon_offset` decides which one is
+    /// visible, see [`TerrainEntity::polygon_offset`].
     pub fn bushes(
-        height_fn: &(impl NoiseFn<f64, 2> + ?Sized),
-        variant_fn: &(impl NoiseFn<f64, 2> + ?Sized),
+        height_fn: &dyn NoiseFn<f64, 2>,
+        ground: &TerrainEntity,
+        size: f32,
         assets: &Assets,
+        polygon_offset: f32,
     ) -> Self {
-        let model = glm::scale(&glm::identity(), &glm::vec3(SCENE_SIZE, SCENE_SIZE, 1.0));
-        let height_tex = Texture::from_noise(height_fn, (0., SCENE_SIZE, 0., SCENE_SIZE), 256);
-        let variant_tex = Texture::from_noise(variant_fn, (0., SCENE_SIZE, 0., SCENE_SIZE), 256);
+        TerrainEntityBuilder::new()
+            .with_height_fn(height_fn)
+            .with_variant_texture(ground.variant.clone())
+            .with_rocky_texture(ground.rocky.clone())
+            .with_size(size)
+            .with_materials(TerrainMaterials::bushes(assets))
+            .with_polygon_offset(polygon_offset)
+            // Leaves stay matte
+            .with_specular(0.0, 1.0)
+            .with_transparency()
+            .build(assets)
+    }
+
+    /// A flat plane at `height`, reaching `margin` meters past the scene of
+    /// `size` on every side. Drawn below the terrain, it hides the sky that
+    /// would otherwise show through gaps and past the edges.
+    pub fn base_plane(height: f32, size: f32, margin: f32, assets: &Assets) -> Self {
+        let extent = size + 2.0 * margin;
+        let model = glm::scale(
+            &glm::translation(&glm::vec3(-margin, -margin, 0.0)),
+            &glm::vec3(extent, extent, 1.0),
+        );
+        // A single texel each, the plane is the same everywhere
+        let bounds = (0., 1., 0., 1.);
+        let constant = |value| Texture::from_noise(noise::Constant::new(value), bounds, 1);
 
         TerrainEntity {
             vao: assets.terrain_quad_mesh.clone(),
-            displacement: Rc::new(height_tex),
-            variant: Rc::new(variant_tex),
-            albedo_xy1: assets.transparent_tex.clone(),
-            albedo_xy2: assets.bush_tex.clone(),
-            albedo_xz: assets.transparent_tex.clone(),
-            albedo_yz: assets.transparent_tex.clone(),
+            displacement: Rc::new(constant(height as f64)),
+            variant: Rc::new(constant(0.5)),
+            rocky: Rc::new(constant(0.0)),
+            albedo_xy1: assets.moss_tex.clone(),
+            albedo_xy2: assets.ground_tex.clone(),
+            albedo_xz: assets.rock_tex.clone(),
+            albedo_yz: assets.rock_tex.clone(),
             model,
-            world_to_uv: glm::scale2d(
-                &glm::identity(),
-                &glm::vec2(1.0 / SCENE_SIZE, 1.0 / SCENE_SIZE),
-            ),
+            world_to_uv: glm::Mat3::identity(),
             shader: assets.terrain_shader.clone(),
+            polygon_offset: 0.0,
+            specular: DEFAULT_SPECULAR,
+            roughness: DEFAULT_ROUGHNESS,
+            tiles: vec![TerrainTile::ORIGINAL],
+            transparent: false,
+        }
+    }
+}
+
+impl TerrainEntity {
+    /// Repeats the terrain with the given copies, replacing the original.
+    /// Include [`TerrainTile::ORIGINAL`] to keep it.
+    pub fn with_tiles(mut self, tiles: Vec<TerrainTile>) -> Self {
+        if tiles.len() > MAX_TILES {
+            eprintln!(
+                "Only {MAX_TILES} terrain tiles are supported, ignoring the last {}",
+                tiles.len() - MAX_TILES
+            );
         }
+        self.tiles = tiles;
+        self
     }
+
+    /// Draws one instance per tile, with the shader already set up.
+    fn render_tiles(&self, tiles: &[&TerrainTile]) {
+        if tiles.is_empty() {
+            return;
+        }
+        let tiles: Vec<glm::Vec4> = tiles.iter().map(|tile| tile.packed()).collect();
+        unsafe {
+            gl::Uniform4fv(
+                self.shader.get_uniform_location("tiles"),
+                tiles.len() as i32,
+                tiles.as_ptr() as *const f32,
+            );
+        }
+        self.vao.render_instanced(tiles.len());
+    }
+}
+
+/// Texels per side of the generated maps, keeping the detail of `base` texels
+/// over [`SCENE_SIZE`] meters for larger scenes.
+fn map_resolution(size: f32, base: u32) -> u32 {
+    let base = base.max(1) as f32;
+    (base * size / SCENE_SIZE).clamp(base, 4.0 * base) as u32
 }
 
 impl Renderable for TerrainEntity {
-    fn render(&self, view_proj_mat: &glm::Mat4) {
+    fn render(&self, ctx: &RenderContext) {
         unsafe {
             self.shader.activate();
             gl::UniformMatrix4fv(
                 self.shader.get_uniform_location("view_proj"),
                 1,
                 gl::FALSE,
-                view_proj_mat.as_ptr(),
+                ctx.view_proj_mat.as_ptr(),
+            );
+            set_light_uniforms(&self.shader, ctx.lights);
+            ctx.set_fog_uniforms(&self.shader);
+            gl::Uniform3fv(
+                self.shader.get_uniform_location("eye_position"),
+                1,
+                ctx.eye.as_ptr(),
+            );
+            gl::Uniform1f(
+                self.shader.get_uniform_location("specular_strength"),
+                self.specular,
+            );
+            gl::Uniform1f(
+                self.shader.get_uniform_location("roughness"),
+                self.roughness,
+            );
+            gl::Uniform3fv(
+                self.shader.get_uniform_location("ambient_color"),
+                1,
+                ctx.palette.ambient.as_ptr(),
             );
             gl::UniformMatrix4fv(
                 self.shader.get_uniform_location("model_mat"),
@@ -107,6 +474,8 @@ impl Renderable for TerrainEntity {
             gl::Uniform1i(self.shader.get_uniform_location("displacement_map"), 0);
             self.variant.activate(5);
             gl::Uniform1i(self.shader.get_uniform_location("variant_map"), 5);
+            self.rocky.activate(6);
+            gl::Uniform1i(self.shader.get_uniform_location("rocky_map"), 6);
 
             self.albedo_xy1.activate(4);
             gl::Uniform1i(self.shader.get_uniform_location("terrain_albedo_xy1"), 4);
@@ -116,9 +485,67 @@ impl Renderable for TerrainEntity {
             gl::Uniform1i(self.shader.get_uniform_location("terrain_albedo_xz"), 2);
             self.albedo_yz.activate(3);
             gl::Uniform1i(self.shader.get_uniform_location("terrain_albedo_yz"), 3);
+
+            // Leaves and other matte layers don't reflect
+            let reflective = ctx.environment.filter(|_| self.specular > 0.0);
+            if let Some(environment) = reflective {
+                environment.activate(7);
+            }
+            // Even unbound, the cube sampler needs a unit of its own
+            gl::Uniform1i(self.shader.get_uniform_location("environment"), 7);
+            gl::Uniform1f(
+                self.shader.get_uniform_location("environment_strength"),
+                if reflective.is_some() { 1.0 } else { 0.0 },
+            );
+            gl::Uniform1i(
+                self.shader.get_uniform_location("soft_edges"),
+                ctx.soft_edges as i32,
+            );
+            ctx.set_debug_uniforms(&self.shader);
+
+            if self.polygon_offset != 0.0 {
+                gl::Enable(gl::POLYGON_OFFSET_FILL);
+                gl::PolygonOffset(-self.polygon_offset, -self.polygon_offset);
+            }
+        }
+
+        let (reversed, kept): (Vec<_>, Vec<_>) = self
+            .tiles
+            .iter()
+            .take(MAX_TILES)
+            .partition(|tile| tile.reverses_winding());
+        self.render_tiles(&kept);
+        if !reversed.is_empty() {
+            // Otherwise back face culling would remove the whole copy
+            let mut front_face = 0;
+            unsafe {
+                gl::GetIntegerv(gl::FRONT_FACE, &mut front_face);
+                let flipped = if front_face as u32 == gl::CCW {
+                    gl::CW
+                } else {
+                    gl::CCW
+                };
+                gl::FrontFace(flipped);
+            }
+            self.render_tiles(&reversed);
+            unsafe {
+                gl::FrontFace(front_face as u32);
+            }
+        }
+
+        if self.polygon_offset != 0.0 {
+            unsafe {
+                gl::Disable(gl::POLYGON_OFFSET_FILL);
+            }
         }
+    }
+
+    fn is_terrain(&self) -> bool {
+        true
+    }
 
-        self.vao.render();
+    fn is_transparent(&self) -> bool {
+        self.transparent
     }
 }
 
@@ -133,10 +560,10 @@ pub struct RockMap {
 
 impl RockMap {
     pub fn new(seed: u32) -> RockMap {
-        let mut rng = rand::rngs::SmallRng::seed_from_u64(seed as u64);
+        let root = Seed::from(seed);
 
         // Large scale features, but not very much detail
-        let rockyness = noise::Fbm::<noise::Value>::new(rng.gen())
+        let rockyness = noise::Fbm::<noise::Value>::new(root.derive("rockiness").noise_seed())
             .set_octaves(4)
             .set_frequency(0.5);
         // Flatten out a lot of the values.
@@ -147,9 +574,9 @@ impl RockMap {
         let rockyness = noise::Min::new(rockyness, noise::Constant::new(1.0));
 
         // Manhattan distances to create hard ridges
-        let ridges = noise::Worley::new(rng.gen())
+        let ridges = noise::Worley::new(root.derive("ridges").noise_seed())
             .set_frequency(1.0)
-            .set_distance_function(&noise::core::worley::distance_functions::manhattan)
+            .set_distance_function(noise::core::worley::distance_functions::manhattan)
             .set_return_type(noise::core::worley::ReturnType::Distance);
         let ridges = Slice4D { func_4d: ridges };
 
@@ -187,10 +614,16 @@ impl noise::Seedable for RockMap {
     }
 }
 
-pub fn height_map(base: Rc<image::RgbaImage>, seed: u32) -> impl NoiseFn<f64, 2> + 'static {
-    let mut rng = rand::rngs::SmallRng::seed_from_u64(seed as u64);
+/// The base map is stretched over the whole scene of `scene_size` meters,
+/// while the noise keeps its scale.
+pub fn height_map(
+    base: Rc<image::RgbaImage>,
+    scene_size: f32,
+    seed: u32,
+) -> impl NoiseFn<f64, 2> + 'static {
+    let root = Seed::from(seed);
 
-    let rocks = noise::Fbm::<RockMap>::new(rng.gen())
+    let rocks = noise::Fbm::<RockMap>::new(root.derive("rocks").noise_seed())
         .set_octaves(3)
         .set_lacunarity(3.0)
         .set_persistence(0.3)
@@ -198,13 +631,13 @@ pub fn height_map(base: Rc<image::RgbaImage>, seed: u32) -> impl NoiseFn<f64, 2>
 
     let rocks = ScaleBias::new(rocks).set_scale(0.8);
 
-    let height = noise::Fbm::<noise::Value>::new(rng.gen())
+    let height = noise::Fbm::<noise::Value>::new(root.derive("hills").noise_seed())
         .set_octaves(6)
         .set_frequency(0.2);
     let height = ScaleBias::new(height).set_scale(0.3).set_bias(0.3);
 
     let base_height = noise::Power::new(
-        ImageNoiseFnWrapper::new_red(base),
+        ImageNoiseFnWrapper::new_red(base).with_scene_size(scene_size),
         noise::Constant::new(2.0),
     );
     let base_height = noise::ScaleBias::new(base_height).set_scale(2.0);
@@ -212,14 +645,19 @@ pub fn height_map(base: Rc<image::RgbaImage>, seed: u32) -> impl NoiseFn<f64, 2>
     noise::Add::new(base_height, noise::Add::new(rocks, height))
 }
 
-pub fn variant_map(base: Rc<image::RgbaImage>, seed: u32) -> impl NoiseFn<f64, 2> + 'static {
+/// See [`height_map`] for the `scene_size`.
+pub fn variant_map(
+    base: Rc<image::RgbaImage>,
+    scene_size: f32,
+    seed: u32,
+) -> impl NoiseFn<f64, 2> + 'static {
     let noise = noise::Fbm::<noise::Value>::new(seed)
         .set_octaves(6)
         .set_frequency(0.2);
     let noise = ScaleBias::new(noise).set_scale(0.5).set_bias(0.5);
 
     let bushiness = noise::Power::new(
-        ImageNoiseFnWrapper::new_green(base),
+        ImageNoiseFnWrapper::new_green(base).with_scene_size(scene_size),
         noise::Constant::new(2.0),
     );
     let bushiness = noise::ScaleBias::new(bushiness).set_scale(0.3);
@@ -238,11 +676,9 @@ pub fn bush_heights(seed: u32) -> impl NoiseFn<f64, 2> + 'static {
         .set_frequency(0.8);
     // Move from [-1, 1] to interval [MIN_HEIGHT, MAX_HEIGHT]
     let noise = ScaleBias::new(noise).set_scale(0.5).set_bias(0.5);
-    let noise = ScaleBias::new(noise)
+    ScaleBias::new(noise)
         .set_scale(MAX_HEIGHT - MIN_HEIGHT)
-        .set_bias(MIN_HEIGHT);
-
-    noise
+        .set_bias(MIN_HEIGHT)
 }
 
 struct Slice4D<F: NoiseFn<f64, 4>> {
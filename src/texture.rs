@@ -2,13 +2,21 @@ use std::marker::PhantomData;
 
 use gl::types::GLuint;
 use image::GenericImageView;
+use nalgebra_glm as glm;
 use noise::NoiseFn;
 
 use crate::error::clear_gl_errors;
 use crate::error::get_gl_errors;
+use crate::generation::height_grid;
+use crate::palette::srgb_channel_to_linear;
 
 pub struct Texture {
     id: GLuint,
+    width: u32,
+    height: u32,
+    /// The client format of the channels, like `GL_RGBA`, for later uploads.
+    format: gl::types::GLenum,
+    components: usize,
     /// Mark the texture as !Send and !Sync, since OpenGL is not thread safe
     _marker: PhantomData<*const ()>,
 }
@@ -18,6 +26,7 @@ impl Texture {
     ///
     /// Specify the format using the generics, for example:
     /// ```no_run
+    /// # use undergrowth::texture::{format::RGBA, Texture};
     /// let data = vec![0.0, 0.0, 0.0, 1.0];
     /// let texture = Texture::new::<f32, RGBA>(1, 1, data.as_slice());
     /// ```
@@ -36,59 +45,94 @@ impl Texture {
         unsafe {
             gl::GenTextures(1, &mut id);
             gl::BindTexture(gl::TEXTURE_2D, id);
+            // Rows of single channel bytes aren't 4 byte aligned in general
+            gl::PixelStorei(gl::UNPACK_ALIGNMENT, 1);
             // SAFETY: data is a valid pointer to a valid slice of T and
             // has the correct length (asserted above).
             gl::TexImage2D(
                 gl::TEXTURE_2D,
                 0,
-                F::to_glenum() as i32, // The target format
+                F::internal_format::<T>() as i32, // The target format
                 width as i32,
                 height as i32,
                 0,
-                F::to_glenum(),
+                F::client_format(),
                 T::to_glenum(),
                 data.as_ptr() as *const _,
             );
-
-            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::LINEAR as i32);
-            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
-            gl::TexParameteri(
-                gl::TEXTURE_2D,
-                gl::TEXTURE_WRAP_S,
-                gl::MIRRORED_REPEAT as i32,
-            );
-            gl::TexParameteri(
-                gl::TEXTURE_2D,
-                gl::TEXTURE_WRAP_T,
-                gl::MIRRORED_REPEAT as i32,
-            );
+            gl::PixelStorei(gl::UNPACK_ALIGNMENT, 4);
         }
         get_gl_errors().expect("Failed to create texture");
-        Self {
+        let texture = Self {
             id,
+            width,
+            height,
+            format: F::client_format(),
+            components: F::num_components(),
             _marker: PhantomData,
-        }
+        };
+        texture.set_params(TextureParams::default());
+        texture
     }
 
+    /// Loads an image with linear values, like a data map. For colors use
+    /// [`Texture::from_file_srgb`].
     pub fn from_file(
         path: impl AsRef<std::path::Path>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::from_image_file(path, false)
+    }
+
+    /// Loads an image with colors, like an albedo map. The 8 bit colors of
+    /// usual images are sRGB encoded, and get decoded when sampled, so the
+    /// lighting adds and multiplies linear values. Float images, like HDR
+    /// skies, are linear already and load like with [`Texture::from_file`].
+    pub fn from_file_srgb(
+        path: impl AsRef<std::path::Path>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::from_image_file(path, true)
+    }
+
+    fn from_image_file(
+        path: impl AsRef<std::path::Path>,
+        srgb: bool,
     ) -> Result<Self, Box<dyn std::error::Error>> {
         use image::io::Reader as ImageReader;
 
+        let path = path.as_ref();
+        let is_hdr = path
+            .extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case("hdr"));
+        if is_hdr {
+            return Self::from_hdr_file(path);
+        }
+
         let img = ImageReader::open(path)?.decode()?;
         let (width, height) = img.dimensions();
 
         img.as_rgba8()
             .map(|img| {
-                Ok(Self::new::<u8, format::RGBA>(
-                    width,
-                    height,
-                    img.as_raw().as_slice(),
-                ))
+                let data = img.as_raw().as_slice();
+                Ok(if srgb {
+                    Self::new::<u8, format::SRGBA>(width, height, data)
+                } else {
+                    Self::new::<u8, format::RGBA>(width, height, data)
+                })
             })
             .or_else(|| {
                 img.as_rgb8().map(|img| {
-                    Ok(Self::new::<u8, format::RGB>(
+                    let data = img.as_raw().as_slice();
+                    Ok(if srgb {
+                        Self::new::<u8, format::SRGB>(width, height, data)
+                    } else {
+                        Self::new::<u8, format::RGB>(width, height, data)
+                    })
+                })
+            })
+            .or_else(|| {
+                // Float images, like OpenEXR skies, keep their range
+                img.as_rgb32f().map(|img| {
+                    Ok(Self::new::<f32, format::RGB>(
                         width,
                         height,
                         img.as_raw().as_slice(),
@@ -96,45 +140,305 @@ impl Texture {
                 })
             })
             .or_else(|| {
-                img.as_luma8().map(|img| {
-                    Ok(Self::new::<u8, format::GrayScale>(
+                img.as_rgba32f().map(|img| {
+                    Ok(Self::new::<f32, format::RGBA>(
                         width,
                         height,
                         img.as_raw().as_slice(),
                     ))
                 })
             })
-            .unwrap_or_else(|| Err("Unsupported image format".into()))
+            .or_else(|| {
+                img.as_rgba16().map(|img| {
+                    let data = decode_srgb_16bit(img.as_raw(), 4, srgb);
+                    Ok(Self::new::<u16, format::RGBA>(width, height, &data))
+                })
+            })
+            .or_else(|| {
+                img.as_rgb16().map(|img| {
+                    let data = decode_srgb_16bit(img.as_raw(), 3, srgb);
+                    Ok(Self::new::<u16, format::RGB>(width, height, &data))
+                })
+            })
+            .or_else(|| {
+                img.as_luma8().map(|img| {
+                    let data = img.as_raw().as_slice();
+                    // No single channel sRGB format, so colors take up RGB
+                    Ok(if srgb {
+                        let data = gray_to_color(data, 1);
+                        Self::new::<u8, format::SRGB>(width, height, &data)
+                    } else {
+                        Self::new::<u8, format::GrayScale>(width, height, data)
+                    })
+                })
+            })
+            .or_else(|| {
+                img.as_luma16().map(|img| {
+                    let data = decode_srgb_16bit(img.as_raw(), 1, srgb);
+                    Ok(Self::new::<u16, format::GrayScale>(width, height, &data))
+                })
+            })
+            .or_else(|| {
+                // There are no two channel formats, so the gray is repeated
+                // into RGB next to the alpha
+                img.as_luma_alpha8().map(|img| {
+                    let data = gray_to_color(img.as_raw(), 2);
+                    Ok(if srgb {
+                        Self::new::<u8, format::SRGBA>(width, height, &data)
+                    } else {
+                        Self::new::<u8, format::RGBA>(width, height, &data)
+                    })
+                })
+            })
+            .or_else(|| {
+                img.as_luma_alpha16().map(|img| {
+                    let data = gray_to_color(img.as_raw(), 2);
+                    let data = decode_srgb_16bit(&data, 4, srgb);
+                    Ok(Self::new::<u16, format::RGBA>(width, height, &data))
+                })
+            })
+            .unwrap_or_else(|| Err(format!("Unsupported image format {:?}", img.color()).into()))
+    }
+
+    /// Radiance HDR files, which [`image::DynamicImage`] only gives as 8 bit
+    /// colors, with everything brighter than white clipped.
+    fn from_hdr_file(path: &std::path::Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let file = std::io::BufReader::new(std::fs::File::open(path)?);
+        let decoder = image::codecs::hdr::HdrDecoder::new(file)?;
+        let metadata = decoder.metadata();
+        let data: Vec<f32> = decoder
+            .read_image_hdr()?
+            .into_iter()
+            .flat_map(|pixel| pixel.0)
+            .collect();
+        Ok(Self::new::<f32, format::RGB>(
+            metadata.width,
+            metadata.height,
+            &data,
+        ))
     }
 
     /// This will create a square texture by evaluating the noise function
     /// on a grid in the given bounds.
+    ///
+    /// It is filtered linearly, for crisp texels call
+    /// [`Texture::set_params`] with [`TextureParams::nearest`].
     pub fn from_noise(
         noise: impl NoiseFn<f64, 2>,
-        (x_min, x_max, y_min, y_max): (f32, f32, f32, f32),
+        bounds: (f32, f32, f32, f32),
         resolution: u32,
     ) -> Self {
-        // Not using [`noise::utils::PlaneMapBuilder`], since I
-        // want to get the vector directly.
+        let result_map = height_grid(&noise, bounds, resolution);
 
-        let mut result_map = vec![0.0f32; (resolution * resolution) as usize];
+        Self::new::<f32, format::GrayScale>(resolution, resolution, result_map.as_slice())
+    }
 
-        let x_step = (x_max - x_min) / resolution as f32;
-        let y_step = (y_max - y_min) / resolution as f32;
+    /// Like [`Texture::from_noise`], but stored as 16 bit normalized integers,
+    /// half the memory of floats while still smooth on gentle slopes. The
+    /// noise is mapped from `range` to 0 to 1 and clamped, so a shader gets
+    /// the original value back as `min + sampled * (max - min)`.
+    pub fn from_noise_16bit(
+        noise: impl NoiseFn<f64, 2>,
+        bounds: (f32, f32, f32, f32),
+        resolution: u32,
+        range: (f32, f32),
+    ) -> Self {
+        let (min, max) = range;
+        assert!(min < max, "Empty range for the noise texture");
+        let result_map: Vec<u16> = height_grid(&noise, bounds, resolution)
+            .into_iter()
+            .map(|value| {
+                let normalized = (value - min) / (max - min);
+                (normalized.clamp(0.0, 1.0) * u16::MAX as f32).round() as u16
+            })
+            .collect();
 
-        for y in 0..resolution {
-            for x in 0..resolution {
-                let current_x = x_min + x_step * (x as f32 + 0.5);
-                let current_y = y_min + y_step * (y as f32 + 0.5);
+        Self::new::<u16, format::GrayScale>(resolution, resolution, result_map.as_slice())
+    }
 
-                result_map[(y * resolution + x) as usize] =
-                    noise.get([current_x as f64, current_y as f64]) as f32;
+    /// Creates an uninitialized half float RGBA texture, meant to be rendered into.
+    pub fn new_render_target(width: u32, height: u32) -> Self {
+        let mut id = 0;
+        clear_gl_errors();
+        unsafe {
+            gl::GenTextures(1, &mut id);
+            gl::BindTexture(gl::TEXTURE_2D, id);
+            gl::TexImage2D(
+                gl::TEXTURE_2D,
+                0,
+                gl::RGBA16F as i32,
+                width as i32,
+                height as i32,
+                0,
+                gl::RGBA,
+                gl::FLOAT,
+                std::ptr::null(),
+            );
+
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::LINEAR as i32);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as i32);
+            // Post-processing blurs would otherwise wrap around the screen edges
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as i32);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as i32);
+        }
+        get_gl_errors().expect("Failed to create render target texture");
+        Self {
+            id,
+            width,
+            height,
+            format: gl::RGBA,
+            components: 4,
+            _marker: PhantomData,
+        }
+    }
+
+    /// The OpenGL texture object, to hand to other GL code sharing the context,
+    /// like a UI compositing the scene.
+    ///
+    /// The name is only valid on the thread of the context that created it,
+    /// which is why `Texture` is neither `Send` nor `Sync`. Don't pass the raw
+    /// id around that restriction, and don't use it after the texture is dropped.
+    pub fn raw_id(&self) -> GLuint {
+        self.id
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Overwrites the `width` by `height` texels starting at (`x`, `y`) with
+    /// rows of `data`, in the channels the texture was created with. Cheaper
+    /// than creating a new texture, e.g. to animate a map every frame.
+    ///
+    /// Mipmaps are not regenerated, call [`Texture::enable_mipmap`] again if
+    /// they are used.
+    pub fn update_region<T: format::TextureDataValue>(
+        &self,
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+        data: &[T],
+    ) {
+        assert!(
+            x.checked_add(width).is_some_and(|end| end <= self.width)
+                && y.checked_add(height).is_some_and(|end| end <= self.height),
+            "Region {width}x{height} at ({x}, {y}) is outside the {}x{} texture",
+            self.width,
+            self.height
+        );
+        assert!(
+            data.len() == (width * height) as usize * self.components,
+            "Texture data length does not match the region and format"
+        );
+
+        clear_gl_errors();
+        unsafe {
+            gl::BindTexture(gl::TEXTURE_2D, self.id);
+            // Rows of single channel bytes aren't 4 byte aligned in general
+            gl::PixelStorei(gl::UNPACK_ALIGNMENT, 1);
+            // SAFETY: data is a valid slice with the length of the region (asserted above).
+            gl::TexSubImage2D(
+                gl::TEXTURE_2D,
+                0,
+                x as i32,
+                y as i32,
+                width as i32,
+                height as i32,
+                self.format,
+                T::to_glenum(),
+                data.as_ptr() as *const _,
+            );
+            gl::PixelStorei(gl::UNPACK_ALIGNMENT, 4);
+        }
+        get_gl_errors().expect("Failed to update texture");
+    }
+
+    /// Copies the texels back from the GPU, rows from the first one uploaded,
+    /// converted to the channels and type given by the generics like in
+    /// [`Texture::new`].
+    pub fn read_pixels<T: format::TextureDataValue, F: format::TextureFormat>(&self) -> Vec<T> {
+        let mut data =
+            vec![T::default(); (self.width * self.height) as usize * F::num_components()];
+        clear_gl_errors();
+        unsafe {
+            gl::BindTexture(gl::TEXTURE_2D, self.id);
+            // Rows of single channel bytes aren't 4 byte aligned in general
+            gl::PixelStorei(gl::PACK_ALIGNMENT, 1);
+            // SAFETY: data has room for all texels of the requested format and type.
+            gl::GetTexImage(
+                gl::TEXTURE_2D,
+                0,
+                F::client_format(),
+                T::to_glenum(),
+                data.as_mut_ptr() as *mut _,
+            );
+            gl::PixelStorei(gl::PACK_ALIGNMENT, 4);
+        }
+        get_gl_errors().expect("Failed to read texture");
+        data
+    }
+
+    /// Saves the texels as an 8 bit PNG, with the first uploaded row at the
+    /// top. Float channels like those of [`Texture::from_noise`] are clamped
+    /// to the range 0 to 1.
+    pub fn save_png(
+        &self,
+        path: impl AsRef<std::path::Path>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (width, height) = (self.width, self.height);
+        // Reading as bytes lets OpenGL do the clamping and conversion
+        let size_error = || "The texel data doesn't match the size";
+        match self.format {
+            gl::RED => image::GrayImage::from_raw(
+                width,
+                height,
+                self.read_pixels::<u8, format::GrayScale>(),
+            )
+            .ok_or_else(size_error)?
+            .save(path)?,
+            gl::RGB => {
+                image::RgbImage::from_raw(width, height, self.read_pixels::<u8, format::RGB>())
+                    .ok_or_else(size_error)?
+                    .save(path)?
+            }
+            gl::RGBA => {
+                image::RgbaImage::from_raw(width, height, self.read_pixels::<u8, format::RGBA>())
+                    .ok_or_else(size_error)?
+                    .save(path)?
             }
+            _ => return Err("Unsupported texture format".into()),
         }
+        Ok(())
+    }
 
-        Self::new::<f32, format::GrayScale>(resolution, resolution, result_map.as_slice())
+    /// Changes how the texture is sampled. Textures start out with
+    /// [`TextureParams::default`].
+    pub fn set_params(&self, params: TextureParams) {
+        unsafe {
+            gl::BindTexture(gl::TEXTURE_2D, self.id);
+            gl::TexParameteri(
+                gl::TEXTURE_2D,
+                gl::TEXTURE_MIN_FILTER,
+                params.min_filter.to_glenum() as i32,
+            );
+            gl::TexParameteri(
+                gl::TEXTURE_2D,
+                gl::TEXTURE_MAG_FILTER,
+                params.mag_filter.to_glenum() as i32,
+            );
+            let wrap = params.wrap.to_glenum() as i32;
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, wrap);
+            gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, wrap);
+        }
     }
 
+    /// Also switches the minification to linear between the mipmaps, replacing
+    /// the filter of [`Texture::set_params`].
     pub fn enable_mipmap(&self) {
         unsafe {
             gl::BindTexture(gl::TEXTURE_2D, self.id);
@@ -159,28 +463,285 @@ impl Texture {
     }
 }
 
+/// Repeats the gray of each pixel of `components` values into RGB, keeping
+/// the alpha after it if there is one.
+fn gray_to_color<T: Copy>(data: &[T], components: usize) -> Vec<T> {
+    data.chunks_exact(components)
+        .flat_map(|pixel| {
+            let gray = pixel[0];
+            [gray, gray, gray].into_iter().chain(pixel.get(1).copied())
+        })
+        .collect()
+}
+
+/// There are no 16 bit sRGB formats on the GPU, so 16 bit colors are decoded
+/// here instead, leaving the alpha in every `components`th value as it is.
+fn decode_srgb_16bit(data: &[u16], components: usize, srgb: bool) -> Vec<u16> {
+    if !srgb {
+        return data.to_vec();
+    }
+    data.iter()
+        .enumerate()
+        .map(|(index, &value)| {
+            if components == 4 && index % 4 == 3 {
+                return value;
+            }
+            let linear = srgb_channel_to_linear(value as f32 / u16::MAX as f32);
+            (linear * u16::MAX as f32).round() as u16
+        })
+        .collect()
+}
+
+/// How a [`Texture`] is sampled, see [`Texture::set_params`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TextureParams {
+    /// When a texel is smaller than a pixel.
+    pub min_filter: Filter,
+    /// When a texel is larger than a pixel.
+    pub mag_filter: Filter,
+    /// Outside of the 0 to 1 texture coordinates.
+    pub wrap: Wrap,
+}
+
+impl Default for TextureParams {
+    fn default() -> Self {
+        TextureParams {
+            min_filter: Filter::Linear,
+            mag_filter: Filter::Linear,
+            wrap: Wrap::MirroredRepeat,
+        }
+    }
+}
+
+impl TextureParams {
+    /// Unfiltered, each texel a sharp square, e.g. to see which texel a world
+    /// coordinate maps to.
+    pub fn nearest() -> Self {
+        TextureParams {
+            min_filter: Filter::Nearest,
+            mag_filter: Filter::Nearest,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Filter {
+    /// The closest texel.
+    Nearest,
+    /// Blends the four closest texels.
+    Linear,
+}
+
+impl Filter {
+    fn to_glenum(self) -> gl::types::GLenum {
+        match self {
+            Filter::Nearest => gl::NEAREST,
+            Filter::Linear => gl::LINEAR,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Wrap {
+    Repeat,
+    MirroredRepeat,
+    ClampToEdge,
+}
+
+impl Wrap {
+    fn to_glenum(self) -> gl::types::GLenum {
+        match self {
+            Wrap::Repeat => gl::REPEAT,
+            Wrap::MirroredRepeat => gl::MIRRORED_REPEAT,
+            Wrap::ClampToEdge => gl::CLAMP_TO_EDGE,
+        }
+    }
+}
+
 impl Drop for Texture {
     fn drop(&mut self) {
         unsafe { gl::DeleteTextures(1, &self.id) };
     }
 }
 
+/// A cube map, looked up by direction instead of uv coordinates.
+pub struct CubeTexture {
+    id: GLuint,
+    size: u32,
+    /// Mark the texture as !Send and !Sync, since OpenGL is not thread safe
+    _marker: PhantomData<*const ()>,
+}
+
+impl CubeTexture {
+    /// Create a cube map from six square faces of `size` texels, given in the
+    /// OpenGL order +x, -x, +y, -y, +z, -z and orientation. The directions are
+    /// in world space, so +z is up.
+    ///
+    /// Mipmaps are generated, so blurry reflections can sample a lower level.
+    pub fn new<T: format::TextureDataValue, F: format::TextureFormat>(
+        size: u32,
+        faces: [&[T]; 6],
+    ) -> Self {
+        assert!(
+            faces
+                .iter()
+                .all(|face| face.len() == (size * size) as usize * F::num_components()),
+            "Cube face data length does not match size and format"
+        );
+
+        let mut id = 0;
+        clear_gl_errors();
+        unsafe {
+            gl::GenTextures(1, &mut id);
+            gl::BindTexture(gl::TEXTURE_CUBE_MAP, id);
+            gl::PixelStorei(gl::UNPACK_ALIGNMENT, 1);
+            for (i, face) in faces.iter().enumerate() {
+                // SAFETY: each face is a valid slice with the asserted length.
+                gl::TexImage2D(
+                    gl::TEXTURE_CUBE_MAP_POSITIVE_X + i as u32,
+                    0,
+                    F::internal_format::<T>() as i32,
+                    size as i32,
+                    size as i32,
+                    0,
+                    F::client_format(),
+                    T::to_glenum(),
+                    face.as_ptr() as *const _,
+                );
+            }
+            gl::PixelStorei(gl::UNPACK_ALIGNMENT, 4);
+            gl::GenerateMipmap(gl::TEXTURE_CUBE_MAP);
+
+            gl::TexParameteri(
+                gl::TEXTURE_CUBE_MAP,
+                gl::TEXTURE_MIN_FILTER,
+                gl::LINEAR_MIPMAP_LINEAR as i32,
+            );
+            gl::TexParameteri(
+                gl::TEXTURE_CUBE_MAP,
+                gl::TEXTURE_MAG_FILTER,
+                gl::LINEAR as i32,
+            );
+            for wrap in [gl::TEXTURE_WRAP_S, gl::TEXTURE_WRAP_T, gl::TEXTURE_WRAP_R] {
+                gl::TexParameteri(gl::TEXTURE_CUBE_MAP, wrap, gl::CLAMP_TO_EDGE as i32);
+            }
+            // Global state, but no other cube maps care about the seams either
+            gl::Enable(gl::TEXTURE_CUBE_MAP_SEAMLESS);
+        }
+        get_gl_errors().expect("Failed to create cube texture");
+        Self {
+            id,
+            size,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Loads the six faces from image files, in the order of [`CubeTexture::new`].
+    /// The colors are sRGB encoded, like with [`Texture::from_file_srgb`].
+    pub fn from_files<P: AsRef<std::path::Path>>(
+        paths: [P; 6],
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut faces = Vec::with_capacity(6);
+        for path in paths {
+            faces.push(image::open(path)?.into_rgba8());
+        }
+        let size = faces[0].width();
+        if faces.iter().any(|face| face.dimensions() != (size, size)) {
+            return Err("Cube faces must be square and of the same size".into());
+        }
+        let data: [&[u8]; 6] = std::array::from_fn(|i| faces[i].as_raw().as_slice());
+        Ok(Self::new::<u8, format::SRGBA>(size, data))
+    }
+
+    /// Loads an equirectangular panorama, like the ones from
+    /// [`crate::renderer::Renderer::capture_panorama`], into faces of
+    /// `face_size` texels.
+    pub fn from_equirectangular(
+        path: impl AsRef<std::path::Path>,
+        face_size: u32,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let panorama = image::open(path)?.into_rgba8();
+        let faces = crate::panorama::cube_map_faces(&panorama, face_size);
+        let data: [&[u8]; 6] = std::array::from_fn(|i| faces[i].as_raw().as_slice());
+        Ok(Self::new::<u8, format::SRGBA>(face_size, data))
+    }
+
+    /// A sky without image files, blending from the linear `horizon` color up
+    /// to the `zenith`. Below the horizon it stays at the horizon color.
+    pub fn from_gradient(face_size: u32, horizon: glm::Vec3, zenith: glm::Vec3) -> Self {
+        let faces: Vec<Vec<f32>> = (0..6)
+            .map(|face| {
+                (0..face_size * face_size)
+                    .flat_map(|index| {
+                        let (column, row) = (index % face_size, index / face_size);
+                        let direction =
+                            crate::panorama::cube_map_direction(face, face_size, column, row);
+                        // Most of the change close to the horizon, like a real sky
+                        let height = direction.z.max(0.0).sqrt();
+                        let color = glm::mix(&horizon, &zenith, height);
+                        [color.x, color.y, color.z]
+                    })
+                    .collect()
+            })
+            .collect();
+        let data: [&[f32]; 6] = std::array::from_fn(|i| faces[i].as_slice());
+        Self::new::<f32, format::RGB>(face_size, data)
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// See [`Texture::raw_id`].
+    pub fn raw_id(&self) -> GLuint {
+        self.id
+    }
+
+    pub fn activate(&self, texture_unit: u32) {
+        assert!(texture_unit < 32, "Texture unit out of range");
+        unsafe {
+            gl::ActiveTexture(gl::TEXTURE0 + texture_unit);
+            gl::BindTexture(gl::TEXTURE_CUBE_MAP, self.id);
+        }
+    }
+}
+
+impl Drop for CubeTexture {
+    fn drop(&mut self) {
+        unsafe { gl::DeleteTextures(1, &self.id) };
+    }
+}
+
 /// Weird magic I had fun creating, to leverage the Rust type system to create
 /// overloading of the `Texture::new` function.
 pub mod format {
     trait Sealed {}
 
-    /// Use either [`f32`] or [`u8`].
+    /// Use either [`f32`], [`u16`] or [`u8`]. The integers are normalized, so
+    /// the shaders read them as floats from 0 to 1.
     #[allow(private_bounds)]
-    pub trait TextureDataValue: Sealed {
+    pub trait TextureDataValue: Sealed + Copy + Default {
         fn to_glenum() -> gl::types::GLenum;
+        /// The sized format storing `components` channels of this type without
+        /// loss. An unsized one like `GL_RED` lets the driver pick, which usually
+        /// means 8 bit normalized, even for float data.
+        fn sized_internal_format(components: usize) -> gl::types::GLenum;
     }
 
-    /// Use either [`GrayScale`] or [`RGBA`].
+    /// Use either [`GrayScale`], [`RGB`], [`RGBA`] or their sRGB variants.
     #[allow(private_bounds)]
     pub trait TextureFormat: Sealed {
         fn num_components() -> usize;
-        fn to_glenum() -> gl::types::GLenum;
+        /// The channels of the data on the CPU side, when uploading or reading
+        /// back, like `GL_RGBA`.
+        fn client_format() -> gl::types::GLenum;
+        /// The format of the texture on the GPU, for data of type `T`. By
+        /// default it stores the client channels without loss, formats like
+        /// sRGB override it.
+        fn internal_format<T: TextureDataValue>() -> gl::types::GLenum {
+            T::sized_internal_format(Self::num_components())
+        }
     }
 
     pub struct GrayScale;
@@ -189,37 +750,95 @@ pub mod format {
         fn num_components() -> usize {
             1
         }
-        fn to_glenum() -> gl::types::GLenum {
+        fn client_format() -> gl::types::GLenum {
             gl::RED
         }
     }
+    #[allow(clippy::upper_case_acronyms)]
     pub struct RGBA;
     impl Sealed for RGBA {}
     impl TextureFormat for RGBA {
         fn num_components() -> usize {
             4
         }
-        fn to_glenum() -> gl::types::GLenum {
+        fn client_format() -> gl::types::GLenum {
             gl::RGBA
         }
     }
 
+    #[allow(clippy::upper_case_acronyms)]
     pub struct RGB;
     impl Sealed for RGB {}
     impl TextureFormat for RGB {
         fn num_components() -> usize {
             3
         }
-        fn to_glenum() -> gl::types::GLenum {
+        fn client_format() -> gl::types::GLenum {
             gl::RGB
         }
     }
 
+    /// Like [`RGBA`], but the colors are sRGB encoded, as in most images. The
+    /// GPU decodes them to linear values when sampling. Only for [`u8`] data.
+    #[allow(clippy::upper_case_acronyms)]
+    pub struct SRGBA;
+    impl Sealed for SRGBA {}
+    impl TextureFormat for SRGBA {
+        fn num_components() -> usize {
+            4
+        }
+        fn client_format() -> gl::types::GLenum {
+            gl::RGBA
+        }
+        fn internal_format<T: TextureDataValue>() -> gl::types::GLenum {
+            gl::SRGB8_ALPHA8
+        }
+    }
+
+    /// Like [`SRGBA`], without alpha.
+    #[allow(clippy::upper_case_acronyms)]
+    pub struct SRGB;
+    impl Sealed for SRGB {}
+    impl TextureFormat for SRGB {
+        fn num_components() -> usize {
+            3
+        }
+        fn client_format() -> gl::types::GLenum {
+            gl::RGB
+        }
+        fn internal_format<T: TextureDataValue>() -> gl::types::GLenum {
+            gl::SRGB8
+        }
+    }
+
     impl Sealed for f32 {}
     impl TextureDataValue for f32 {
         fn to_glenum() -> gl::types::GLenum {
             gl::FLOAT
         }
+        fn sized_internal_format(components: usize) -> gl::types::GLenum {
+            match components {
+                1 => gl::R32F,
+                3 => gl::RGB32F,
+                4 => gl::RGBA32F,
+                _ => unreachable!("No texture format with {components} components"),
+            }
+        }
+    }
+
+    impl Sealed for u16 {}
+    impl TextureDataValue for u16 {
+        fn to_glenum() -> gl::types::GLenum {
+            gl::UNSIGNED_SHORT
+        }
+        fn sized_internal_format(components: usize) -> gl::types::GLenum {
+            match components {
+                1 => gl::R16,
+                3 => gl::RGB16,
+                4 => gl::RGBA16,
+                _ => unreachable!("No texture format with {components} components"),
+            }
+        }
     }
 
     impl Sealed for u8 {}
@@ -227,5 +846,13 @@ pub mod format {
         fn to_glenum() -> gl::types::GLenum {
             gl::UNSIGNED_BYTE
         }
+        fn sized_internal_format(components: usize) -> gl::types::GLenum {
+            match components {
+                1 => gl::R8,
+                3 => gl::RGB8,
+                4 => gl::RGBA8,
+                _ => unreachable!("No texture format with {components} components"),
+            }
+        }
     }
 }
@@ -0,0 +1,24 @@
+//! Culling math, which doesn't need an OpenGL context.
+
+use nalgebra_glm as glm;
+use undergrowth::renderer::Frustum;
+
+#[test]
+fn frustum_keeps_boxes_in_view_and_culls_the_others() {
+    let eye = glm::vec3(0.0, 0.0, 5.0);
+    let view = glm::look_at(&eye, &glm::Vec3::zeros(), &glm::Vec3::y());
+    let frustum = Frustum::from_view_proj(&(glm::perspective(1.0, 1.0, 0.1, 10.0) * view));
+    let cube = |center: glm::Vec3| {
+        let half = glm::vec3(0.5, 0.5, 0.5);
+        frustum.intersects_box(&(center - half), &(center + half))
+    };
+
+    assert!(cube(glm::Vec3::zeros()));
+    // Behind the camera and beyond the far plane
+    assert!(!cube(glm::vec3(0.0, 0.0, 8.0)));
+    assert!(!cube(glm::vec3(0.0, 0.0, -8.0)));
+    // Off to the sides, only the one poking into the view is kept
+    assert!(!cube(glm::vec3(6.0, 0.0, 0.0)));
+    assert!(!cube(glm::vec3(0.0, -6.0, 0.0)));
+    assert!(cube(glm::vec3(2.9, 0.0, 0.0)));
+}
@@ -0,0 +1,90 @@
+//! The generation is CPU only, so these run without an OpenGL context.
+
+#[cfg(feature = "parallel")]
+#[test]
+fn parallel_points_match_single_threaded() {
+    use undergrowth::generation::{
+        generate_points_on_distribution, generate_points_on_distribution_par,
+    };
+
+    let distribution = || {
+        noise::ScaleBias::new(noise::Perlin::new(3))
+            .set_bias(1.0)
+            .set_scale(2.0)
+    };
+    let bounds = (0.0, 13.0, 1.0, 20.0);
+    let seed = 42;
+
+    let single = generate_points_on_distribution(distribution(), bounds, seed);
+    assert!(!single.is_empty());
+    for threads in [1, 4] {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap();
+        let parallel =
+            pool.install(|| generate_points_on_distribution_par(distribution(), bounds, seed));
+        assert_eq!(single, parallel, "differs on {threads} threads");
+    }
+}
+
+#[test]
+fn exact_count_is_met_from_a_low_density() {
+    use std::rc::Rc;
+    use undergrowth::generation::{generate_foliage, FoliageConfig};
+
+    let mut config = FoliageConfig::new(Rc::new(noise::Constant::new(0.0)));
+    config.density = 0.1;
+    config.exact_count = Some(2000);
+    assert_eq!(generate_foliage(&config, 7).len(), 2000);
+
+    // Nothing fits, which must give up instead of raising the density forever
+    config.height_range = Some((1.0, 2.0));
+    assert!(generate_foliage(&config, 7).is_empty());
+}
+
+#[test]
+fn exact_count_is_met_in_a_sparse_masked_layer() {
+    use std::rc::Rc;
+    use undergrowth::generation::{generate_foliage, FoliageConfig};
+
+    let mut config = FoliageConfig::new(Rc::new(noise::Constant::new(0.0)));
+    config.density = 0.1;
+    config.exact_count = Some(3000);
+    // The lowest bushiness leaves a tenth of the density, and the mask
+    // clears every other square meter
+    config.bushiness = Some(Box::new(noise::Constant::new(0.0)));
+    config.density_mask = Some(Box::new(
+        noise::Clamp::new(noise::ScaleBias::new(noise::Checkerboard::new(0)).set_scale(100.0))
+            .set_bounds(0.0, 1.0),
+    ));
+    for seed in [3, 7] {
+        assert_eq!(generate_foliage(&config, seed).len(), 3000, "seed {seed}");
+    }
+}
+
+#[test]
+fn count_normalization_evens_out_seeds_and_keeps_the_density() {
+    use std::rc::Rc;
+    use undergrowth::generation::{generate_foliage, FoliageConfig};
+
+    let counts = |density: f64| -> Vec<usize> {
+        let mut config = FoliageConfig::new(Rc::new(noise::Constant::new(0.0)));
+        config.bounds = (0.0, 20.0, 0.0, 20.0);
+        config.density = density;
+        config.count_tolerance = Some(0.15);
+        (1..=6)
+            .map(|seed| generate_foliage(&config, seed).len())
+            .collect()
+    };
+
+    let dense = counts(50.0);
+    let (min, max) = (dense.iter().min().unwrap(), dense.iter().max().unwrap());
+    assert!(*max as f64 <= *min as f64 * 1.15 / 0.85, "{dense:?}");
+    // A lower density is a lower target, not the same one
+    let sparse = counts(5.0);
+    assert!(
+        sparse.iter().max().unwrap() < min,
+        "{sparse:?} vs {dense:?}"
+    );
+}
@@ -0,0 +1,246 @@
+//! Rendering checks on an offscreen context. They skip themselves on machines
+//! without an EGL device, since there is nothing to render with.
+
+use std::cell::Cell;
+use std::rc::Rc;
+
+use nalgebra_glm as glm;
+use undergrowth::foliage::{ShrubEntities, ShrubEntitiesBuilder};
+use undergrowth::framebuffer::{read_window_pixels, Framebuffer};
+use undergrowth::headless::HeadlessContext;
+use undergrowth::lighting::DirectionalLight;
+use undergrowth::mesh::Mesh;
+use undergrowth::palette::Palette;
+use undergrowth::renderer::{
+    render_entities, DebugView, FrameStats, Frustum, RenderContext, Renderable,
+};
+use undergrowth::shader::{Shader, ShaderBuilder};
+use undergrowth::texture::{format, Texture};
+
+/// A current context with loaded GL functions, or `None` to skip the test.
+fn context(width: u32, height: u32) -> Option<HeadlessContext> {
+    match HeadlessContext::new(width, height) {
+        Ok(context) => {
+            context.load_gl();
+            Some(context)
+        }
+        Err(err) => {
+            eprintln!("Skipping, no headless OpenGL context: {err}");
+            None
+        }
+    }
+}
+
+/// Clears all of the bound framebuffer to `top`, then the bottom row to `bottom`.
+fn clear_rows(width: i32, top: [f32; 4], bottom: [f32; 4]) {
+    unsafe {
+        gl::ClearColor(top[0], top[1], top[2], top[3]);
+        gl::Clear(gl::COLOR_BUFFER_BIT);
+        gl::Enable(gl::SCISSOR_TEST);
+        gl::Scissor(0, 0, width, 1);
+        gl::ClearColor(bottom[0], bottom[1], bottom[2], bottom[3]);
+        gl::Clear(gl::COLOR_BUFFER_BIT);
+        gl::Disable(gl::SCISSOR_TEST);
+    }
+}
+
+#[test]
+fn framebuffer_reads_back_the_clear_color() {
+    let Some(_context) = context(4, 4) else {
+        return;
+    };
+    let target = Framebuffer::new(5, 3, None);
+    target.bind();
+    clear_rows(5, [1.0, 0.0, 1.0, 1.0], [0.0, 0.0, 1.0, 1.0]);
+
+    let image = target.read_pixels();
+    assert_eq!(image.dimensions(), (5, 3));
+    // Top row first, so the bottom row of OpenGL comes last
+    assert_eq!(image.get_pixel(0, 0).0, [255, 0, 255, 255]);
+    assert_eq!(image.get_pixel(4, 1).0, [255, 0, 255, 255]);
+    assert_eq!(image.get_pixel(2, 2).0, [0, 0, 255, 255]);
+}
+
+#[test]
+fn framebuffer_pixels_are_srgb_encoded() {
+    let Some(_context) = context(4, 4) else {
+        return;
+    };
+    let target = Framebuffer::new(2, 2, None);
+    target.bind();
+    clear_rows(2, [0.5, 0.0, 0.2, 0.5], [0.5, 0.0, 0.2, 0.5]);
+
+    // Like an sRGB window shows linear 0.5 and 0.2, alpha stays linear
+    let image = target.read_pixels();
+    assert_eq!(image.get_pixel(1, 1).0, [188, 0, 124, 128]);
+}
+
+#[test]
+fn window_reads_back_the_clear_color() {
+    let Some(context) = context(6, 2) else {
+        return;
+    };
+    let (width, height) = context.size();
+    Framebuffer::bind_default(width as i32, height as i32);
+    clear_rows(width as i32, [0.0, 1.0, 0.0, 1.0], [1.0, 1.0, 1.0, 1.0]);
+
+    let image = read_window_pixels(width as i32, height as i32);
+    assert_eq!(image.get_pixel(5, 0).0, [0, 255, 0, 255]);
+    assert_eq!(image.get_pixel(0, 1).0, [255, 255, 255, 255]);
+}
+
+#[test]
+fn window_reads_back_dark_srgb_values() {
+    let Some(context) = context(2, 2) else {
+        return;
+    };
+    let (width, height) = context.size();
+    Framebuffer::bind_default(width as i32, height as i32);
+    let mut encoding = gl::LINEAR as i32;
+    unsafe {
+        gl::GetFramebufferAttachmentParameteriv(
+            gl::FRAMEBUFFER,
+            gl::BACK_LEFT,
+            gl::FRAMEBUFFER_ATTACHMENT_COLOR_ENCODING,
+            &mut encoding,
+        );
+        gl::Enable(gl::FRAMEBUFFER_SRGB);
+    }
+    clear_rows(
+        width as i32,
+        [0.5, 0.2, 0.002, 1.0],
+        [0.001, 0.0005, 0.0, 1.0],
+    );
+
+    let image = read_window_pixels(width as i32, height as i32);
+    unsafe { gl::Disable(gl::FRAMEBUFFER_SRGB) };
+    // A linear pbuffer stores the values as they are, nothing to check
+    if encoding != gl::SRGB as i32 {
+        return;
+    }
+    // Mid grey, and sRGB 1 to 12, which 8 linear bits can't tell apart
+    assert_eq!(image.get_pixel(0, 0).0, [188, 124, 7, 255]);
+    assert_eq!(image.get_pixel(1, 1).0, [3, 2, 0, 255]);
+}
+
+#[test]
+fn hdr_texture_keeps_values_above_one() {
+    let Some(_context) = context(4, 4) else {
+        return;
+    };
+    let pixels = [image::Rgb([4.0, 0.5, 1.0]), image::Rgb([0.25, 16.0, 2.0])];
+    let path = std::env::temp_dir().join(format!("undergrowth-sky-{}.hdr", std::process::id()));
+    let file = std::fs::File::create(&path).unwrap();
+    image::codecs::hdr::HdrEncoder::new(file)
+        .encode(&pixels, 2, 1)
+        .unwrap();
+
+    let texture = Texture::from_file_srgb(&path);
+    std::fs::remove_file(&path).unwrap();
+    let data = texture.unwrap().read_pixels::<f32, format::RGB>();
+    // Radiance stores 8 bit mantissas with a shared exponent, so powers of two are exact
+    assert_eq!(data, [4.0, 0.5, 1.0, 0.25, 16.0, 2.0]);
+}
+
+#[test]
+fn gray_alpha_image_loads_as_rgba() {
+    let Some(_context) = context(4, 4) else {
+        return;
+    };
+    let image = image::GrayAlphaImage::from_raw(2, 1, vec![40, 255, 200, 0]).unwrap();
+    let path = std::env::temp_dir().join(format!("undergrowth-leaf-{}.png", std::process::id()));
+    image.save(&path).unwrap();
+
+    let texture = Texture::from_file(&path);
+    std::fs::remove_file(&path).unwrap();
+    let data = texture.unwrap().read_pixels::<u8, format::RGBA>();
+    assert_eq!(data, [40, 40, 40, 255, 200, 200, 200, 0]);
+}
+
+#[test]
+fn texture_reads_back_what_it_was_created_with() {
+    let Some(_context) = context(4, 4) else {
+        return;
+    };
+    // Rows of 3 RGB bytes are not 4 byte aligned
+    let bytes: Vec<u8> = (0..3 * 2 * 3).map(|i| i * 13).collect();
+    let texture = Texture::new::<u8, format::RGB>(3, 2, &bytes);
+    assert_eq!(texture.read_pixels::<u8, format::RGB>(), bytes);
+
+    let floats: Vec<f32> = (0..2 * 2 * 4).map(|i| i as f32 * 0.75 - 2.0).collect();
+    let texture = Texture::new::<f32, format::RGBA>(2, 2, &floats);
+    assert_eq!(texture.read_pixels::<f32, format::RGBA>(), floats);
+}
+
+/// A transparent layer of a single flat quad of `color`, 8 m wide, lying
+/// `height` meters above the origin, give or take 2 m sideways.
+fn leaf_layer(height: f64, color: [u8; 4], shader: &Rc<Shader>) -> ShrubEntities {
+    let height_map: Rc<dyn noise::NoiseFn<f64, 2>> = Rc::new(noise::Constant::new(height));
+    let pixels: Vec<u8> = color.repeat(4);
+    ShrubEntitiesBuilder::new()
+        .with_exact_count(1)
+        .with_bounds(-2.0, 2.0, -2.0, 2.0)
+        .with_scale_range(4.0, 4.0)
+        .on_height_map(&height_map)
+        .with_model(Rc::new(Mesh::quad()))
+        .with_texture(Rc::new(Texture::new::<u8, format::RGBA>(2, 2, &pixels)))
+        .with_transparency()
+        .with_shader(shader.clone())
+        .load(1)
+}
+
+#[test]
+fn transparent_layers_hide_each_other_in_any_order() {
+    let Some(_context) = context(4, 4) else {
+        return;
+    };
+    let shader = Rc::new(
+        ShaderBuilder::new()
+            .with_shader_file("shaders/foliage_instanced.vert")
+            .with_shader_file("shaders/foliage_shaded_texture.frag")
+            .link()
+            .unwrap(),
+    );
+    let mut near = leaf_layer(1.0, [255, 0, 0, 255], &shader);
+    let far = leaf_layer(0.5, [0, 0, 255, 255], &shader);
+    // Sorted by their centers, the near layer comes first
+    near.center = glm::vec3(0.0, 0.0, -100.0);
+
+    let eye = glm::vec3(0.0, 0.0, 5.0);
+    let view = glm::look_at(&eye, &glm::Vec3::zeros(), &glm::Vec3::y());
+    let palette = Palette::from_seed(0);
+    let lights = DirectionalLight::defaults(&palette);
+    let stats = Cell::new(FrameStats::default());
+    let view_proj = glm::perspective(1.0, 1.0, 0.1, 10.0) * view;
+    let ctx = RenderContext {
+        view_proj_mat: view_proj,
+        eye,
+        palette: &palette,
+        lights: &lights,
+        environment: None,
+        debug_view: DebugView::Off,
+        depth_plane: glm::Vec4::zeros(),
+        frustum: Frustum::from_view_proj(&view_proj),
+        fog_density: 0.0,
+        time: 0.0,
+        stats: &stats,
+        soft_edges: false,
+    };
+
+    let target = Framebuffer::new(8, 8, Some(24));
+    target.bind();
+    unsafe {
+        gl::Enable(gl::DEPTH_TEST);
+        gl::ClearColor(0.0, 0.0, 0.0, 1.0);
+        gl::Clear(gl::COLOR_BUFFER_BIT | gl::DEPTH_BUFFER_BIT);
+    }
+    let entities: [&dyn Renderable; 2] = [&near, &far];
+    render_entities(&ctx, &entities, false);
+
+    let [red, green, blue, _] = target.read_pixels().get_pixel(4, 4).0;
+    assert!(
+        red > 0 && green == 0 && blue == 0,
+        "{:?}",
+        [red, green, blue]
+    );
+}
@@ -0,0 +1,144 @@
+//! Mesh math and files, which don't need an OpenGL context.
+
+use nalgebra_glm as glm;
+use undergrowth::mesh::{normal_matrix, Mesh};
+
+#[test]
+fn normal_matrix_keeps_normals_perpendicular_when_stretched() {
+    let model = glm::rotate_z(&glm::identity(), 0.7)
+        * glm::scale(&glm::identity(), &glm::vec3(1.0, 1.0, 4.0));
+    let normals = normal_matrix(&model);
+    let expected = glm::mat4_to_mat3(&model).try_inverse().unwrap().transpose();
+    assert!((normals - expected).amax() < 1e-6);
+
+    // A 45 degree slope gets steeper, and its normal has to flatten with it
+    let tangent = glm::vec3(1.0, 0.0, 1.0);
+    let normal = glm::vec3(-1.0, 0.0, 1.0);
+    let stretched_tangent = glm::mat4_to_mat3(&model) * tangent;
+    let stretched_normal = normals * normal;
+    assert!(glm::dot(&stretched_tangent, &stretched_normal).abs() < 1e-5);
+    // Transformed like the positions, it would tilt away from perpendicular
+    let naive_normal = glm::mat4_to_mat3(&model) * normal;
+    assert!(glm::dot(&stretched_tangent, &naive_normal).abs() > 1.0);
+}
+
+#[test]
+fn normal_matrix_of_a_flattened_model_keeps_the_normals() {
+    let model = glm::scale(&glm::identity(), &glm::vec3(1.0, 1.0, 0.0));
+    assert_eq!(normal_matrix(&model), glm::Mat3::identity());
+}
+
+#[test]
+fn bounding_box_spans_the_positions() {
+    let mut mesh = Mesh::quad_mesh(4);
+    assert_eq!(
+        mesh.bounding_box(),
+        (glm::vec3(0.0, 0.0, 0.0), glm::vec3(1.0, 1.0, 0.0))
+    );
+
+    mesh.positions.extend_from_slice(&[-2.0, 0.5, 3.0]);
+    assert_eq!(
+        mesh.bounding_box(),
+        (glm::vec3(-2.0, 0.0, 0.0), glm::vec3(1.0, 1.0, 3.0))
+    );
+
+    mesh.positions.clear();
+    assert_eq!(
+        mesh.bounding_box(),
+        (glm::Vec3::zeros(), glm::Vec3::zeros())
+    );
+}
+
+/// Position, UV and normal of every triangle corner, independent of how the
+/// vertices are numbered.
+fn corners(mesh: &Mesh) -> Vec<[f32; 8]> {
+    mesh.indices
+        .iter()
+        .map(|&index| {
+            let i = index as usize;
+            let p = &mesh.positions[3 * i..3 * i + 3];
+            let uv = &mesh.uvs[2 * i..2 * i + 2];
+            let n = &mesh.normals[3 * i..3 * i + 3];
+            [p[0], p[1], p[2], uv[0], uv[1], n[0], n[1], n[2]]
+        })
+        .collect()
+}
+
+#[test]
+fn saved_obj_loads_back_the_same_mesh() {
+    let mesh = Mesh::quad_mesh(4);
+    let dir = std::env::temp_dir().join(format!("undergrowth-obj-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("quads.obj");
+    mesh.save_obj(&path).unwrap();
+
+    let loaded = Mesh::load(path.to_str().unwrap());
+    std::fs::remove_dir_all(&dir).unwrap();
+    let loaded = loaded.unwrap();
+    assert_eq!(loaded.positions.len(), mesh.positions.len());
+    // The floats are written in full, so they come back exactly
+    assert_eq!(corners(&loaded), corners(&mesh));
+    assert_eq!(loaded.diffuse_texture, None);
+}
+
+#[test]
+fn model_without_faces_names_the_file() {
+    let dir = std::env::temp_dir().join(format!("undergrowth-points-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("points.obj");
+    std::fs::write(&path, "v 0 0 0\nv 1 0 0\nv 0 1 0\n").unwrap();
+
+    let loaded = Mesh::load(path.to_str().unwrap());
+    std::fs::remove_dir_all(&dir).unwrap();
+    let message = loaded
+        .err()
+        .expect("Loaded a model without faces")
+        .to_string();
+    assert!(message.contains(path.to_str().unwrap()), "{message}");
+    assert!(message.contains("no faces"), "{message}");
+}
+
+/// [`Mesh::quad`] with its own vertices for every triangle corner, so the two
+/// corners shared by both triangles exist twice.
+fn unshared_quad() -> Mesh {
+    let quad = Mesh::quad();
+    let mut mesh = Mesh::quad();
+    let gather = |data: &[f32], components: usize| -> Vec<f32> {
+        quad.indices
+            .iter()
+            .flat_map(|&i| &data[components * i as usize..components * (i as usize + 1)])
+            .copied()
+            .collect()
+    };
+    mesh.positions = gather(&quad.positions, 3);
+    mesh.normals = gather(&quad.normals, 3);
+    mesh.uvs = gather(&quad.uvs, 2);
+    mesh.indices = (0..quad.indices.len() as u32).collect();
+    mesh
+}
+
+#[test]
+fn weld_without_tolerance_merges_exact_duplicates() {
+    let mut mesh = unshared_quad();
+    let before = corners(&mesh);
+    assert_eq!(mesh.weld(0.0), 2);
+    assert_eq!(mesh.positions.len(), 4 * 3);
+    assert_eq!(corners(&mesh), before);
+}
+
+#[test]
+fn weld_merges_near_duplicates_within_epsilon() {
+    // The second copy of the first shared corner is slightly off
+    let near_duplicate = || {
+        let mut mesh = unshared_quad();
+        let copy = mesh.indices[3] as usize;
+        mesh.positions[3 * copy] += 1e-4;
+        mesh.uvs[2 * copy] += 1e-4;
+        mesh
+    };
+
+    assert_eq!(near_duplicate().weld(1e-5), 1);
+    let mut mesh = near_duplicate();
+    assert_eq!(mesh.weld(1e-3), 2);
+    assert_eq!(mesh.indices[3], mesh.indices[0]);
+}